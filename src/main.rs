@@ -1,20 +1,30 @@
 #![feature(alloc_layout_extra)]
 #![feature(const_generics)]
+#![cfg_attr(test, feature(test))]
+
+#[cfg(test)]
+extern crate test;
 
 mod util;
 mod octree;
+mod material;
+mod worldgen;
+mod vox;
+mod voxelize;
+mod storage;
+mod world;
 
 use amethyst::{
-    controls::{FlyControlBundle, FlyControlTag},
+    controls::{FlyControlBundle, FlyControlTag, HideCursor},
     core::{
-        math::{Point3, Vector3},
+        math::{Point3, Quaternion, UnitQuaternion, Vector3},
         transform::{Transform, TransformBundle},
         Time,
         frame_limiter::FrameRateLimitStrategy
     },
     derive::SystemDesc,
-    ecs::{Read, System, SystemData, WorldExt, Write},
-    input::{is_close_requested, is_key_down, InputBundle, StringBindings},
+    ecs::{Entity, Read, System, SystemData, WorldExt, Write},
+    input::{is_close_requested, is_key_down, InputBundle, InputHandler, StringBindings},
     prelude::*,
     renderer::{
         camera::{Camera},
@@ -36,16 +46,87 @@ use amethyst::{
         }
     },
     utils::application_root_dir,
+    window::{ScreenDimensions, Window},
     winit::VirtualKeyCode,
     assets::{AssetLoaderSystemData}
 };
+use std::fs::File;
 use std::time::Duration;
 use crate::util::gridline::get_gridline_component;
 use crate::octree::VoxelData;
 use crate::octree::direction::Direction;
 use crate::octree::mesher::Mesher;
+use crate::material::MaterialRegistry;
+use crate::worldgen::biome::BiomeMap;
+use crate::worldgen::caves::CaveSettings;
+use crate::storage::save::{CameraState, GeneratorSettings, Migrations, WorldManifest, WorldSave};
+use crate::world::ChunkSource;
+use crate::world::controller::{CameraCollisionSystem, ControllerSettings, OrbitSystem, WalkingSystem};
+use crate::world::history::{History, HistorySystem, HISTORY_FILE};
+use crate::util::aabb::Aabb;
+use crate::world::picking::{cursor_ray, pick, Pick};
+use crate::world::sculpt::{BrushSettings, SculptSystem};
+use crate::world::streaming::{ChunkStreamingSystem, RenderMaterials, StreamingSettings};
+
+struct GameState {
+    camera: Option<Entity>,
+    /// Debug lines outlining the leaf under the cursor
+    highlight: Option<Entity>,
+    /// Value of the leaf under the cursor, shown in the window title
+    hovered: Option<VoxelData>,
+}
+
+impl GameState {
+    /// Saves changed chunks and the camera, then flushes the save
+    fn save_world(&mut self, world: &World) {
+        let mut source = world.write_resource::<ChunkSource>();
+        let mut chunks = world.write_resource::<world::World>();
+        if let Err(err) = chunks.save_dirty(&source) {
+            log::error!("Failed to save chunks: {}", err);
+        }
+        log::info!("Chunk cache: {}", chunks.metrics());
+        let save = match source.save_data_mut() {
+            Some(save) => save,
+            None => return,
+        };
+        if let Err(err) = world.read_resource::<History>().save(save.root().join(HISTORY_FILE)) {
+            log::error!("Failed to save the edit history: {}", err);
+        }
+        if let Some(camera) = self.camera {
+            if let Some(transform) = world.read_storage::<Transform>().get(camera) {
+                let rotation = transform.rotation().quaternion().coords;
+                save.manifest_mut().camera = CameraState {
+                    position: (*transform.translation()).into(),
+                    rotation: [rotation.x, rotation.y, rotation.z, rotation.w],
+                };
+            }
+        }
+        if let Err(err) = save.flush() {
+            log::error!("Failed to save the world: {}", err);
+        }
+    }
 
-struct GameState;
+    /// Leaf under the cursor, liquids included
+    fn hovered_leaf(&self, world: &World) -> Option<Pick> {
+        let camera = self.camera?;
+        let cameras = world.read_storage::<Camera>();
+        let transforms = world.read_storage::<Transform>();
+        let ray = cursor_ray(
+            &world.read_resource::<InputHandler<StringBindings>>(),
+            &world.read_resource::<HideCursor>(),
+            &world.read_resource::<ScreenDimensions>(),
+            cameras.get(camera)?,
+            transforms.get(camera)?,
+        );
+        pick(
+            &world.read_resource::<world::World>(),
+            &world.read_resource::<StreamingSettings>(),
+            &ray,
+            world.read_resource::<BrushSettings>().reach,
+            |value| !value.is_empty(),
+        )
+    }
+}
 
 impl SimpleState for GameState {
     fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
@@ -55,72 +136,34 @@ impl SimpleState for GameState {
             .with(get_gridline_component())
             .build();
 
-        // Setup camera
+        // Setup camera where it was when the world was last saved
+        let camera = data.world
+            .read_resource::<ChunkSource>()
+            .save_data()
+            .map(|save| save.manifest().camera.clone())
+            .unwrap_or_default();
+        let field_of_view = data.world.read_resource::<ControllerSettings>().field_of_view;
         let mut local_transform = Transform::default();
-        local_transform.set_translation_xyz(0.0, 0.5, 2.0);
-        data.world
+        local_transform.set_translation_xyz(camera.position[0], camera.position[1], camera.position[2]);
+        let [x, y, z, w] = camera.rotation;
+        local_transform.set_rotation(UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z)));
+        self.camera = Some(data.world
             .create_entity()
             .with(FlyControlTag)
-            .with(Camera::perspective(1.33333, 1.0, 0.01))
+            .with(Camera::perspective(1.33333, field_of_view, 0.01))
             .with(local_transform)
-            .build();
-
-        let generator: octree::world_builder::WorldBuilder<octree::VoxelData, _> = octree::world_builder::WorldBuilder::new(
-            |chunk: &octree::world::ChunkCoordinates, bounds: &octree::bounds::Bounds| {
-                let target_bounds = octree::bounds::Bounds::from_discrete_grid((32, 32, 32), 48, 128);
+            .build());
 
-                let intersects = target_bounds.intersects(bounds);
-                println!("{:?} {:?} {:?}", target_bounds, intersects, bounds);
-                match target_bounds.intersects(bounds) {
-                    octree::bounds::BoundsSpacialRelationship::Disjoint => octree::world_builder::Isosurface::Uniform(VoxelData::EMPTY),
-                    octree::bounds::BoundsSpacialRelationship::Contain => octree::world_builder::Isosurface::Uniform(1.into()),
-                    octree::bounds::BoundsSpacialRelationship::Intersect => octree::world_builder::Isosurface::Surface,
-                }
-            }
-        );
-        let chunk = generator.build(& octree::world::ChunkCoordinates::new());
-
-        let mut mesh_generator = crate::octree::mesher::dualmc::MeshGenerator::new(&chunk, 1.0);
-        let wireframe = mesh_generator.gen_wireframe();
-
-
-        // Getting us a ball
-        let mesh = data.world
-            .exec(|loader: AssetLoaderSystemData<'_, Mesh>| {
-                loader.load_from_data(
-                    mesh_generator.into_mesh_builder().into(),
-                    (),
-                )
-            });
-        let albedo = data.world
-            .exec(|loader: AssetLoaderSystemData<'_, Texture>| {
-                let albedo = loader.load_from_data(
-                    load_from_linear_rgba(LinSrgba::new(1.0, 1.0, 1.0, 0.5))
-                        .into(),
-                    (),
-                );
-                albedo
-            });
-        let mat_defaults = data.world.read_resource::<MaterialDefaults>().0.clone();
-        let material = data.world.exec(
-            |loader: AssetLoaderSystemData<'_, Material>| {
-                loader.load_from_data(
-                    Material {
-                        albedo,
-                        ..mat_defaults.clone()
-                    },
-                    (),
-                )
-            });
-        let mut pos = Transform::default();
-        pos.set_translation_xyz(0.0, 0.0, 0.0);
-        data.world
+        self.highlight = Some(data.world
             .create_entity()
-            .with(pos)
-            .with(mesh)
-            .with(material)
-            .with(wireframe)
-            .build();
+            .with(DebugLinesComponent::with_capacity(12))
+            .build());
+
+        // Chunks are loaded and meshed around the camera by `ChunkStreamingSystem`
+        let materials = data.world.read_resource::<MaterialRegistry>().clone();
+        let render_materials = materials.create_render_materials(data.world);
+        data.world.insert(RenderMaterials(render_materials));
+
         // Creating light source
         let light: light::Light = light::DirectionalLight {
             color: Srgb::new(0.8, 0.0, 0.0),
@@ -134,13 +177,45 @@ impl SimpleState for GameState {
             .build();
     }
 
+    fn update(&mut self, data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        let hovered = self.hovered_leaf(data.world);
+        if let Some(highlight) = self.highlight {
+            if let Some(lines) = data.world.write_storage::<DebugLinesComponent>().get_mut(highlight) {
+                lines.clear();
+                if let Some(hovered) = &hovered {
+                    // Slightly larger than the leaf so the outline isn't hidden by its faces
+                    let size = hovered.aabb.size();
+                    let half_extents = [size[0] * 0.51, size[1] * 0.51, size[2] * 0.51];
+                    let aabb = Aabb::from_center(hovered.aabb.center(), half_extents);
+                    lines.add_box(aabb.min.into(), aabb.max.into(), Srgba::new(1.0, 0.9, 0.2, 1.0));
+                }
+            }
+        }
+
+        let value = hovered.map(|hovered| hovered.hit.value);
+        if value != self.hovered {
+            self.hovered = value;
+            let title = match value {
+                Some(value) => {
+                    let registry = data.world.read_resource::<MaterialRegistry>();
+                    let name = registry.get(value.material()).map_or("unknown material", |material| material.name.as_str());
+                    format!("gog - {} {:?}", name, value)
+                }
+                None => String::from("gog"),
+            };
+            data.world.read_resource::<Window>().set_title(&title);
+        }
+        Trans::None
+    }
+
     fn handle_event(
         &mut self,
-        _: StateData<'_, GameData<'_, '_>>,
+        data: StateData<'_, GameData<'_, '_>>,
         event: StateEvent,
     ) -> SimpleTrans {
         if let StateEvent::Window(event) = event {
             if is_close_requested(&event) || is_key_down(&event, VirtualKeyCode::Escape) {
+                self.save_world(data.world);
                 Trans::Quit
             } else {
                 Trans::None
@@ -159,6 +234,36 @@ fn main() -> amethyst::Result<()> {
     let display_config_path = app_root.join("config/display.ron");
     let key_bindings_path = app_root.join("config/input.ron");
     let assets_dir = app_root.join("assets");
+    let materials_path = app_root.join("config/materials.ron");
+    let save_dir = app_root.join("saves/default");
+    // A saved world keeps the settings it was generated with, the config files only seed new worlds
+    let (save, materials) = if WorldSave::exists(&save_dir) {
+        let save = WorldSave::open(&save_dir, Migrations::default())?;
+        let materials = save.materials(Some(&assets_dir))?;
+        (save, materials)
+    } else {
+        let materials = MaterialRegistry::load(&materials_path, &assets_dir)?;
+        let biomes = BiomeMap::load(app_root.join("config/biomes.ron"), &materials)?;
+        let settings = GeneratorSettings { biomes: biomes.settings().clone(), caves: CaveSettings::default() };
+        let manifest = WorldManifest::new(biomes.settings().seed, settings, &materials);
+        (WorldSave::create(&save_dir, manifest)?, materials)
+    };
+    let generator = save.generator(&materials)?;
+    let source = ChunkSource::new(generator, Some(save));
+    let streaming: StreamingSettings = ron::de::from_reader(File::open(app_root.join("config/streaming.ron"))?)?;
+    let memory_budget = streaming.memory_budget();
+    let controller: ControllerSettings = ron::de::from_reader(File::open(app_root.join("config/controller.ron"))?)?;
+    let brush: BrushSettings = ron::de::from_reader(File::open(app_root.join("config/brush.ron"))?)?;
+    // Edits stay undoable across sessions, an unreadable history is dropped rather than blocking the world
+    let history = source
+        .save_data()
+        .map(|save| History::load(save.root().join(HISTORY_FILE), brush.history_budget()))
+        .transpose()
+        .unwrap_or_else(|err| {
+            log::error!("Failed to load the edit history, starting a new one: {}", err);
+            None
+        })
+        .unwrap_or_else(|| History::with_budget(brush.history_budget()));
 
     let fly_control_bundle = FlyControlBundle::<StringBindings>::new(
         Some(String::from("move_x")),
@@ -172,7 +277,13 @@ fn main() -> amethyst::Result<()> {
             InputBundle::<StringBindings>::new().with_bindings_from_file(&key_bindings_path)?,
         )?
         .with_bundle(fly_control_bundle)?
-        .with_bundle(TransformBundle::new().with_dep(&["fly_movement"]))?
+        .with(WalkingSystem::default(), "walking", &["fly_movement"])
+        .with(OrbitSystem::default(), "orbit", &["fly_movement", "walking"])
+        .with(CameraCollisionSystem, "camera_collision", &["fly_movement", "walking", "orbit"])
+        .with(SculptSystem::default(), "sculpt", &["camera_collision"])
+        .with(HistorySystem::default(), "history", &["sculpt"])
+        .with(ChunkStreamingSystem, "chunk_streaming", &["fly_movement", "sculpt", "history"])
+        .with_bundle(TransformBundle::new().with_dep(&["camera_collision"]))?
         .with_bundle(
             RenderingBundle::<DefaultBackend>::new()
                 .with_plugin(RenderToWindow::from_config_path(display_config_path)?)
@@ -181,7 +292,14 @@ fn main() -> amethyst::Result<()> {
                 .with_plugin(RenderShaded3D::default()),
         )?;
 
-    let mut game = Application::build(assets_dir, GameState)?
+    let mut game = Application::build(assets_dir, GameState { camera: None, highlight: None, hovered: None })?
+        .with_resource(materials)
+        .with_resource(source)
+        .with_resource(streaming)
+        .with_resource(controller)
+        .with_resource(brush)
+        .with_resource(history)
+        .with_resource(world::World::with_budget(memory_budget))
         .with_frame_limit(FrameRateLimitStrategy::SleepAndYield(Duration::from_millis(2)), 60)
         .build(game_data)?;
     game.run();
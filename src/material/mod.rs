@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use amethyst::{
+    assets::{AssetLoaderSystemData, Handle},
+    ecs::{World, WorldExt},
+    renderer::{
+        mtl::{Material, MaterialDefaults},
+        palette::LinSrgba,
+        rendy::texture::palette::load_from_linear_rgba,
+        ImageFormat,
+        Texture,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::octree::{VoxelData, VoxelFlags};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Albedo {
+    /// Linear RGBA color
+    Color([f32; 4]),
+    /// Texture path, relative to the assets directory
+    Texture(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MaterialDefinition {
+    pub id: u16,
+    pub name: String,
+    pub albedo: Albedo,
+    #[serde(default = "MaterialDefinition::default_roughness")]
+    pub roughness: f32,
+    #[serde(default)]
+    pub metallic: f32,
+    #[serde(default)]
+    pub transparent: bool,
+    #[serde(default = "MaterialDefinition::default_solid")]
+    pub solid: bool,
+}
+
+impl MaterialDefinition {
+    fn default_roughness() -> f32 {
+        1.0
+    }
+    fn default_solid() -> bool {
+        true
+    }
+
+    pub fn flags(&self) -> VoxelFlags {
+        let mut flags = VoxelFlags::empty();
+        flags.set(VoxelFlags::SOLID, self.solid);
+        flags.set(VoxelFlags::TRANSPARENT, self.transparent);
+        flags
+    }
+}
+
+#[derive(Debug)]
+pub enum MaterialError {
+    Io(PathBuf, std::io::Error),
+    Parse(ron::de::Error),
+    /// Material id 0 is reserved for `VoxelData::EMPTY`, and ids must fit into the packed voxel.
+    InvalidId(u16),
+    DuplicateId(u16),
+    MissingTexture { id: u16, path: PathBuf },
+}
+
+impl fmt::Display for MaterialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MaterialError::Io(path, err) => write!(f, "failed to read {}: {}", path.display(), err),
+            MaterialError::Parse(err) => write!(f, "failed to parse material registry: {}", err),
+            MaterialError::InvalidId(id) => write!(f, "material id {} is reserved or out of range", id),
+            MaterialError::DuplicateId(id) => write!(f, "material id {} is defined more than once", id),
+            MaterialError::MissingTexture { id, path } => {
+                write!(f, "texture {} of material {} does not exist", path.display(), id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MaterialError {}
+
+#[derive(Debug, Deserialize)]
+struct MaterialRegistryConfig {
+    materials: Vec<MaterialDefinition>,
+}
+
+/**
+ Maps the material id stored in each `VoxelData` to its name and render properties.
+ Loaded from `config/materials.ron` and inserted into the `World` as a resource.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct MaterialRegistry {
+    materials: BTreeMap<u16, MaterialDefinition>,
+}
+
+impl MaterialRegistry {
+    pub fn load(path: impl AsRef<Path>, assets_dir: impl AsRef<Path>) -> Result<Self, MaterialError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|err| MaterialError::Io(path.to_path_buf(), err))?;
+        let config: MaterialRegistryConfig = ron::de::from_reader(file).map_err(MaterialError::Parse)?;
+        Self::from_definitions(config.materials, Some(assets_dir.as_ref()))
+    }
+
+    /// Builds a registry, checking texture paths against `assets_dir` if given.
+    pub fn from_definitions(
+        definitions: Vec<MaterialDefinition>,
+        assets_dir: Option<&Path>,
+    ) -> Result<Self, MaterialError> {
+        let mut materials = BTreeMap::new();
+        for definition in definitions {
+            let id = definition.id;
+            if id == 0 || id > VoxelData::MAX_MATERIAL {
+                return Err(MaterialError::InvalidId(id));
+            }
+            if let (Some(assets_dir), Albedo::Texture(texture)) = (assets_dir, &definition.albedo) {
+                let path = assets_dir.join(texture);
+                if !path.is_file() {
+                    return Err(MaterialError::MissingTexture { id, path });
+                }
+            }
+            if materials.insert(id, definition).is_some() {
+                return Err(MaterialError::DuplicateId(id));
+            }
+        }
+        Ok(Self { materials })
+    }
+
+    pub fn get(&self, id: u16) -> Option<&MaterialDefinition> {
+        self.materials.get(&id)
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<&MaterialDefinition> {
+        self.materials.values().find(|material| material.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MaterialDefinition> {
+        self.materials.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
+
+    /// Creates a full voxel of the given material, with flags taken from its definition.
+    pub fn voxel(&self, id: u16) -> VoxelData {
+        match self.get(id) {
+            Some(material) => VoxelData::new(id, std::i8::MAX, material.flags()),
+            None => VoxelData::from(id),
+        }
+    }
+
+    /// Whether the voxel counts as solid for meshing and collision.
+    /// Materials missing from the registry fall back to the flags stored in the voxel.
+    pub fn is_solid(&self, voxel: VoxelData) -> bool {
+        if voxel.is_empty() {
+            return false;
+        }
+        match self.get(voxel.material()) {
+            Some(material) => material.solid,
+            None => voxel.is_solid(),
+        }
+    }
+
+    /// Creates one render `Material` per registry entry.
+    pub fn create_render_materials(&self, world: &mut World) -> HashMap<u16, Handle<Material>> {
+        let mat_defaults = world.read_resource::<MaterialDefaults>().0.clone();
+        self.materials
+            .values()
+            .map(|definition| {
+                let albedo = world.exec(|loader: AssetLoaderSystemData<'_, Texture>| {
+                    match &definition.albedo {
+                        Albedo::Color([r, g, b, a]) => loader.load_from_data(
+                            load_from_linear_rgba(LinSrgba::new(*r, *g, *b, *a)).into(),
+                            (),
+                        ),
+                        Albedo::Texture(path) => loader.load(path.as_str(), ImageFormat::default(), ()),
+                    }
+                });
+                // Metallic in the blue channel, roughness in the green channel
+                let metallic_roughness = world.exec(|loader: AssetLoaderSystemData<'_, Texture>| {
+                    loader.load_from_data(
+                        load_from_linear_rgba(LinSrgba::new(0.0, definition.roughness, definition.metallic, 0.0))
+                            .into(),
+                        (),
+                    )
+                });
+                let material = world.exec(|loader: AssetLoaderSystemData<'_, Material>| {
+                    loader.load_from_data(
+                        Material {
+                            albedo,
+                            metallic_roughness,
+                            ..mat_defaults.clone()
+                        },
+                        (),
+                    )
+                });
+                (definition.id, material)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition(id: u16, albedo: Albedo) -> MaterialDefinition {
+        MaterialDefinition {
+            id,
+            name: format!("material{}", id),
+            albedo,
+            roughness: 1.0,
+            metallic: 0.0,
+            transparent: false,
+            solid: true,
+        }
+    }
+
+    #[test]
+    fn test_duplicate_id() {
+        let result = MaterialRegistry::from_definitions(vec![
+            definition(1, Albedo::Color([1.0, 1.0, 1.0, 1.0])),
+            definition(1, Albedo::Color([0.0, 0.0, 0.0, 1.0])),
+        ], None);
+        assert!(matches!(result, Err(MaterialError::DuplicateId(1))));
+    }
+
+    #[test]
+    fn test_reserved_id() {
+        let result = MaterialRegistry::from_definitions(vec![
+            definition(0, Albedo::Color([1.0, 1.0, 1.0, 1.0])),
+        ], None);
+        assert!(matches!(result, Err(MaterialError::InvalidId(0))));
+    }
+
+    #[test]
+    fn test_missing_texture() {
+        let result = MaterialRegistry::from_definitions(vec![
+            definition(2, Albedo::Texture("does/not/exist.png".into())),
+        ], Some(Path::new("assets")));
+        assert!(matches!(result, Err(MaterialError::MissingTexture { id: 2, .. })));
+    }
+
+    #[test]
+    fn test_parse() {
+        let config: MaterialRegistryConfig = ron::de::from_str(r#"(
+            materials: [
+                (id: 1, name: "stone", albedo: Color((0.5, 0.5, 0.5, 1.0))),
+                (id: 2, name: "water", albedo: Color((0.1, 0.2, 0.8, 0.5)), transparent: true, solid: false),
+            ],
+        )"#).unwrap();
+        let registry = MaterialRegistry::from_definitions(config.materials, None).unwrap();
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.get_by_name("water").unwrap().id, 2);
+        assert!(registry.is_solid(registry.voxel(1)));
+        assert!(!registry.is_solid(registry.voxel(2)));
+        assert!(registry.voxel(2).is_transparent());
+    }
+}
@@ -0,0 +1,303 @@
+use crate::octree::bounds::{Bounds, BoundsSpacialRelationship};
+use crate::octree::direction::Direction;
+use crate::octree::{Chunk, Voxel, VoxelData};
+use crate::util::aabb::Aabb;
+
+/// Query cubes are snapped to a grid this fine per chunk axis
+const QUERY_RESOLUTION: u32 = 1 << 16;
+/// Pushing out of one leaf may push into another, so resolution takes a few rounds at most
+const MAX_RESOLVE_STEPS: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sphere {
+    pub center: [f32; 3],
+    pub radius: f32,
+}
+
+impl Sphere {
+    pub fn new(center: [f32; 3], radius: f32) -> Self {
+        Self { center, radius }
+    }
+
+    pub fn aabb(&self) -> Aabb {
+        Aabb::from_center(self.center, [self.radius; 3])
+    }
+
+    pub fn overlaps(&self, aabb: &Aabb) -> bool {
+        let closest = closest_point(aabb, self.center);
+        distance_squared(self.center, closest) < self.radius * self.radius
+    }
+
+    fn translated(&self, offset: [f32; 3]) -> Self {
+        Self::new(add(self.center, offset), self.radius)
+    }
+}
+
+fn add(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn length(v: [f32; 3]) -> f32 {
+    (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+}
+
+fn distance_squared(a: [f32; 3], b: [f32; 3]) -> f32 {
+    (0..3).map(|i| (a[i] - b[i]) * (a[i] - b[i])).sum()
+}
+
+fn closest_point(aabb: &Aabb, point: [f32; 3]) -> [f32; 3] {
+    [
+        point[0].max(aabb.min[0]).min(aabb.max[0]),
+        point[1].max(aabb.min[1]).min(aabb.max[1]),
+        point[2].max(aabb.min[2]).min(aabb.max[2]),
+    ]
+}
+
+fn translate(aabb: &Aabb, offset: [f32; 3]) -> Aabb {
+    Aabb::new(add(aabb.min, offset), add(aabb.max, offset))
+}
+
+/// Extents of a leaf, normalized to its chunk
+pub fn leaf_aabb(bounds: &Bounds) -> Aabb {
+    let position: [f32; 3] = bounds.get_position().into();
+    let width = bounds.get_width();
+    Aabb::new(position, [position[0] + width, position[1] + width, position[2] + width])
+}
+
+/// Smallest cube on the query grid around the part of `aabb` inside the chunk, `None` if it is outside
+fn query_bounds(aabb: &Aabb) -> Option<Bounds> {
+    if (0..3).any(|i| aabb.max[i] <= 0.0 || aabb.min[i] >= 1.0) {
+        return None;
+    }
+    let resolution = QUERY_RESOLUTION as f32;
+    let cell = |v: f32| (v * resolution).max(0.0).min(resolution);
+    let min = [cell(aabb.min[0]).floor(), cell(aabb.min[1]).floor(), cell(aabb.min[2]).floor()];
+    let width = (0..3)
+        .map(|i| cell(aabb.max[i]).ceil() - min[i])
+        .fold(1.0, f32::max);
+    Some(Bounds::from_discrete_grid(
+        (min[0] as _, min[1] as _, min[2] as _),
+        width as _,
+        QUERY_RESOLUTION as _,
+    ))
+}
+
+/**
+ Calls `found` with every leaf accepted by `solid` whose extents pass `overlaps`, until it returns false.
+ Nodes disjoint from `query` are skipped without looking at their leaves.
+ */
+fn visit<'a>(
+    node: Voxel<'a>,
+    query: &Bounds,
+    overlaps: &impl Fn(&Aabb) -> bool,
+    solid: &impl Fn(VoxelData) -> bool,
+    found: &mut impl FnMut(Voxel<'a>) -> bool,
+) -> bool {
+    let bounds = node.get_bounds();
+    if let BoundsSpacialRelationship::Disjoint = query.intersects(&bounds) {
+        return true;
+    }
+    if node.is_leaf() {
+        if solid(*node.get_value()) && overlaps(&leaf_aabb(&bounds)) {
+            return found(node);
+        }
+        return true;
+    }
+    Direction::map(|dir| dir)
+        .data
+        .iter()
+        .all(|dir| visit(node.get_child(*dir), query, overlaps, solid, found))
+}
+
+fn visit_chunk<'a>(
+    chunk: &'a Chunk,
+    aabb: &Aabb,
+    overlaps: impl Fn(&Aabb) -> bool,
+    solid: impl Fn(VoxelData) -> bool,
+    mut found: impl FnMut(Voxel<'a>) -> bool,
+) {
+    if let Some(query) = query_bounds(aabb) {
+        visit(chunk.get_root(), &query, &overlaps, &solid, &mut found);
+    }
+}
+
+/// Leaves accepted by `solid` that overlap a box given in chunk-normalized coordinates
+pub fn overlapping_leaves<'a>(chunk: &'a Chunk, aabb: &Aabb, solid: impl Fn(VoxelData) -> bool) -> Vec<Voxel<'a>> {
+    let mut leaves = Vec::new();
+    visit_chunk(chunk, aabb, |leaf| leaf.overlaps(aabb), solid, |leaf| {
+        leaves.push(leaf);
+        true
+    });
+    leaves
+}
+
+/// Leaves accepted by `solid` that overlap a sphere given in chunk-normalized coordinates
+pub fn sphere_leaves<'a>(chunk: &'a Chunk, sphere: &Sphere, solid: impl Fn(VoxelData) -> bool) -> Vec<Voxel<'a>> {
+    let mut leaves = Vec::new();
+    visit_chunk(chunk, &sphere.aabb(), |leaf| sphere.overlaps(leaf), solid, |leaf| {
+        leaves.push(leaf);
+        true
+    });
+    leaves
+}
+
+pub fn aabb_overlaps(chunk: &Chunk, aabb: &Aabb, solid: impl Fn(VoxelData) -> bool) -> bool {
+    let mut overlaps = false;
+    visit_chunk(chunk, aabb, |leaf| leaf.overlaps(aabb), solid, |_| {
+        overlaps = true;
+        false
+    });
+    overlaps
+}
+
+pub fn sphere_overlaps(chunk: &Chunk, sphere: &Sphere, solid: impl Fn(VoxelData) -> bool) -> bool {
+    let mut overlaps = false;
+    visit_chunk(chunk, &sphere.aabb(), |leaf| sphere.overlaps(leaf), solid, |_| {
+        overlaps = true;
+        false
+    });
+    overlaps
+}
+
+/// Shortest translation that moves box `a` out of box `b`, zero if they don't overlap
+pub fn aabb_penetration(a: &Aabb, b: &Aabb) -> [f32; 3] {
+    let mut push = [0.0; 3];
+    let mut depth = std::f32::INFINITY;
+    for i in 0..3 {
+        let overlap = (a.max[i] - b.min[i]).min(b.max[i] - a.min[i]);
+        if overlap <= 0.0 {
+            return [0.0; 3];
+        }
+        if overlap < depth {
+            depth = overlap;
+            push = [0.0; 3];
+            push[i] = if a.center()[i] < b.center()[i] { -overlap } else { overlap };
+        }
+    }
+    push
+}
+
+/// Shortest translation that moves `sphere` out of `aabb`, zero if they don't overlap
+pub fn sphere_penetration(sphere: &Sphere, aabb: &Aabb) -> [f32; 3] {
+    let closest = closest_point(aabb, sphere.center);
+    let offset = [sphere.center[0] - closest[0], sphere.center[1] - closest[1], sphere.center[2] - closest[2]];
+    let distance = length(offset);
+    if distance >= sphere.radius {
+        return [0.0; 3];
+    }
+    if distance > 0.0 {
+        let scale = (sphere.radius - distance) / distance;
+        return [offset[0] * scale, offset[1] * scale, offset[2] * scale];
+    }
+    // The center is inside the box: leave through the nearest face
+    let mut push = [0.0; 3];
+    let mut depth = std::f32::INFINITY;
+    for i in 0..3 {
+        let below = sphere.center[i] - aabb.min[i];
+        let above = aabb.max[i] - sphere.center[i];
+        if below < depth {
+            depth = below;
+            push = [0.0; 3];
+            push[i] = -(below + sphere.radius);
+        }
+        if above < depth {
+            depth = above;
+            push = [0.0; 3];
+            push[i] = above + sphere.radius;
+        }
+    }
+    push
+}
+
+/**
+ Translation that moves a shape out of the boxes returned by `leaves`, pushing it out of the deepest
+ one at a time. `leaves` is asked for the boxes overlapping the shape moved by the translation so far.
+ */
+fn resolve(
+    leaves: impl Fn([f32; 3]) -> Vec<Aabb>,
+    penetration: impl Fn([f32; 3], &Aabb) -> [f32; 3],
+) -> [f32; 3] {
+    let mut total = [0.0; 3];
+    for _ in 0..MAX_RESOLVE_STEPS {
+        let deepest = leaves(total)
+            .iter()
+            .map(|leaf| penetration(total, leaf))
+            .max_by(|a, b| length(*a).partial_cmp(&length(*b)).unwrap_or(std::cmp::Ordering::Equal));
+        match deepest {
+            Some(push) if length(push) > 0.0 => total = add(total, push),
+            _ => break,
+        }
+    }
+    total
+}
+
+/// Minimum translation out of the boxes returned by `leaves`, which are asked for everything overlapping a box
+pub fn resolve_aabb(aabb: &Aabb, leaves: impl Fn(&Aabb) -> Vec<Aabb>) -> [f32; 3] {
+    resolve(
+        |offset| leaves(&translate(aabb, offset)),
+        |offset, leaf| aabb_penetration(&translate(aabb, offset), leaf),
+    )
+}
+
+/// Minimum translation out of the boxes returned by `leaves`, which are asked for everything overlapping a box
+pub fn resolve_sphere(sphere: &Sphere, leaves: impl Fn(&Aabb) -> Vec<Aabb>) -> [f32; 3] {
+    resolve(
+        |offset| leaves(&sphere.translated(offset).aabb()),
+        |offset, leaf| sphere_penetration(&sphere.translated(offset), leaf),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::chunk::test_utils::{floor, solid};
+
+    fn leaf_boxes(chunk: &Chunk, aabb: &Aabb) -> Vec<Aabb> {
+        overlapping_leaves(chunk, aabb, solid)
+            .iter()
+            .map(|leaf| leaf_aabb(&leaf.get_bounds()))
+            .collect()
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let chunk = floor().to_chunk([0, 0, 0]);
+        assert!(aabb_overlaps(&chunk, &Aabb::new([0.4, 0.45, 0.4], [0.6, 0.55, 0.6]), solid));
+        assert!(!aabb_overlaps(&chunk, &Aabb::new([0.4, 0.5, 0.4], [0.6, 0.7, 0.6]), solid));
+        assert!(!aabb_overlaps(&chunk, &Aabb::new([2.0, 0.0, 0.0], [3.0, 1.0, 1.0]), solid));
+        assert!(sphere_overlaps(&chunk, &Sphere::new([0.5, 0.55, 0.5], 0.1), solid));
+        assert!(!sphere_overlaps(&chunk, &Sphere::new([0.5, 0.65, 0.5], 0.1), solid));
+
+        // Straddling the middle of the floor touches all four of its leaves
+        let leaves = overlapping_leaves(&chunk, &Aabb::new([0.4, 0.4, 0.4], [0.6, 0.6, 0.6]), solid);
+        assert_eq!(leaves.len(), 4);
+        assert!(leaves.iter().all(|leaf| *leaf.get_value() == VoxelData::solid(1)));
+        assert_eq!(sphere_leaves(&chunk, &Sphere::new([0.25, 0.55, 0.25], 0.1), solid).len(), 1);
+    }
+
+    #[test]
+    fn test_penetration() {
+        let leaf = Aabb::new([0.0; 3], [1.0; 3]);
+        let push = aabb_penetration(&Aabb::new([0.5, 0.9, 0.5], [0.7, 1.2, 0.7]), &leaf);
+        assert!((push[1] - 0.1).abs() < 1e-6 && push[0] == 0.0 && push[2] == 0.0);
+        assert_eq!(aabb_penetration(&Aabb::new([2.0; 3], [3.0; 3]), &leaf), [0.0; 3]);
+
+        let push = sphere_penetration(&Sphere::new([0.5, 1.1, 0.5], 0.3), &leaf);
+        assert!((push[1] - 0.2).abs() < 1e-6);
+        // Inside, nearest to the x = 0 face
+        let push = sphere_penetration(&Sphere::new([0.1, 0.5, 0.5], 0.2), &leaf);
+        assert!((push[0] + 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_resolve_onto_floor() {
+        let chunk = floor().to_chunk([0, 0, 0]);
+        let player = Aabb::new([0.45, 0.48, 0.45], [0.55, 0.68, 0.55]);
+        let push = resolve_aabb(&player, |aabb| leaf_boxes(&chunk, aabb));
+        assert!((push[1] - 0.02).abs() < 1e-5);
+        assert!(push[0].abs() < 1e-6 && push[2].abs() < 1e-6);
+
+        let push = resolve_sphere(&Sphere::new([0.3, 0.52, 0.3], 0.05), |aabb| leaf_boxes(&chunk, aabb));
+        assert!((push[1] - 0.03).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,235 @@
+use crate::octree::{Chunk, VoxelData};
+use crate::storage::chunk::{ChunkFormatError, Tree};
+use crate::util::aabb::Aabb;
+
+/// Face-connected leaves that are all solid or all empty
+#[derive(Clone, Debug, PartialEq)]
+pub struct Component {
+    pub solid: bool,
+    pub leaves: usize,
+    /// Fraction of the chunk's volume
+    pub volume: f32,
+    /// Extents normalized to the chunk
+    pub bounds: Aabb,
+    /// Whether it reaches a face of the chunk, so it may continue into a neighbouring chunk.
+    /// A solid component that doesn't is a floating island, an empty one a sealed cave.
+    pub touches_border: bool,
+}
+
+struct Leaf {
+    solid: bool,
+    min: [f32; 3],
+    size: f32,
+}
+
+/// The tree with leaves replaced by their index in the leaf list
+enum Node {
+    Leaf(usize),
+    Branch(Vec<Node>),
+}
+
+fn label(tree: &Tree, min: [f32; 3], size: f32, solid: &impl Fn(VoxelData) -> bool, leaves: &mut Vec<Leaf>) -> Node {
+    match tree {
+        Tree::Leaf(value) => {
+            leaves.push(Leaf { solid: solid(*value), min, size });
+            Node::Leaf(leaves.len() - 1)
+        }
+        Tree::Branch(children) => {
+            let half = size / 2.0;
+            let children = children
+                .iter()
+                .enumerate()
+                .map(|(i, child)| {
+                    let min = [
+                        min[0] + (i & 1) as f32 * half,
+                        min[1] + ((i >> 1) & 1) as f32 * half,
+                        min[2] + ((i >> 2) & 1) as f32 * half,
+                    ];
+                    label(child, min, half, solid, leaves)
+                })
+                .collect();
+            Node::Branch(children)
+        }
+    }
+}
+
+struct DisjointSets {
+    parent: Vec<usize>,
+    rank: Vec<u8>,
+}
+
+impl DisjointSets {
+    fn new(len: usize) -> Self {
+        Self { parent: (0..len).collect(), rank: vec![0; len] }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            self.parent[i] = self.parent[self.parent[i]];
+            i = self.parent[i];
+        }
+        i
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+        match self.rank[a].cmp(&self.rank[b]) {
+            std::cmp::Ordering::Less => self.parent[a] = b,
+            std::cmp::Ordering::Greater => self.parent[b] = a,
+            std::cmp::Ordering::Equal => {
+                self.parent[b] = a;
+                self.rank[a] += 1;
+            }
+        }
+    }
+}
+
+/// Joins neighbouring leaves inside a node, then across the faces between its children
+fn cell_proc(node: &Node, leaves: &[Leaf], sets: &mut DisjointSets) {
+    if let Node::Branch(children) = node {
+        for child in children {
+            cell_proc(child, leaves, sets);
+        }
+        for axis in 0..3 {
+            let bit = 1 << axis;
+            for i in (0..8).filter(|i| i & bit == 0) {
+                face_proc(&children[i], &children[i | bit], axis, leaves, sets);
+            }
+        }
+    }
+}
+
+/**
+ Joins the leaves on both sides of the face between `low` and `high`, which are neighbours along `axis`.
+ Only the children touching the face are visited, and a leaf facing a subdivided node is paired with
+ each of the smaller nodes across from it, so large leaves are never split up.
+ */
+fn face_proc(low: &Node, high: &Node, axis: usize, leaves: &[Leaf], sets: &mut DisjointSets) {
+    if let (Node::Leaf(a), Node::Leaf(b)) = (low, high) {
+        if leaves[*a].solid == leaves[*b].solid {
+            sets.union(*a, *b);
+        }
+        return;
+    }
+    let bit = 1 << axis;
+    for i in (0..8).filter(|i| i & bit != 0) {
+        let low = match low {
+            Node::Leaf(_) => low,
+            Node::Branch(children) => &children[i],
+        };
+        let high = match high {
+            Node::Leaf(_) => high,
+            Node::Branch(children) => &children[i & !bit],
+        };
+        face_proc(low, high, axis, leaves, sets);
+    }
+}
+
+/// Connected components of the leaves of a tree, with `solid` deciding which leaves belong together.
+/// Largest components come first.
+pub fn components(tree: &Tree, solid: impl Fn(VoxelData) -> bool) -> Vec<Component> {
+    let mut leaves = Vec::new();
+    let root = label(tree, [0.0; 3], 1.0, &solid, &mut leaves);
+    let mut sets = DisjointSets::new(leaves.len());
+    cell_proc(&root, &leaves, &mut sets);
+
+    let mut components: Vec<Component> = Vec::new();
+    let mut index = vec![None; leaves.len()];
+    for (i, leaf) in leaves.iter().enumerate() {
+        let root = sets.find(i);
+        let max = [leaf.min[0] + leaf.size, leaf.min[1] + leaf.size, leaf.min[2] + leaf.size];
+        let touches_border = (0..3).any(|axis| leaf.min[axis] <= 0.0 || max[axis] >= 1.0);
+        let component = match index[root] {
+            Some(component) => &mut components[component],
+            None => {
+                index[root] = Some(components.len());
+                components.push(Component {
+                    solid: leaf.solid,
+                    leaves: 0,
+                    volume: 0.0,
+                    bounds: Aabb::new(leaf.min, max),
+                    touches_border: false,
+                });
+                components.last_mut().unwrap()
+            }
+        };
+        component.leaves += 1;
+        component.volume += leaf.size * leaf.size * leaf.size;
+        component.touches_border |= touches_border;
+        for axis in 0..3 {
+            component.bounds.min[axis] = component.bounds.min[axis].min(leaf.min[axis]);
+            component.bounds.max[axis] = component.bounds.max[axis].max(max[axis]);
+        }
+    }
+    components.sort_by(|a, b| b.volume.partial_cmp(&a.volume).unwrap_or(std::cmp::Ordering::Equal));
+    components
+}
+
+/// Connected components of a chunk, see `components`
+pub fn chunk_components(chunk: &Chunk, solid: impl Fn(VoxelData) -> bool) -> Result<Vec<Component>, ChunkFormatError> {
+    Ok(components(&Tree::from_chunk(chunk)?, solid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::chunk::test_utils::solid;
+
+    #[test]
+    fn test_floating_island() {
+        let stone = VoxelData::solid(1);
+        let mut tree = Tree::Leaf(VoxelData::EMPTY);
+        // A floor along the bottom, and a block hovering inside the chunk
+        for x in 0..4 {
+            for z in 0..4 {
+                tree.set_node(2, [x, 0, z], Tree::Leaf(stone));
+            }
+        }
+        tree.set_node(2, [1, 2, 1], Tree::Leaf(stone));
+
+        let components = components(&tree, solid);
+        assert_eq!(components.len(), 3);
+        let air = &components[0];
+        assert!(!air.solid && air.touches_border);
+        assert_eq!(air.volume, 1.0 - 17.0 / 64.0);
+
+        let floor = &components[1];
+        assert!(floor.solid && floor.touches_border);
+        assert_eq!(floor.bounds, Aabb::new([0.0; 3], [1.0, 0.25, 1.0]));
+
+        let island = &components[2];
+        assert!(island.solid && !island.touches_border);
+        assert_eq!(island.leaves, 1);
+        assert_eq!(island.bounds, Aabb::new([0.25, 0.5, 0.25], [0.5, 0.75, 0.5]));
+    }
+
+    #[test]
+    fn test_large_leaf_meets_small_ones() {
+        let stone = VoxelData::solid(1);
+        let mut tree = Tree::Leaf(VoxelData::EMPTY);
+        // An octant of stone, and a much smaller block against its face
+        tree.set_node(1, [0, 0, 0], Tree::Leaf(stone));
+        tree.set_node(3, [4, 1, 1], Tree::Leaf(stone));
+        let components = components(&tree, solid);
+        // The small block touches the large one's face at x = 0.5
+        let solids: Vec<&Component> = components.iter().filter(|component| component.solid).collect();
+        assert_eq!(solids.len(), 1);
+        assert_eq!(solids[0].leaves, 2);
+        assert_eq!(solids[0].volume, 0.125 + 1.0 / 512.0);
+    }
+
+    #[test]
+    fn test_sealed_cave() {
+        let stone = VoxelData::solid(1);
+        let mut tree = Tree::Leaf(stone);
+        tree.set_node(3, [3, 3, 3], Tree::Leaf(VoxelData::EMPTY));
+        let components = components(&tree, solid);
+        assert_eq!(components.len(), 2);
+        let cave = &components[1];
+        assert!(!cave.solid && !cave.touches_border);
+        assert_eq!(cave.volume, 1.0 / 512.0);
+    }
+}
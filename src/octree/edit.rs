@@ -0,0 +1,196 @@
+use crate::octree::bounds::Bounds;
+use crate::octree::{Chunk, VoxelData};
+use crate::storage::chunk::{ChunkFormatError, Tree};
+
+/**
+ Edits a chunk at a fixed voxel resolution of `2^depth` per axis.
+ Leaves are subdivided as far as an edit needs, and children that end up uniform are merged again,
+ so the result is the same tree `WorldBuilder` would produce for the edited voxels.
+ */
+pub struct ChunkEditor {
+    tree: Tree,
+    /// The tree before any edit
+    original: Tree,
+    position: [i32; 3],
+    depth: u8,
+}
+
+impl ChunkEditor {
+    pub fn new(chunk: &Chunk, position: [i32; 3], depth: u8) -> Result<Self, ChunkFormatError> {
+        Ok(Self::from_tree(Tree::from_chunk(chunk)?, position, depth))
+    }
+
+    pub fn from_tree(tree: Tree, position: [i32; 3], depth: u8) -> Self {
+        Self { original: tree.clone(), tree, position, depth }
+    }
+
+    /// Voxels along every axis
+    pub fn resolution(&self) -> u32 {
+        1 << self.depth
+    }
+
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// The tree as it was before any edit
+    pub fn original(&self) -> &Tree {
+        &self.original
+    }
+
+    /// Whether the edits changed any voxel. Edits that write the values already there don't count.
+    pub fn is_changed(&self) -> bool {
+        self.tree != self.original
+    }
+
+    pub fn get_voxel(&self, voxel: [u32; 3]) -> VoxelData {
+        match self.tree.node(self.depth, voxel) {
+            Tree::Leaf(value) => *value,
+            // Deeper than the edit resolution: report the first leaf below it
+            mut node => loop {
+                match node {
+                    Tree::Leaf(value) => break *value,
+                    Tree::Branch(children) => node = &children[0],
+                }
+            },
+        }
+    }
+
+    /// Sets a single voxel. Positions outside the chunk are ignored.
+    pub fn set_voxel(&mut self, voxel: [u32; 3], value: VoxelData) {
+        let max = [voxel[0] + 1, voxel[1] + 1, voxel[2] + 1];
+        self.fill_voxels(voxel, max, value);
+    }
+
+    /// Sets every voxel in `[min, max)`
+    pub fn fill_voxels(&mut self, min: [u32; 3], max: [u32; 3], value: VoxelData) {
+        let resolution = self.resolution();
+        let min = [min[0].min(resolution), min[1].min(resolution), min[2].min(resolution)];
+        let max = [max[0].min(resolution), max[1].min(resolution), max[2].min(resolution)];
+        if (0..3).any(|i| min[i] >= max[i]) {
+            return;
+        }
+        fill(&mut self.tree, resolution, [0, 0, 0], &min, &max, value);
+    }
+
+    /// Sets every voxel whose center lies within `bounds`, given in chunk-normalized coordinates
+    pub fn fill_bounds(&mut self, bounds: &Bounds, value: VoxelData) {
+        let (min, max) = self.voxel_range(bounds);
+        self.fill_voxels(min, max, value);
+    }
+
+    pub fn clear_bounds(&mut self, bounds: &Bounds) {
+        self.fill_bounds(bounds, VoxelData::EMPTY);
+    }
+
+    /// Replaces a whole node, which may be coarser or finer than the edit resolution
+    pub fn set_node(&mut self, depth: u8, cell: [u32; 3], subtree: Tree) {
+        self.tree.set_node(depth, cell, subtree);
+    }
+
+    /// Voxels with their centers inside `bounds`, as `[min, max)`
+    pub fn voxel_range(&self, bounds: &Bounds) -> ([u32; 3], [u32; 3]) {
+        let resolution = self.resolution() as f32;
+        let position: [f32; 3] = bounds.get_position().into();
+        let width = bounds.get_width();
+        let first = |p: f32| (p * resolution - 0.5).ceil().max(0.0).min(resolution) as u32;
+        let min = [first(position[0]), first(position[1]), first(position[2])];
+        let max = [first(position[0] + width), first(position[1] + width), first(position[2] + width)];
+        (min, max)
+    }
+
+    pub fn into_tree(self) -> Tree {
+        self.tree
+    }
+
+    /// Rebuilds the chunk through `WorldBuilder`
+    pub fn finish(self) -> Chunk {
+        self.tree.to_chunk(self.position)
+    }
+}
+
+/// Fills the part of `node` inside `[min, max)`. `size` is the node's width in voxels.
+fn fill(node: &mut Tree, size: u32, origin: [u32; 3], min: &[u32; 3], max: &[u32; 3], value: VoxelData) {
+    if (0..3).any(|i| origin[i] >= max[i] || origin[i] + size <= min[i]) {
+        return;
+    }
+    if (0..3).all(|i| min[i] <= origin[i] && origin[i] + size <= max[i]) {
+        *node = Tree::Leaf(value);
+        return;
+    }
+    if let Tree::Leaf(current) = node {
+        if *current == value {
+            return;
+        }
+        *node = Tree::Branch(vec![Tree::Leaf(*current); 8]);
+    }
+    if let Tree::Branch(children) = node {
+        let half = size / 2;
+        for (i, child) in children.iter_mut().enumerate() {
+            let child_origin = [
+                origin[0] + (i & 1) as u32 * half,
+                origin[1] + ((i >> 1) & 1) as u32 * half,
+                origin[2] + ((i >> 2) & 1) as u32 * half,
+            ];
+            fill(child, half, child_origin, min, max, value);
+        }
+        let first = match &children[0] {
+            Tree::Leaf(first) => Some(*first),
+            Tree::Branch(_) => None,
+        };
+        if let Some(first) = first {
+            if children.iter().all(|child| *child == Tree::Leaf(first)) {
+                *node = Tree::Leaf(first);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor() -> ChunkEditor {
+        ChunkEditor::from_tree(Tree::Leaf(VoxelData::EMPTY), [0, 0, 0], 3)
+    }
+
+    #[test]
+    fn test_set_voxel_subdivides() {
+        let mut editor = editor();
+        editor.set_voxel([5, 2, 7], VoxelData::solid(1));
+        assert_eq!(editor.tree().depth(), 3);
+        assert_eq!(editor.get_voxel([5, 2, 7]), VoxelData::solid(1));
+        assert_eq!(editor.get_voxel([5, 2, 6]), VoxelData::EMPTY);
+        assert!(editor.is_changed());
+
+        // Undoing the edit merges all the way back to the root
+        editor.set_voxel([5, 2, 7], VoxelData::EMPTY);
+        assert_eq!(editor.tree(), &Tree::Leaf(VoxelData::EMPTY));
+        assert!(!editor.is_changed());
+    }
+
+    #[test]
+    fn test_fill_merges() {
+        let mut editor = editor();
+        // One octant, then the other seven
+        editor.fill_voxels([0, 0, 0], [4, 4, 4], VoxelData::solid(2));
+        assert_eq!(editor.tree().depth(), 1);
+        editor.fill_voxels([4, 0, 0], [8, 8, 8], VoxelData::solid(2));
+        editor.fill_voxels([0, 4, 0], [4, 8, 8], VoxelData::solid(2));
+        editor.fill_voxels([0, 0, 4], [4, 4, 8], VoxelData::solid(2));
+        assert_eq!(editor.tree(), &Tree::Leaf(VoxelData::solid(2)));
+    }
+
+    #[test]
+    fn test_unaligned_fill() {
+        let mut editor = editor();
+        editor.fill_voxels([1, 1, 1], [7, 7, 7], VoxelData::solid(3));
+        for x in 0..8 {
+            let inside = x >= 1 && x < 7;
+            let expected = if inside { VoxelData::solid(3) } else { VoxelData::EMPTY };
+            assert_eq!(editor.get_voxel([x, 3, 4]), expected);
+        }
+        editor.fill_voxels([0, 0, 0], [20, 20, 20], VoxelData::EMPTY);
+        assert_eq!(editor.tree(), &Tree::Leaf(VoxelData::EMPTY));
+    }
+}
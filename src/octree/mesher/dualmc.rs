@@ -35,6 +35,8 @@ use crate::octree::bounds::Bounds;
 use crate::octree::direction::{Direction, DirectionMapper, Edge};
 use crate::octree::{Chunk, Voxel, VoxelData};
 use crate::octree::mesher::Mesher;
+use crate::material::MaterialRegistry;
+use std::collections::BTreeMap;
 
 trait Dimension {
     type FaceEdges1: Dimension;
@@ -423,14 +425,18 @@ const EDGE_TABLE: [[u16; 5]; 256] = [
 
 pub struct MeshGenerator<'a> {
     chunk: &'a Chunk,
+    materials: &'a MaterialRegistry,
     pub dual_cells: Vec<DirectionMapper<Voxel<'a>>>,
 
     vertices: Vec<Position>,
     normal: Vec<Normal>,
     texcoords: Vec<TexCoord>,
-    indices: Vec<u16>,
+    // 32 bit, a chunk of terrain easily has more than 65 535 vertices of one material
+    indices: Vec<u32>,
+    // Material id of each triangle in `indices`
+    triangle_materials: Vec<u16>,
 
-    current: u16,
+    current: u32,
     pub size: f32,
 
     pub count: usize,
@@ -546,10 +552,11 @@ impl<'a> MeshGenerator<'a> {
         let mut edge_index: u8 = 0;
         for node in nodes.iter().rev() {
             edge_index <<= 1;
-            if *node.get_value() == VoxelData::EMPTY {
+            if !self.materials.is_solid(*node.get_value()) {
                 edge_index |= 1;
             }
         }
+        let material = self.dominant_material(&nodes);
 
         let edge_bin = EDGE_TABLE[edge_index as usize];
         for edges in edge_bin.iter() {
@@ -563,11 +570,35 @@ impl<'a> MeshGenerator<'a> {
             let edge3: Edge = ((edges >> 8) as u8).into();
 
             self.add_triangle([edge1, edge2, edge3], &nodes);
+            self.triangle_materials.push(material);
         }
 
         self.dual_cells.push(nodes);
     }
 
+    /// The most common solid material among the corners of a dual cell.
+    fn dominant_material(&self, nodes: &DirectionMapper<Voxel<'a>>) -> u16 {
+        let mut counts: [(u16, u8); 8] = [(0, 0); 8];
+        for node in nodes.iter() {
+            let value = *node.get_value();
+            if !self.materials.is_solid(value) {
+                continue;
+            }
+            let material = value.material();
+            for slot in counts.iter_mut() {
+                if slot.1 == 0 || slot.0 == material {
+                    slot.0 = material;
+                    slot.1 += 1;
+                    break;
+                }
+            }
+        }
+        counts.iter()
+            .max_by_key(|(_, count)| *count)
+            .map(|(material, _)| *material)
+            .unwrap_or(0)
+    }
+
     fn add_triangle(&mut self, edges: [Edge; 3], nodes: &DirectionMapper<Voxel>) {
         for edge in edges.iter() {
             let (v1, v2) = edge.vertices();
@@ -589,14 +620,16 @@ impl<'a> MeshGenerator<'a> {
 }
 
 impl<'a> Mesher<'a> for MeshGenerator<'a> {
-    fn new(chunk: &'a Chunk, size: f32) -> Self {
+    fn new(chunk: &'a Chunk, size: f32, materials: &'a MaterialRegistry) -> Self {
         let mut mesher = Self {
             chunk,
+            materials,
             dual_cells: Vec::new(),
             vertices: Vec::new(),
             normal: Vec::new(),
             texcoords: Vec::new(),
             indices: Vec::new(),
+            triangle_materials: Vec::new(),
             current: 0,
             size,
             count: 0,
@@ -682,6 +715,31 @@ impl<'a> Mesher<'a> for MeshGenerator<'a> {
             .with_vertices(self.vertices)
             .with_vertices(self.normal)
             .with_vertices(self.texcoords)
-            .with_indices(Indices::U16(self.indices.into()))
+            .with_indices(Indices::U32(self.indices.into()))
+    }
+
+    fn into_mesh_builders(self) -> Vec<(u16, MeshBuilder<'static>)> {
+        // Every triangle owns its three vertices, so splitting only needs to copy them over
+        let mut groups: BTreeMap<u16, (Vec<Position>, Vec<Normal>, Vec<TexCoord>)> = BTreeMap::new();
+        for (triangle, material) in self.triangle_materials.iter().enumerate() {
+            let group = groups.entry(*material).or_default();
+            for index in &self.indices[triangle * 3..triangle * 3 + 3] {
+                let index = *index as usize;
+                group.0.push(self.vertices[index]);
+                group.1.push(self.normal[index]);
+                group.2.push(self.texcoords[index]);
+            }
+        }
+        groups.into_iter()
+            .map(|(material, (vertices, normal, texcoords))| {
+                let indices: Vec<u32> = (0..vertices.len() as u32).collect();
+                let builder = MeshBuilder::new()
+                    .with_vertices(vertices)
+                    .with_vertices(normal)
+                    .with_vertices(texcoords)
+                    .with_indices(Indices::U32(indices.into()));
+                (material, builder)
+            })
+            .collect()
     }
 }
@@ -1,4 +1,5 @@
 use crate::octree::Chunk;
+use crate::material::MaterialRegistry;
 use amethyst::{
     renderer::{
         debug_drawing::{DebugLinesComponent},
@@ -12,7 +13,9 @@ use amethyst::{
 pub mod dualmc;
 
 pub trait Mesher<'a> {
-    fn new(chunk: &'a Chunk, size: f32) -> Self;
+    fn new(chunk: &'a Chunk, size: f32, materials: &'a MaterialRegistry) -> Self;
     fn gen_wireframe(&self) -> DebugLinesComponent;
     fn into_mesh_builder(self) -> MeshBuilder<'static>;
-}
\ No newline at end of file
+    /// One mesh per material id, so each can be rendered with its own `Material`.
+    fn into_mesh_builders(self) -> Vec<(u16, MeshBuilder<'static>)>;
+}
@@ -1,21 +1,47 @@
 extern crate octree;
+pub mod collision;
+pub mod connectivity;
+pub mod edit;
 pub mod mesher;
-#[derive(Eq, PartialEq, Copy, Clone, Default)]
-pub struct VoxelData(u16);
-impl VoxelData {
-    pub const EMPTY: VoxelData = VoxelData(0);
-    pub fn is_empty(&self) -> bool {
-        *self == Self::EMPTY
-    }
-}
-pub use octree::*;
+pub mod raycast;
+mod voxel_data;
 
-impl From<u16> for VoxelData {
-    fn from(d: u16) -> Self {
-        Self(d)
-    }
-}
+pub use octree::*;
+pub use voxel_data::{VoxelData, VoxelFlags};
 
 pub type Voxel<'a> = octree::voxel::Voxel<'a, VoxelData>;
 pub type Chunk = octree::chunk::Chunk<VoxelData>;
 pub type WorldBuilder<ORACLE> = octree::world_builder::WorldBuilder<VoxelData, ORACLE>;
+
+/// Integer position of a chunk, measured in chunks.
+pub fn chunk_position(coords: &world::ChunkCoordinates) -> [i32; 3] {
+    [coords.x as i32, coords.y as i32, coords.z as i32]
+}
+
+pub fn chunk_coordinates(position: [i32; 3]) -> world::ChunkCoordinates {
+    world::ChunkCoordinates {
+        x: position[0] as _,
+        y: position[1] as _,
+        z: position[2] as _,
+    }
+}
+
+/// Leaf containing a chunk-normalized point, points outside the chunk are clamped to its border
+pub fn leaf_at<'a>(chunk: &'a Chunk, point: [f32; 3]) -> Voxel<'a> {
+    let directions = direction::Direction::map(|dir| dir).data;
+    let mut node = chunk.get_root();
+    while !node.is_leaf() {
+        let center: [f32; 3] = node.get_bounds().center().into();
+        // Children split their parent at its center, so one comparison per axis picks the octant
+        let dir = directions
+            .iter()
+            .copied()
+            .find(|dir| {
+                let child: [f32; 3] = node.get_child(*dir).get_bounds().center().into();
+                (0..3).all(|i| (child[i] < center[i]) == (point[i] < center[i]))
+            })
+            .expect("a branch has a child in every octant");
+        node = node.get_child(dir);
+    }
+    node
+}
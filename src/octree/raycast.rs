@@ -0,0 +1,237 @@
+use crate::octree::bounds::Bounds;
+use crate::octree::direction::Direction;
+use crate::octree::{Chunk, Voxel, VoxelData};
+
+/// First leaf a ray hits. Positions and distances are in the units of the ray.
+pub struct RayHit {
+    pub value: VoxelData,
+    /// Extents of the leaf, normalized to its chunk
+    pub bounds: Bounds,
+    /// Where the ray enters the leaf
+    pub position: [f32; 3],
+    /// Outward normal of the face the ray enters through, zero if the ray starts inside the leaf
+    pub normal: [f32; 3],
+    pub distance: f32,
+}
+
+/**
+ Parameters along the ray where it enters and leaves the box `[min, min + width]`, and the axis it
+ enters through. `None` if it misses. `inverse` is `1 / direction` per axis.
+ */
+fn slab(origin: [f32; 3], inverse: [f32; 3], min: [f32; 3], width: f32) -> Option<(f32, f32, usize)> {
+    let mut enter = std::f32::NEG_INFINITY;
+    let mut exit = std::f32::INFINITY;
+    let mut axis = 0;
+    for i in 0..3 {
+        let (near, far) = if inverse[i].is_infinite() {
+            // Parallel to the slab: inside it everywhere or nowhere
+            if origin[i] < min[i] || origin[i] > min[i] + width {
+                return None;
+            }
+            (std::f32::NEG_INFINITY, std::f32::INFINITY)
+        } else {
+            let a = (min[i] - origin[i]) * inverse[i];
+            let b = (min[i] + width - origin[i]) * inverse[i];
+            if a < b { (a, b) } else { (b, a) }
+        };
+        if near > enter {
+            enter = near;
+            axis = i;
+        }
+        exit = exit.min(far);
+    }
+    if enter > exit {
+        None
+    } else {
+        Some((enter, exit, axis))
+    }
+}
+
+fn normalize(direction: [f32; 3]) -> Option<[f32; 3]> {
+    let length = (direction[0] * direction[0] + direction[1] * direction[1] + direction[2] * direction[2]).sqrt();
+    if length == 0.0 || !length.is_finite() {
+        None
+    } else {
+        Some([direction[0] / length, direction[1] / length, direction[2] / length])
+    }
+}
+
+/**
+ Casts a ray through a chunk and returns the first non-empty leaf within `max_distance`.
+ The ray is given in chunk-normalized coordinates, and distances are measured along the normalized direction.
+ */
+pub fn raycast(chunk: &Chunk, origin: [f32; 3], direction: [f32; 3], max_distance: f32) -> Option<RayHit> {
+    raycast_filtered(chunk, origin, direction, max_distance, |value| !value.is_empty())
+}
+
+/**
+ Like `raycast`, but stops at the first leaf accepted by `hits`, for example only solid materials.
+
+ Nodes are visited front to back: children are sorted by where the ray enters them, and since they
+ don't overlap, the first hit below the nearest child that is hit at all is the nearest hit overall.
+ */
+pub fn raycast_filtered(
+    chunk: &Chunk,
+    origin: [f32; 3],
+    direction: [f32; 3],
+    max_distance: f32,
+    hits: impl Fn(VoxelData) -> bool,
+) -> Option<RayHit> {
+    let direction = normalize(direction)?;
+    let inverse = [1.0 / direction[0], 1.0 / direction[1], 1.0 / direction[2]];
+    let ray = Ray { origin, direction, inverse, max_distance };
+    ray.traverse(&chunk.get_root(), &hits)
+}
+
+struct Ray {
+    origin: [f32; 3],
+    direction: [f32; 3],
+    inverse: [f32; 3],
+    max_distance: f32,
+}
+
+impl Ray {
+    fn enter(&self, bounds: &Bounds) -> Option<(f32, usize)> {
+        let min: [f32; 3] = bounds.get_position().into();
+        let (enter, exit, axis) = slab(self.origin, self.inverse, min, bounds.get_width())?;
+        if exit < 0.0 || enter > self.max_distance {
+            None
+        } else {
+            Some((enter, axis))
+        }
+    }
+
+    fn traverse(&self, node: &Voxel, hits: &impl Fn(VoxelData) -> bool) -> Option<RayHit> {
+        let bounds = node.get_bounds();
+        let (enter, axis) = self.enter(&bounds)?;
+        if node.is_leaf() {
+            let value = *node.get_value();
+            if !hits(value) {
+                return None;
+            }
+            let distance = enter.max(0.0);
+            let mut normal = [0.0; 3];
+            if enter >= 0.0 {
+                normal[axis] = -self.direction[axis].signum();
+            }
+            let position = [
+                self.origin[0] + self.direction[0] * distance,
+                self.origin[1] + self.direction[1] * distance,
+                self.origin[2] + self.direction[2] * distance,
+            ];
+            return Some(RayHit { value, bounds, position, normal, distance });
+        }
+
+        let mut children: Vec<(f32, Direction)> = Direction::map(|dir| dir)
+            .data
+            .iter()
+            .filter_map(|dir| self.enter(&node.get_child(*dir).get_bounds()).map(|(enter, _)| (enter, *dir)))
+            .collect();
+        children.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        children
+            .into_iter()
+            .find_map(|(_, dir)| self.traverse(&node.get_child(dir), hits))
+    }
+}
+
+/**
+ Chunks a ray passes through in order, with the distance at which it enters each, using the
+ traversal of Amanatides and Woo over the grid of chunks. The ray is measured in chunks.
+ */
+pub fn chunks_along(origin: [f32; 3], direction: [f32; 3], max_distance: f32) -> Vec<([i32; 3], f32)> {
+    let direction = match normalize(direction) {
+        Some(direction) => direction,
+        None => return Vec::new(),
+    };
+    let mut chunk = [origin[0].floor() as i32, origin[1].floor() as i32, origin[2].floor() as i32];
+    let mut step = [0; 3];
+    let mut next = [std::f32::INFINITY; 3];
+    let mut delta = [std::f32::INFINITY; 3];
+    for i in 0..3 {
+        if direction[i] > 0.0 {
+            step[i] = 1;
+            next[i] = (chunk[i] as f32 + 1.0 - origin[i]) / direction[i];
+            delta[i] = 1.0 / direction[i];
+        } else if direction[i] < 0.0 {
+            step[i] = -1;
+            next[i] = (chunk[i] as f32 - origin[i]) / direction[i];
+            delta[i] = -1.0 / direction[i];
+        }
+    }
+
+    let mut chunks = vec![(chunk, 0.0)];
+    loop {
+        let axis = (0..3)
+            .min_by(|a, b| next[*a].partial_cmp(&next[*b]).unwrap_or(std::cmp::Ordering::Equal))
+            .unwrap_or(0);
+        let distance = next[axis];
+        if distance > max_distance || !distance.is_finite() {
+            break;
+        }
+        chunk[axis] += step[axis];
+        next[axis] += delta[axis];
+        chunks.push((chunk, distance));
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::chunk::Tree;
+
+    fn chunk() -> Chunk {
+        // Stone in the octant at x = 1, y = 0, z = 0
+        let mut tree = Tree::Leaf(VoxelData::EMPTY);
+        tree.set_node(1, [1, 0, 0], Tree::Leaf(VoxelData::solid(1)));
+        tree.to_chunk([0, 0, 0])
+    }
+
+    #[test]
+    fn test_hit_face() {
+        let chunk = chunk();
+        let hit = raycast(&chunk, [-1.0, 0.25, 0.25], [1.0, 0.0, 0.0], 10.0).unwrap();
+        assert_eq!(hit.value, VoxelData::solid(1));
+        assert!((hit.distance - 1.5).abs() < 1e-5);
+        assert_eq!(hit.normal, [-1.0, 0.0, 0.0]);
+        assert!((hit.position[0] - 0.5).abs() < 1e-5);
+        assert_eq!(hit.bounds.get_width(), 0.5);
+
+        // From above, falling onto its top face
+        let hit = raycast(&chunk, [0.75, 2.0, 0.25], [0.0, -2.0, 0.0], 10.0).unwrap();
+        assert!((hit.distance - 1.5).abs() < 1e-5);
+        assert_eq!(hit.normal, [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn test_misses() {
+        let chunk = chunk();
+        // Passes over the stone
+        assert!(raycast(&chunk, [-1.0, 0.75, 0.25], [1.0, 0.0, 0.0], 10.0).is_none());
+        // Too short
+        assert!(raycast(&chunk, [-1.0, 0.25, 0.25], [1.0, 0.0, 0.0], 1.0).is_none());
+        // Pointing away
+        assert!(raycast(&chunk, [-1.0, 0.25, 0.25], [-1.0, 0.0, 0.0], 10.0).is_none());
+        assert!(raycast(&chunk, [0.0; 3], [0.0; 3], 10.0).is_none());
+        // Filtered out
+        assert!(raycast_filtered(&chunk, [-1.0, 0.25, 0.25], [1.0, 0.0, 0.0], 10.0, |value| value.material() == 2).is_none());
+    }
+
+    #[test]
+    fn test_starting_inside() {
+        let hit = raycast(&chunk(), [0.75, 0.25, 0.25], [0.0, 0.0, 1.0], 10.0).unwrap();
+        assert_eq!(hit.distance, 0.0);
+        assert_eq!(hit.normal, [0.0; 3]);
+    }
+
+    #[test]
+    fn test_chunks_along() {
+        let chunks = chunks_along([0.5, 0.5, 0.5], [1.0, 0.0, -1.0], 3.0);
+        let positions: Vec<[i32; 3]> = chunks.iter().map(|(chunk, _)| *chunk).collect();
+        // Passing exactly through a corner steps one axis at a time
+        assert_eq!(positions[0], [0, 0, 0]);
+        assert_eq!(positions.len(), 5);
+        assert_eq!(*positions.last().unwrap(), [2, 0, -2]);
+        assert!(chunks.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+    }
+}
@@ -0,0 +1,217 @@
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+bitflags::bitflags! {
+    #[derive(Default, Serialize, Deserialize)]
+    pub struct VoxelFlags: u8 {
+        const SOLID = 0b0001;
+        const TRANSPARENT = 0b0010;
+        const LIQUID = 0b0100;
+        const EMISSIVE = 0b1000;
+    }
+}
+
+/**
+ A single voxel packed into 32 bits.
+
+ | bits  | content                  |
+ |-------|--------------------------|
+ | 0-11  | material id              |
+ | 12-15 | `VoxelFlags`             |
+ | 16-23 | signed density (`i8`)    |
+ | 24-31 | reserved, always zero    |
+
+ Material id 0 is reserved for empty space, and every empty voxel is `EMPTY` regardless of the
+ density and flags it was built with, so air always compares equal and merges. Positive density
+ means "inside the surface". Serialized as the packed bits, which are normalized again when read.
+ */
+#[derive(Eq, PartialEq, Copy, Clone, Default, Hash, Serialize, Deserialize)]
+#[serde(from = "u32", into = "u32")]
+pub struct VoxelData(u32);
+
+impl VoxelData {
+    pub const EMPTY: VoxelData = VoxelData(0);
+    pub const MAX_MATERIAL: u16 = (1 << Self::MATERIAL_BITS) - 1;
+
+    const MATERIAL_BITS: u32 = 12;
+    const MATERIAL_MASK: u32 = (1 << Self::MATERIAL_BITS) - 1;
+    const FLAGS_SHIFT: u32 = 12;
+    const FLAGS_MASK: u32 = 0b1111 << Self::FLAGS_SHIFT;
+    const DENSITY_SHIFT: u32 = 16;
+    const DENSITY_MASK: u32 = 0xff << Self::DENSITY_SHIFT;
+
+    /// Material ids above `MAX_MATERIAL` are clamped to it.
+    pub fn new(material: u16, density: i8, flags: VoxelFlags) -> Self {
+        if material == 0 {
+            return Self::EMPTY;
+        }
+        let material = material.min(Self::MAX_MATERIAL);
+        Self(
+            material as u32
+                | ((flags.bits() as u32) << Self::FLAGS_SHIFT)
+                | (((density as u8) as u32) << Self::DENSITY_SHIFT)
+        )
+    }
+
+    /// A fully solid voxel of the given material.
+    pub fn solid(material: u16) -> Self {
+        Self::new(material, std::i8::MAX, VoxelFlags::SOLID)
+    }
+
+    /// A fully submerged liquid voxel of the given material.
+    pub fn liquid(material: u16) -> Self {
+        Self::new(material, std::i8::MAX, VoxelFlags::LIQUID | VoxelFlags::TRANSPARENT)
+    }
+
+    pub fn from_bits(bits: u32) -> Self {
+        if bits & Self::MATERIAL_MASK == 0 {
+            return Self::EMPTY;
+        }
+        Self(bits & !0xff00_0000)
+    }
+
+    pub fn to_bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.material() == 0
+    }
+
+    pub fn material(&self) -> u16 {
+        (self.0 & Self::MATERIAL_MASK) as u16
+    }
+
+    pub fn density(&self) -> i8 {
+        ((self.0 & Self::DENSITY_MASK) >> Self::DENSITY_SHIFT) as u8 as i8
+    }
+
+    pub fn flags(&self) -> VoxelFlags {
+        VoxelFlags::from_bits_truncate(((self.0 & Self::FLAGS_MASK) >> Self::FLAGS_SHIFT) as u8)
+    }
+
+    pub fn is_solid(&self) -> bool {
+        self.flags().contains(VoxelFlags::SOLID)
+    }
+
+    pub fn is_transparent(&self) -> bool {
+        self.flags().contains(VoxelFlags::TRANSPARENT)
+    }
+
+    pub fn is_liquid(&self) -> bool {
+        self.flags().contains(VoxelFlags::LIQUID)
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        self.flags().contains(VoxelFlags::EMISSIVE)
+    }
+
+    pub fn with_material(self, material: u16) -> Self {
+        Self::new(material, self.density(), self.flags())
+    }
+
+    pub fn with_density(self, density: i8) -> Self {
+        Self::new(self.material(), density, self.flags())
+    }
+
+    pub fn with_flags(self, flags: VoxelFlags) -> Self {
+        Self::new(self.material(), self.density(), flags)
+    }
+}
+
+impl From<u16> for VoxelData {
+    /// Material 0 maps to `EMPTY`, everything else to a solid voxel of that material.
+    fn from(material: u16) -> Self {
+        if material == 0 {
+            Self::EMPTY
+        } else {
+            Self::solid(material)
+        }
+    }
+}
+
+impl From<u32> for VoxelData {
+    fn from(bits: u32) -> Self {
+        Self::from_bits(bits)
+    }
+}
+
+impl From<VoxelData> for u32 {
+    fn from(voxel: VoxelData) -> Self {
+        voxel.to_bits()
+    }
+}
+
+impl fmt::Debug for VoxelData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("VoxelData::EMPTY");
+        }
+        f.debug_struct("VoxelData")
+            .field("material", &self.material())
+            .field("density", &self.density())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{VoxelData, VoxelFlags};
+
+    #[test]
+    fn test_packing() {
+        let flags = VoxelFlags::SOLID | VoxelFlags::EMISSIVE;
+        let voxel = VoxelData::new(VoxelData::MAX_MATERIAL, -42, flags);
+        assert_eq!(voxel.material(), VoxelData::MAX_MATERIAL);
+        assert_eq!(voxel.density(), -42);
+        assert_eq!(voxel.flags(), flags);
+        assert_eq!(voxel.to_bits() >> 24, 0);
+        assert_eq!(VoxelData::from_bits(voxel.to_bits()), voxel);
+    }
+
+    #[test]
+    fn test_builders() {
+        let voxel = VoxelData::solid(3).with_density(10).with_material(7);
+        assert_eq!(voxel.material(), 7);
+        assert_eq!(voxel.density(), 10);
+        assert!(voxel.is_solid());
+        assert!(!voxel.is_liquid());
+        assert!(VoxelData::liquid(2).is_transparent());
+    }
+
+    #[test]
+    fn test_empty() {
+        assert!(VoxelData::EMPTY.is_empty());
+        assert_eq!(VoxelData::from(0u16), VoxelData::EMPTY);
+        assert!(!VoxelData::from(1u16).is_empty());
+        assert!(VoxelData::EMPTY.with_density(5).is_empty());
+    }
+
+    #[test]
+    fn test_empty_is_normalized() {
+        assert_eq!(VoxelData::EMPTY.with_density(-20), VoxelData::EMPTY);
+        assert_eq!(VoxelData::new(0, 5, VoxelFlags::SOLID), VoxelData::EMPTY);
+        assert_eq!(VoxelData::from_bits(0x0012_3000), VoxelData::EMPTY);
+        assert_eq!(VoxelData::solid(4).with_material(0), VoxelData::EMPTY);
+    }
+
+    #[test]
+    fn test_material_clamped() {
+        let voxel = VoxelData::new(VoxelData::MAX_MATERIAL + 10, 1, VoxelFlags::SOLID);
+        assert_eq!(voxel.material(), VoxelData::MAX_MATERIAL);
+        assert_eq!(voxel.flags(), VoxelFlags::SOLID);
+        assert_eq!(voxel.density(), 1);
+    }
+
+    #[test]
+    fn test_deserialize_is_normalized() {
+        let voxel = VoxelData::new(5, -3, VoxelFlags::LIQUID);
+        let text = ron::ser::to_string(&voxel).unwrap();
+        assert_eq!(ron::de::from_str::<VoxelData>(&text).unwrap(), voxel);
+        // 0x0012_3000, air with a density and flags
+        assert_eq!(ron::de::from_str::<VoxelData>("1191936").unwrap(), VoxelData::EMPTY);
+        // 0xff00_0001, with the reserved bits set
+        assert_eq!(ron::de::from_str::<VoxelData>("4278190081").unwrap(), VoxelData::from_bits(1));
+    }
+}
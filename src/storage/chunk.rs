@@ -0,0 +1,798 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::octree::bounds::Bounds;
+use crate::octree::world::ChunkCoordinates;
+use crate::octree::world_builder::Isosurface;
+use crate::octree::{chunk_coordinates, chunk_position, Chunk, VoxelData};
+use crate::worldgen::{world_builder, Oracle};
+
+pub const MAGIC: &[u8; 4] = b"GOGC";
+/// Version 1 stores raw leaf values only, version 2 adds palettes, run lengths and compression
+pub const VERSION: u16 = 2;
+
+/// Magic, version, flags, chunk position, mask count, leaf count and checksum
+const HEADER_SIZE_V1: usize = 32;
+/// Version 2 adds the body length, since the body size no longer follows from the counts
+const HEADER_SIZE: usize = 36;
+// Deeper trees can't come out of `WorldBuilder`, so they only show up in corrupted files
+pub(crate) const MAX_DEPTH: u8 = 24;
+const MAX_VARINT_BYTES: usize = 10;
+// A palette entry, and a run length and palette index varint
+const MAX_LEAF_BYTES: usize = 4 + 2 * MAX_VARINT_BYTES;
+const FLAG_PALETTE: u16 = 1;
+const FLAG_RUN_LENGTH: u16 = 2;
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug)]
+pub enum ChunkFormatError {
+    Io(std::io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    ChecksumMismatch { expected: u32, found: u32 },
+    Corrupted(String),
+    /// The compressor was not compiled in
+    UnsupportedCompression(Compression),
+}
+
+impl fmt::Display for ChunkFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkFormatError::Io(err) => write!(f, "{}", err),
+            ChunkFormatError::InvalidMagic => write!(f, "not a chunk file"),
+            ChunkFormatError::UnsupportedVersion(version) => write!(f, "unsupported chunk format version {}", version),
+            ChunkFormatError::Truncated => write!(f, "chunk data is truncated"),
+            ChunkFormatError::ChecksumMismatch { expected, found } => {
+                write!(f, "chunk checksum mismatch: expected {:08x}, found {:08x}", expected, found)
+            }
+            ChunkFormatError::Corrupted(reason) => write!(f, "corrupted chunk data: {}", reason),
+            ChunkFormatError::UnsupportedCompression(compression) => {
+                write!(f, "{:?} compression is not available in this build", compression)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChunkFormatError {}
+
+impl From<std::io::Error> for ChunkFormatError {
+    fn from(err: std::io::Error) -> Self {
+        ChunkFormatError::Io(err)
+    }
+}
+
+/**
+ Octree topology and leaf values of a chunk, independent of the octree crate.
+ Children are ordered by `x + 2y + 4z`, with each offset being 0 or 1.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Tree {
+    Leaf(VoxelData),
+    Branch(Vec<Tree>),
+}
+
+impl Tree {
+    pub fn from_chunk(chunk: &Chunk) -> Result<Tree, ChunkFormatError> {
+        // Leaves keyed by depth and cell at that depth
+        let mut leaves = HashMap::new();
+        for leaf in chunk.iter_leaf() {
+            leaves.insert(node_key(&leaf.get_bounds()), *leaf.get_value());
+        }
+        Self::from_leaves(&leaves, 0, [0, 0, 0])
+    }
+
+    fn from_leaves(leaves: &HashMap<(u8, [u32; 3]), VoxelData>, depth: u8, cell: [u32; 3]) -> Result<Tree, ChunkFormatError> {
+        if let Some(value) = leaves.get(&(depth, cell)) {
+            return Ok(Tree::Leaf(*value));
+        }
+        if depth >= MAX_DEPTH {
+            return Err(ChunkFormatError::Corrupted(format!("no leaf covers cell {:?} at depth {}", cell, depth)));
+        }
+        let children = (0..8)
+            .map(|i| Self::from_leaves(leaves, depth + 1, child_cell(cell, i)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Tree::Branch(children))
+    }
+
+    /// Rebuilds the chunk through `WorldBuilder`, so it ends up exactly as the generator left it.
+    pub fn to_chunk(&self, position: [i32; 3]) -> Chunk {
+        let oracle = TreeOracle { tree: self, position };
+        world_builder(&oracle).build(&chunk_coordinates(position))
+    }
+
+    /// Node at `cell` of `depth`, or the leaf covering it if the tree is shallower there
+    pub fn node(&self, depth: u8, cell: [u32; 3]) -> &Tree {
+        let mut node = self;
+        for level in (0..depth).rev() {
+            match node {
+                Tree::Leaf(_) => break,
+                Tree::Branch(children) => {
+                    let bit = |axis: usize| ((cell[axis] >> level) & 1) as usize;
+                    node = &children[bit(0) | (bit(1) << 1) | (bit(2) << 2)];
+                }
+            }
+        }
+        node
+    }
+
+    /**
+     Replaces the node at `cell` of level `depth` with `subtree`, subdividing leaves on the way down
+     and merging uniform branches on the way back up.
+     */
+    pub fn set_node(&mut self, depth: u8, cell: [u32; 3], subtree: Tree) {
+        if depth == 0 {
+            *self = subtree;
+            return;
+        }
+        if let Tree::Leaf(value) = self {
+            if subtree == Tree::Leaf(*value) {
+                return;
+            }
+            *self = Tree::Branch(vec![Tree::Leaf(*value); 8]);
+        }
+        if let Tree::Branch(children) = self {
+            let level = depth - 1;
+            let bit = |axis: usize| ((cell[axis] >> level) & 1) as usize;
+            let mask = (1u32 << level) - 1;
+            let child = &mut children[bit(0) | (bit(1) << 1) | (bit(2) << 2)];
+            child.set_node(level, [cell[0] & mask, cell[1] & mask, cell[2] & mask], subtree);
+            if let Tree::Leaf(first) = children[0] {
+                if children.iter().all(|child| *child == Tree::Leaf(first)) {
+                    *self = Tree::Leaf(first);
+                }
+            }
+        }
+    }
+
+    /// Number of leaves and branches
+    pub fn node_count(&self) -> usize {
+        match self {
+            Tree::Leaf(_) => 1,
+            Tree::Branch(children) => 1 + children.iter().map(Tree::node_count).sum::<usize>(),
+        }
+    }
+
+    /**
+     Preorder subdivision bitmasks and leaf values. Every branch writes one byte where bit `i`
+     is set if child `i` is subdivided in turn. A tree that is a single leaf has no masks.
+     */
+    fn flatten(&self, masks: &mut Vec<u8>, values: &mut Vec<VoxelData>) {
+        match self {
+            Tree::Leaf(value) => values.push(*value),
+            Tree::Branch(children) => {
+                let mask = children
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, child)| matches!(child, Tree::Branch(_)))
+                    .fold(0u8, |mask, (i, _)| mask | (1 << i));
+                masks.push(mask);
+                for child in children {
+                    child.flatten(masks, values);
+                }
+            }
+        }
+    }
+
+    fn unflatten(
+        subdivided: bool,
+        masks: &mut std::slice::Iter<u8>,
+        values: &mut std::slice::Iter<VoxelData>,
+        depth: u8,
+    ) -> Result<Tree, ChunkFormatError> {
+        if !subdivided {
+            let value = values.next().ok_or_else(|| ChunkFormatError::Corrupted("missing leaf values".to_string()))?;
+            return Ok(Tree::Leaf(*value));
+        }
+        if depth >= MAX_DEPTH {
+            return Err(ChunkFormatError::Corrupted("tree is too deep".to_string()));
+        }
+        let mask = *masks.next().ok_or_else(|| ChunkFormatError::Corrupted("missing subdivision masks".to_string()))?;
+        let children = (0..8)
+            .map(|i| Self::unflatten(mask & (1 << i) != 0, masks, values, depth + 1))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Tree::Branch(children))
+    }
+
+    /// Number of levels below the root
+    pub fn depth(&self) -> u8 {
+        match self {
+            Tree::Leaf(_) => 0,
+            Tree::Branch(children) => 1 + children.iter().map(Tree::depth).max().unwrap_or(0),
+        }
+    }
+
+    pub fn encode(&self, position: [i32; 3]) -> Vec<u8> {
+        self.encode_with(position, &EncodeOptions::default())
+            .expect("the default options need no optional compressor")
+    }
+
+    pub fn encode_with(&self, position: [i32; 3], options: &EncodeOptions) -> Result<Vec<u8>, ChunkFormatError> {
+        let mut masks = Vec::new();
+        let mut values = Vec::new();
+        self.flatten(&mut masks, &mut values);
+
+        let mut body = masks.clone();
+        encode_leaves(&values, options, &mut body);
+        let body = options.compression.compress(body)?;
+
+        let flags = options.flags();
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + body.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&flags.to_le_bytes());
+        for axis in &position {
+            bytes.extend_from_slice(&axis.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(masks.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(values.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+        bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        bytes.extend(body);
+        Ok(bytes)
+    }
+
+    /// The tree and the chunk position stored with it
+    pub fn decode(data: &[u8]) -> Result<(Tree, [i32; 3]), ChunkFormatError> {
+        if data.len() < 4 {
+            return Err(ChunkFormatError::Truncated);
+        }
+        if &data[..4] != MAGIC {
+            return Err(ChunkFormatError::InvalidMagic);
+        }
+        if data.len() < 6 {
+            return Err(ChunkFormatError::Truncated);
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version == 0 || version > VERSION {
+            return Err(ChunkFormatError::UnsupportedVersion(version));
+        }
+        let header_size = if version == 1 { HEADER_SIZE_V1 } else { HEADER_SIZE };
+        if data.len() < header_size {
+            return Err(ChunkFormatError::Truncated);
+        }
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+        };
+        let options = EncodeOptions::from_flags(u16::from_le_bytes([data[6], data[7]]))?;
+        let position = [u32_at(8) as i32, u32_at(12) as i32, u32_at(16) as i32];
+        let mask_count = u32_at(20) as usize;
+        let value_count = u32_at(24) as usize;
+        let expected = u32_at(28);
+        let body_len = if version == 1 {
+            value_count
+                .checked_mul(4)
+                .and_then(|len| len.checked_add(mask_count))
+                .ok_or(ChunkFormatError::Truncated)?
+        } else {
+            u32_at(32) as usize
+        };
+
+        let body = &data[header_size..];
+        if body.len() < body_len {
+            return Err(ChunkFormatError::Truncated);
+        }
+        if body.len() > body_len {
+            return Err(ChunkFormatError::Corrupted(format!("{} trailing bytes", body.len() - body_len)));
+        }
+        let found = crc32fast::hash(body);
+        if found != expected {
+            return Err(ChunkFormatError::ChecksumMismatch { expected, found });
+        }
+
+        // Masks, then a palette and one run length and symbol per leaf at most, see `encode_leaves`
+        let max_body_len = value_count
+            .checked_mul(MAX_LEAF_BYTES)
+            .and_then(|len| len.checked_add(mask_count + MAX_VARINT_BYTES))
+            .ok_or_else(|| ChunkFormatError::Corrupted(format!("{} leaves is too many", value_count)))?;
+        let body = options.compression.decompress(body, max_body_len)?;
+        if body.len() < mask_count {
+            return Err(ChunkFormatError::Truncated);
+        }
+        let (masks, leaves) = body.split_at(mask_count);
+        let values = decode_leaves(leaves, value_count, &options)?;
+        let mut mask_iter = masks.iter();
+        let mut value_iter = values.iter();
+        let tree = Self::unflatten(mask_count > 0, &mut mask_iter, &mut value_iter, 0)?;
+        if mask_iter.next().is_some() || value_iter.next().is_some() {
+            return Err(ChunkFormatError::Corrupted("unused masks or leaf values".to_string()));
+        }
+        Ok((tree, position))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    /// Needs the `zstd` feature
+    Zstd,
+    /// Needs the `lz4` feature
+    Lz4,
+}
+
+impl Compression {
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, ChunkFormatError> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            2 => Ok(Compression::Lz4),
+            _ => Err(ChunkFormatError::Corrupted(format!("unknown compression {}", id))),
+        }
+    }
+
+    fn compress(self, data: Vec<u8>) -> Result<Vec<u8>, ChunkFormatError> {
+        match self {
+            Compression::None => Ok(data),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd::encode_all(&data[..], ZSTD_LEVEL)?),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(&data)),
+            #[allow(unreachable_patterns)]
+            _ => Err(ChunkFormatError::UnsupportedCompression(self)),
+        }
+    }
+
+    /// Fails rather than producing more than `limit` bytes, so a small corrupted body can't exhaust memory
+    #[allow(unused_variables)]
+    fn decompress(self, data: &[u8], limit: usize) -> Result<Vec<u8>, ChunkFormatError> {
+        let too_large = || ChunkFormatError::Corrupted(format!("body decompresses to more than {} bytes", limit));
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let corrupted = |err: std::io::Error| ChunkFormatError::Corrupted(err.to_string());
+                let decoder = zstd::stream::read::Decoder::new(data).map_err(corrupted)?;
+                let mut body = Vec::new();
+                decoder.take(limit as u64 + 1).read_to_end(&mut body).map_err(corrupted)?;
+                if body.len() > limit {
+                    return Err(too_large());
+                }
+                Ok(body)
+            }
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                // The decompressed size is prepended, and allocated up front
+                let size = read_u32(&mut &data[..])? as usize;
+                if size > limit {
+                    return Err(too_large());
+                }
+                lz4_flex::decompress_size_prepended(data).map_err(|err| ChunkFormatError::Corrupted(err.to_string()))
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(ChunkFormatError::UnsupportedCompression(self)),
+        }
+    }
+}
+
+/// How leaf values are stored. Decoding reads the choices back from the header.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncodeOptions {
+    /// Store leaves as indices into a per-chunk list of distinct values
+    pub palette: bool,
+    /// Collapse runs of equal leaves. Leaves are in Morton order, so runs follow spatial locality.
+    pub run_length: bool,
+    pub compression: Compression,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            palette: true,
+            run_length: true,
+            compression: Compression::None,
+        }
+    }
+}
+
+impl EncodeOptions {
+    pub const RAW: EncodeOptions = EncodeOptions {
+        palette: false,
+        run_length: false,
+        compression: Compression::None,
+    };
+
+    /// Low byte holds the leaf encoding bits, high byte the compressor
+    fn flags(&self) -> u16 {
+        let mut flags = 0;
+        if self.palette {
+            flags |= FLAG_PALETTE;
+        }
+        if self.run_length {
+            flags |= FLAG_RUN_LENGTH;
+        }
+        flags | ((self.compression.id() as u16) << 8)
+    }
+
+    fn from_flags(flags: u16) -> Result<Self, ChunkFormatError> {
+        if flags & 0xff & !(FLAG_PALETTE | FLAG_RUN_LENGTH) != 0 {
+            return Err(ChunkFormatError::Corrupted(format!("unknown flags {:04x}", flags)));
+        }
+        Ok(Self {
+            palette: flags & FLAG_PALETTE != 0,
+            run_length: flags & FLAG_RUN_LENGTH != 0,
+            compression: Compression::from_id((flags >> 8) as u8)?,
+        })
+    }
+}
+
+/**
+ Leaves are written as symbols: palette indices as varints, or raw little endian values.
+ With run length encoding every symbol is preceded by a varint repeat count.
+ */
+fn encode_leaves(values: &[VoxelData], options: &EncodeOptions, out: &mut Vec<u8>) {
+    let mut palette: Vec<VoxelData> = Vec::new();
+    let mut indices: HashMap<VoxelData, u64> = HashMap::new();
+    if options.palette {
+        for value in values {
+            indices.entry(*value).or_insert_with(|| {
+                palette.push(*value);
+                palette.len() as u64 - 1
+            });
+        }
+        write_varint(out, palette.len() as u64);
+        for value in &palette {
+            out.extend_from_slice(&value.to_bits().to_le_bytes());
+        }
+    }
+    let symbol = |out: &mut Vec<u8>, value: &VoxelData| {
+        if options.palette {
+            write_varint(out, indices[value]);
+        } else {
+            out.extend_from_slice(&value.to_bits().to_le_bytes());
+        }
+    };
+    if options.run_length {
+        let mut i = 0;
+        while i < values.len() {
+            let run = values[i..].iter().take_while(|value| **value == values[i]).count();
+            write_varint(out, run as u64);
+            symbol(out, &values[i]);
+            i += run;
+        }
+    } else {
+        for value in values {
+            symbol(out, value);
+        }
+    }
+}
+
+fn decode_leaves(data: &[u8], count: usize, options: &EncodeOptions) -> Result<Vec<VoxelData>, ChunkFormatError> {
+    let mut cursor = data;
+    let mut palette = Vec::new();
+    if options.palette {
+        let len = read_varint(&mut cursor)? as usize;
+        if len > count {
+            return Err(ChunkFormatError::Corrupted("palette is larger than the leaf count".to_string()));
+        }
+        for _ in 0..len {
+            palette.push(VoxelData::from_bits(read_u32(&mut cursor)?));
+        }
+    }
+    let symbol = |cursor: &mut &[u8]| {
+        if options.palette {
+            let index = read_varint(cursor)? as usize;
+            palette
+                .get(index)
+                .copied()
+                .ok_or_else(|| ChunkFormatError::Corrupted(format!("palette index {} out of range", index)))
+        } else {
+            read_u32(cursor).map(VoxelData::from_bits)
+        }
+    };
+    let mut values = Vec::with_capacity(count);
+    while values.len() < count {
+        let run = if options.run_length { read_varint(&mut cursor)? as usize } else { 1 };
+        if run == 0 || run > count - values.len() {
+            return Err(ChunkFormatError::Corrupted(format!("invalid run of {} leaves", run)));
+        }
+        let value = symbol(&mut cursor)?;
+        values.extend(std::iter::repeat(value).take(run));
+    }
+    if !cursor.is_empty() {
+        return Err(ChunkFormatError::Corrupted(format!("{} trailing bytes", cursor.len())));
+    }
+    Ok(values)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(cursor: &mut &[u8]) -> Result<u64, ChunkFormatError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = cursor.split_first().ok_or(ChunkFormatError::Truncated)?;
+        *cursor = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(ChunkFormatError::Corrupted("varint is too long".to_string()))
+}
+
+fn read_u32(cursor: &mut &[u8]) -> Result<u32, ChunkFormatError> {
+    if cursor.len() < 4 {
+        return Err(ChunkFormatError::Truncated);
+    }
+    let (bytes, rest) = cursor.split_at(4);
+    *cursor = rest;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+struct TreeOracle<'a> {
+    tree: &'a Tree,
+    position: [i32; 3],
+}
+
+impl<'a> Oracle for TreeOracle<'a> {
+    fn classify(&self, chunk: &ChunkCoordinates, bounds: &Bounds) -> Isosurface<VoxelData> {
+        if chunk_position(chunk) != self.position {
+            return Isosurface::Uniform(VoxelData::EMPTY);
+        }
+        let (depth, cell) = node_key(bounds);
+        match self.tree.node(depth, cell) {
+            Tree::Leaf(value) => Isosurface::Uniform(*value),
+            Tree::Branch(_) => Isosurface::Surface,
+        }
+    }
+}
+
+/// Depth and integer cell of a node. `Bounds` are normalized, so a node at depth `d` is `2^-d` wide.
+fn node_key(bounds: &Bounds) -> (u8, [u32; 3]) {
+    let depth = (-bounds.get_width().log2()).round().max(0.0) as u8;
+    let cells = (1u64 << depth) as f32;
+    let position: [f32; 3] = bounds.get_position().into();
+    let cell = |p: f32| (p * cells).round() as u32;
+    (depth, [cell(position[0]), cell(position[1]), cell(position[2])])
+}
+
+fn child_cell(cell: [u32; 3], child: usize) -> [u32; 3] {
+    [
+        cell[0] * 2 + (child & 1) as u32,
+        cell[1] * 2 + ((child >> 1) & 1) as u32,
+        cell[2] * 2 + ((child >> 2) & 1) as u32,
+    ]
+}
+
+pub fn encode(chunk: &Chunk, position: [i32; 3]) -> Result<Vec<u8>, ChunkFormatError> {
+    Ok(Tree::from_chunk(chunk)?.encode(position))
+}
+
+pub fn encode_with(chunk: &Chunk, position: [i32; 3], options: &EncodeOptions) -> Result<Vec<u8>, ChunkFormatError> {
+    Tree::from_chunk(chunk)?.encode_with(position, options)
+}
+
+/// Format version of encoded chunk data, without decoding it
+pub fn format_version(data: &[u8]) -> Result<u16, ChunkFormatError> {
+    if data.len() < 6 {
+        return Err(ChunkFormatError::Truncated);
+    }
+    if &data[..4] != MAGIC {
+        return Err(ChunkFormatError::InvalidMagic);
+    }
+    Ok(u16::from_le_bytes([data[4], data[5]]))
+}
+
+/// The chunk and its position
+pub fn decode(data: &[u8]) -> Result<(Chunk, [i32; 3]), ChunkFormatError> {
+    let (tree, position) = Tree::decode(data)?;
+    Ok((tree.to_chunk(position), position))
+}
+
+pub fn write(mut writer: impl Write, chunk: &Chunk, position: [i32; 3]) -> Result<(), ChunkFormatError> {
+    writer.write_all(&encode(chunk, position)?)?;
+    Ok(())
+}
+
+pub fn read(mut reader: impl Read) -> Result<(Chunk, [i32; 3]), ChunkFormatError> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    decode(&data)
+}
+
+/// Trees and leaf filters shared by tests of code working on chunks
+#[cfg(test)]
+pub mod test_utils {
+    use super::Tree;
+    use crate::octree::VoxelData;
+
+    /// Accepts every leaf that isn't air
+    pub fn solid(value: VoxelData) -> bool {
+        !value.is_empty()
+    }
+
+    /// The bottom half of the chunk is stone
+    pub fn floor() -> Tree {
+        let mut tree = Tree::Leaf(VoxelData::EMPTY);
+        for x in 0..2 {
+            for z in 0..2 {
+                tree.set_node(1, [x, 0, z], Tree::Leaf(VoxelData::solid(1)));
+            }
+        }
+        tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test::Bencher;
+
+    fn sample_tree() -> Tree {
+        let stone = VoxelData::solid(1);
+        let mut inner = vec![Tree::Leaf(VoxelData::EMPTY); 8];
+        inner[3] = Tree::Leaf(stone);
+        let mut children = vec![Tree::Leaf(stone); 8];
+        children[5] = Tree::Branch(inner);
+        children[6] = Tree::Leaf(VoxelData::EMPTY);
+        Tree::Branch(children)
+    }
+
+    fn all_options() -> Vec<EncodeOptions> {
+        let mut options = Vec::new();
+        for &palette in &[false, true] {
+            for &run_length in &[false, true] {
+                options.push(EncodeOptions { palette, run_length, compression: Compression::None });
+            }
+        }
+        #[cfg(feature = "zstd")]
+        options.push(EncodeOptions { compression: Compression::Zstd, ..EncodeOptions::default() });
+        #[cfg(feature = "lz4")]
+        options.push(EncodeOptions { compression: Compression::Lz4, ..EncodeOptions::default() });
+        options
+    }
+
+    /**
+     Encodes a chunk of generated terrain with `options` and reports its size per voxel at the finest
+     level. Run with `cargo bench bytes_per_voxel -- --nocapture`, adding `--features zstd,lz4`
+     to compare the compressed encodings as well.
+     */
+    fn bench_encode(b: &mut Bencher, options: EncodeOptions) {
+        use crate::worldgen::terrain::{Terrain, TerrainSettings};
+
+        let terrain = Terrain::new(TerrainSettings::default());
+        let tree = Tree::from_chunk(&world_builder(&terrain).build(&chunk_coordinates([0, 0, 0]))).unwrap();
+        let bytes = tree.encode_with([0, 0, 0], &options).unwrap();
+        let voxels = 8f64.powi(tree.depth() as i32);
+        println!("{:?}: {} bytes, {:.6} bytes per voxel", options, bytes.len(), bytes.len() as f64 / voxels);
+        b.bytes = bytes.len() as u64;
+        b.iter(|| tree.encode_with([0, 0, 0], &options).unwrap());
+    }
+
+    #[bench]
+    fn bytes_per_voxel_raw(b: &mut Bencher) {
+        bench_encode(b, EncodeOptions { palette: false, run_length: false, compression: Compression::None });
+    }
+
+    #[bench]
+    fn bytes_per_voxel_palette_run_length(b: &mut Bencher) {
+        bench_encode(b, EncodeOptions::default());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[bench]
+    fn bytes_per_voxel_zstd(b: &mut Bencher) {
+        bench_encode(b, EncodeOptions { compression: Compression::Zstd, ..EncodeOptions::default() });
+    }
+
+    #[cfg(feature = "lz4")]
+    #[bench]
+    fn bytes_per_voxel_lz4(b: &mut Bencher) {
+        bench_encode(b, EncodeOptions { compression: Compression::Lz4, ..EncodeOptions::default() });
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for options in all_options() {
+            for tree in &[sample_tree(), Tree::Leaf(VoxelData::solid(7))] {
+                let bytes = tree.encode_with([3, -1, 20], &options).unwrap();
+                assert_eq!(&Tree::decode(&bytes).unwrap(), &(tree.clone(), [3, -1, 20]));
+            }
+        }
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut bytes = Vec::new();
+        for value in &[0, 127, 128, 300, std::u64::MAX] {
+            write_varint(&mut bytes, *value);
+        }
+        let mut cursor = &bytes[..];
+        for value in &[0, 127, 128, 300, std::u64::MAX] {
+            assert_eq!(read_varint(&mut cursor).unwrap(), *value);
+        }
+        assert!(cursor.is_empty());
+    }
+
+    /// `sample_tree` at [3, -1, 20] as version 1 wrote it: raw masks and leaf values
+    const V1_FIXTURE: [u8; 94] = [
+        0x47, 0x4f, 0x47, 0x43, 0x01, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff,
+        0x14, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x0f, 0x00, 0x00, 0x00, 0xec, 0x97, 0x8f, 0x07,
+        0x20, 0x00, 0x01, 0x10, 0x7f, 0x00, 0x01, 0x10, 0x7f, 0x00, 0x01, 0x10, 0x7f, 0x00, 0x01, 0x10,
+        0x7f, 0x00, 0x01, 0x10, 0x7f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x01, 0x10, 0x7f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x10, 0x7f, 0x00,
+    ];
+
+    #[test]
+    fn test_decode_v1() {
+        assert_eq!(format_version(&V1_FIXTURE).unwrap(), 1);
+        assert_eq!(Tree::decode(&V1_FIXTURE).unwrap(), (sample_tree(), [3, -1, 20]));
+        // Re-encoding upgrades to the current version
+        let bytes = sample_tree().encode([3, -1, 20]);
+        assert_eq!(format_version(&bytes).unwrap(), VERSION);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_output_is_bounded() {
+        // A tiny body that decompresses to megabytes, claiming a single leaf
+        let bomb = zstd::encode_all(&vec![0u8; 1 << 20][..], ZSTD_LEVEL).unwrap();
+        assert!(matches!(
+            Compression::Zstd.decompress(&bomb, 1024),
+            Err(ChunkFormatError::Corrupted(_))
+        ));
+        assert_eq!(Compression::Zstd.decompress(&bomb, 1 << 20).unwrap().len(), 1 << 20);
+    }
+
+    #[test]
+    fn test_node_lookup() {
+        let tree = sample_tree();
+        // Child 5 is x = 1, y = 0, z = 1, and its child 3 is x = 1, y = 1, z = 0
+        assert_eq!(tree.node(2, [3, 1, 2]), &Tree::Leaf(VoxelData::solid(1)));
+        assert_eq!(tree.node(2, [2, 1, 2]), &Tree::Leaf(VoxelData::EMPTY));
+        // Deeper than the tree goes
+        assert_eq!(tree.node(4, [0, 0, 0]), &Tree::Leaf(VoxelData::solid(1)));
+    }
+
+    #[test]
+    fn test_set_node() {
+        let stone = VoxelData::solid(1);
+        let mut tree = sample_tree();
+        assert_eq!(tree.node_count(), 17);
+        tree.set_node(1, [1, 0, 1], Tree::Leaf(stone));
+        assert_eq!(tree.node_count(), 9);
+        // Filling the last empty octant merges the root
+        tree.set_node(2, [0, 2, 2], Tree::Leaf(stone));
+        tree.set_node(1, [0, 1, 1], Tree::Leaf(stone));
+        assert_eq!(tree, Tree::Leaf(stone));
+
+        tree.set_node(2, [3, 1, 2], sample_tree());
+        assert_eq!(tree.node(2, [3, 1, 2]), &sample_tree());
+        assert_eq!(tree.depth(), 4);
+    }
+
+    #[test]
+    fn test_errors() {
+        let bytes = sample_tree().encode([0, 0, 0]);
+        assert!(matches!(Tree::decode(&bytes[..bytes.len() - 1]), Err(ChunkFormatError::Truncated)));
+        assert!(matches!(Tree::decode(&bytes[..10]), Err(ChunkFormatError::Truncated)));
+        assert!(matches!(Tree::decode(b"PNG\0 and more"), Err(ChunkFormatError::InvalidMagic)));
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        assert!(matches!(Tree::decode(&corrupted), Err(ChunkFormatError::ChecksumMismatch { .. })));
+
+        let mut future = bytes;
+        future[4] = 99;
+        assert!(matches!(Tree::decode(&future), Err(ChunkFormatError::UnsupportedVersion(99))));
+    }
+}
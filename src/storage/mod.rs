@@ -0,0 +1,3 @@
+pub mod chunk;
+pub mod region;
+pub mod save;
@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::octree::world::ChunkCoordinates;
+use crate::octree::{chunk_position, Chunk};
+use crate::storage::chunk::{self, ChunkFormatError, EncodeOptions};
+
+/// Chunks along every axis of a region
+pub const REGION_SIZE: i32 = 16;
+pub const SECTOR_SIZE: u64 = 4096;
+
+const MAGIC: &[u8; 4] = b"GOGR";
+const VERSION: u32 = 1;
+const CHUNKS_PER_REGION: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+// One sector with magic and version, then the offset table with 8 bytes per chunk
+const TABLE_OFFSET: u64 = SECTOR_SIZE;
+const HEADER_SECTORS: u32 = 1 + (CHUNKS_PER_REGION as u64 * 8 / SECTOR_SIZE) as u32;
+
+#[derive(Debug)]
+pub enum RegionError {
+    Io(PathBuf, io::Error),
+    InvalidHeader(PathBuf),
+    /// Offset table entries pointing outside the file or at each other's sectors
+    CorruptedTable(PathBuf, String),
+    Chunk(PathBuf, ChunkFormatError),
+}
+
+impl fmt::Display for RegionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegionError::Io(path, err) => write!(f, "region {}: {}", path.display(), err),
+            RegionError::InvalidHeader(path) => write!(f, "{} is not a region file", path.display()),
+            RegionError::CorruptedTable(path, reason) => {
+                write!(f, "region {} has a corrupted offset table: {}", path.display(), reason)
+            }
+            RegionError::Chunk(path, err) => write!(f, "region {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl std::error::Error for RegionError {}
+
+/// Region containing a chunk, and the chunk's slot within it
+pub fn region_slot(chunk: [i32; 3]) -> ([i32; 3], usize) {
+    let region = [
+        chunk[0].div_euclid(REGION_SIZE),
+        chunk[1].div_euclid(REGION_SIZE),
+        chunk[2].div_euclid(REGION_SIZE),
+    ];
+    let local = [
+        chunk[0].rem_euclid(REGION_SIZE) as usize,
+        chunk[1].rem_euclid(REGION_SIZE) as usize,
+        chunk[2].rem_euclid(REGION_SIZE) as usize,
+    ];
+    let size = REGION_SIZE as usize;
+    (region, (local[2] * size + local[1]) * size + local[0])
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct Entry {
+    /// First sector, counted from the start of the file
+    offset: u32,
+    /// Allocated sectors, 0 if the chunk is absent
+    sectors: u32,
+}
+
+struct State {
+    file: File,
+    table: Vec<Entry>,
+    /// Whether each sector of the file is in use, including the header
+    used: Vec<bool>,
+}
+
+impl State {
+    /// First run of `count` free sectors, extending the file if there is none
+    fn allocate(&mut self, count: u32) -> u32 {
+        let count = count as usize;
+        let mut run = 0;
+        for (sector, used) in self.used.iter().enumerate() {
+            run = if *used { 0 } else { run + 1 };
+            if run == count {
+                let start = sector + 1 - count;
+                self.mark(start as u32, count as u32, true);
+                return start as u32;
+            }
+        }
+        // A free run at the very end of the file can be extended
+        let start = self.used.len() - run;
+        self.used.resize(start + count, false);
+        self.mark(start as u32, count as u32, true);
+        start as u32
+    }
+
+    fn mark(&mut self, offset: u32, count: u32, used: bool) {
+        for sector in &mut self.used[offset as usize..(offset + count) as usize] {
+            *sector = used;
+        }
+    }
+}
+
+/**
+ A file holding up to `REGION_SIZE³` encoded chunks.
+ Chunks live in runs of whole sectors, located through an offset table at the start of the file.
+ A chunk that outgrows its sectors moves to the first free run that fits, or to the end of the file;
+ the holes it leaves behind are reused later or removed by `compact`.
+ Any number of threads can read at once, writers get exclusive access.
+ */
+pub struct Region {
+    path: PathBuf,
+    state: RwLock<State>,
+}
+
+impl Region {
+    /// Opens the region file at `path`, creating an empty one if it doesn't exist yet.
+    pub fn open(path: impl AsRef<Path>) -> Result<Region, RegionError> {
+        let path = path.as_ref().to_path_buf();
+        let io_error = |err| RegionError::Io(path.clone(), err);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .map_err(io_error)?;
+        let len = file.metadata().map_err(io_error)?.len();
+        if len == 0 {
+            let mut header = vec![0; (HEADER_SECTORS as u64 * SECTOR_SIZE) as usize];
+            header[..4].copy_from_slice(MAGIC);
+            header[4..8].copy_from_slice(&VERSION.to_le_bytes());
+            write_at(&file, &header, 0).map_err(io_error)?;
+        }
+
+        let mut header = vec![0; (HEADER_SECTORS as u64 * SECTOR_SIZE) as usize];
+        read_at(&file, &mut header, 0).map_err(|_| RegionError::InvalidHeader(path.clone()))?;
+        if &header[..4] != MAGIC || header[4..8] != VERSION.to_le_bytes() {
+            return Err(RegionError::InvalidHeader(path));
+        }
+
+        let file_sectors = ((len.max(header.len() as u64) + SECTOR_SIZE - 1) / SECTOR_SIZE) as usize;
+        let mut used = vec![false; file_sectors];
+        for sector in &mut used[..HEADER_SECTORS as usize] {
+            *sector = true;
+        }
+        let mut table = Vec::with_capacity(CHUNKS_PER_REGION);
+        for slot in 0..CHUNKS_PER_REGION {
+            let at = TABLE_OFFSET as usize + slot * 8;
+            let u32_at = |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+            let entry = Entry { offset: u32_at(at), sectors: u32_at(at + 4) };
+            if entry.sectors > 0 {
+                let end = entry.offset as usize + entry.sectors as usize;
+                if entry.offset < HEADER_SECTORS || end > used.len() {
+                    return Err(RegionError::CorruptedTable(path, format!("slot {} points outside the file", slot)));
+                }
+                if used[entry.offset as usize..end].iter().any(|used| *used) {
+                    return Err(RegionError::CorruptedTable(path, format!("slot {} overlaps another chunk", slot)));
+                }
+                for sector in &mut used[entry.offset as usize..end] {
+                    *sector = true;
+                }
+            }
+            table.push(entry);
+        }
+
+        Ok(Region {
+            path,
+            state: RwLock::new(State { file, table, used }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn contains(&self, slot: usize) -> bool {
+        self.state.read().unwrap().table[slot].sectors > 0
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.state.read().unwrap().table.iter().filter(|entry| entry.sectors > 0).count()
+    }
+
+    /// Encoded chunk stored in `slot`
+    pub fn read_raw(&self, slot: usize) -> Result<Option<Vec<u8>>, RegionError> {
+        let state = self.state.read().unwrap();
+        let entry = state.table[slot];
+        if entry.sectors == 0 {
+            return Ok(None);
+        }
+        let offset = entry.offset as u64 * SECTOR_SIZE;
+        let mut len = [0; 4];
+        read_at(&state.file, &mut len, offset).map_err(|err| RegionError::Io(self.path.clone(), err))?;
+        let len = u32::from_le_bytes(len) as u64;
+        if len + 4 > entry.sectors as u64 * SECTOR_SIZE {
+            return Err(RegionError::CorruptedTable(self.path.clone(), format!("slot {} overflows its sectors", slot)));
+        }
+        let mut data = vec![0; len as usize];
+        read_at(&state.file, &mut data, offset + 4).map_err(|err| RegionError::Io(self.path.clone(), err))?;
+        Ok(Some(data))
+    }
+
+    pub fn write_raw(&self, slot: usize, data: &[u8]) -> Result<(), RegionError> {
+        let mut state = self.state.write().unwrap();
+        let needed = ((data.len() as u64 + 4 + SECTOR_SIZE - 1) / SECTOR_SIZE) as u32;
+        let mut entry = state.table[slot];
+        if entry.sectors < needed {
+            state.mark(entry.offset, entry.sectors, false);
+            entry = Entry { offset: state.allocate(needed), sectors: needed };
+        } else if entry.sectors > needed {
+            // Shrink in place and release the tail
+            state.mark(entry.offset + needed, entry.sectors - needed, false);
+            entry.sectors = needed;
+        }
+
+        let mut payload = Vec::with_capacity(needed as usize * SECTOR_SIZE as usize);
+        payload.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        payload.extend_from_slice(data);
+        payload.resize(needed as usize * SECTOR_SIZE as usize, 0);
+        let io_error = |err| RegionError::Io(self.path.clone(), err);
+        write_at(&state.file, &payload, entry.offset as u64 * SECTOR_SIZE).map_err(io_error)?;
+        Self::write_entry(&mut state, slot, entry).map_err(io_error)
+    }
+
+    pub fn remove(&self, slot: usize) -> Result<(), RegionError> {
+        let mut state = self.state.write().unwrap();
+        let entry = state.table[slot];
+        if entry.sectors == 0 {
+            return Ok(());
+        }
+        state.mark(entry.offset, entry.sectors, false);
+        Self::write_entry(&mut state, slot, Entry::default()).map_err(|err| RegionError::Io(self.path.clone(), err))
+    }
+
+    fn write_entry(state: &mut State, slot: usize, entry: Entry) -> io::Result<()> {
+        let mut bytes = [0; 8];
+        bytes[..4].copy_from_slice(&entry.offset.to_le_bytes());
+        bytes[4..].copy_from_slice(&entry.sectors.to_le_bytes());
+        write_at(&state.file, &bytes, TABLE_OFFSET + slot as u64 * 8)?;
+        state.table[slot] = entry;
+        Ok(())
+    }
+
+    pub fn read_chunk(&self, coords: &ChunkCoordinates) -> Result<Option<Chunk>, RegionError> {
+        let position = chunk_position(coords);
+        let data = match self.read_raw(region_slot(position).1)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let (chunk, stored) = chunk::decode(&data).map_err(|err| RegionError::Chunk(self.path.clone(), err))?;
+        if stored != position {
+            let reason = format!("slot of chunk {:?} holds chunk {:?}", position, stored);
+            return Err(RegionError::CorruptedTable(self.path.clone(), reason));
+        }
+        Ok(Some(chunk))
+    }
+
+    pub fn write_chunk(&self, coords: &ChunkCoordinates, chunk: &Chunk, options: &EncodeOptions) -> Result<(), RegionError> {
+        let position = chunk_position(coords);
+        let data = chunk::encode_with(chunk, position, options).map_err(|err| RegionError::Chunk(self.path.clone(), err))?;
+        self.write_raw(region_slot(position).1, &data)
+    }
+
+    /// Rewrites the file with all chunks packed back to back, dropping every free sector.
+    pub fn compact(&self) -> Result<(), RegionError> {
+        let mut state = self.state.write().unwrap();
+        let io_error = |err| RegionError::Io(self.path.clone(), err);
+        let temp_path = self.path.with_extension("compact");
+        let temp = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)
+            .map_err(io_error)?;
+
+        let mut header = vec![0; (HEADER_SECTORS as u64 * SECTOR_SIZE) as usize];
+        header[..4].copy_from_slice(MAGIC);
+        header[4..8].copy_from_slice(&VERSION.to_le_bytes());
+        let mut table = vec![Entry::default(); CHUNKS_PER_REGION];
+        let mut next = HEADER_SECTORS;
+        // Keep the chunks in file order so the copy reads sequentially
+        let mut slots: Vec<usize> = (0..CHUNKS_PER_REGION).filter(|slot| state.table[*slot].sectors > 0).collect();
+        slots.sort_by_key(|slot| state.table[*slot].offset);
+        for slot in slots {
+            let entry = state.table[slot];
+            let mut data = vec![0; (entry.sectors as u64 * SECTOR_SIZE) as usize];
+            read_at(&state.file, &mut data, entry.offset as u64 * SECTOR_SIZE).map_err(io_error)?;
+            write_at(&temp, &data, next as u64 * SECTOR_SIZE).map_err(io_error)?;
+            table[slot] = Entry { offset: next, sectors: entry.sectors };
+            let at = TABLE_OFFSET as usize + slot * 8;
+            header[at..at + 4].copy_from_slice(&next.to_le_bytes());
+            header[at + 4..at + 8].copy_from_slice(&entry.sectors.to_le_bytes());
+            next += entry.sectors;
+        }
+        write_at(&temp, &header, 0).map_err(io_error)?;
+        temp.sync_all().map_err(io_error)?;
+        drop(temp);
+
+        fs::rename(&temp_path, &self.path).map_err(io_error)?;
+        state.file = OpenOptions::new().read(true).write(true).open(&self.path).map_err(io_error)?;
+        state.table = table;
+        state.used = vec![true; next as usize];
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<(), RegionError> {
+        let state = self.state.read().unwrap();
+        state.file.sync_data().map_err(|err| RegionError::Io(self.path.clone(), err))
+    }
+}
+
+/**
+ All region files of a directory, opened on first use.
+ Regions are named `r.<x>.<y>.<z>.region` after their region coordinates.
+ */
+pub struct RegionStore {
+    directory: PathBuf,
+    regions: RwLock<HashMap<[i32; 3], Arc<Region>>>,
+    options: EncodeOptions,
+}
+
+impl RegionStore {
+    pub fn open(directory: impl AsRef<Path>) -> Result<RegionStore, RegionError> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory).map_err(|err| RegionError::Io(directory.clone(), err))?;
+        Ok(RegionStore {
+            directory,
+            regions: RwLock::new(HashMap::new()),
+            options: EncodeOptions::default(),
+        })
+    }
+
+    /// Encoding of chunks saved from now on. Chunks already stored keep theirs.
+    pub fn with_encode_options(mut self, options: EncodeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn region_path(&self, region: [i32; 3]) -> PathBuf {
+        self.directory.join(format!("r.{}.{}.{}.region", region[0], region[1], region[2]))
+    }
+
+    /// The region at `region`, or `None` if it has no file and `create` is false
+    fn region(&self, region: [i32; 3], create: bool) -> Result<Option<Arc<Region>>, RegionError> {
+        if let Some(opened) = self.regions.read().unwrap().get(&region) {
+            return Ok(Some(opened.clone()));
+        }
+        let path = self.region_path(region);
+        if !create && !path.exists() {
+            return Ok(None);
+        }
+        let mut regions = self.regions.write().unwrap();
+        // Another thread may have opened it in the meantime
+        if let Some(opened) = regions.get(&region) {
+            return Ok(Some(opened.clone()));
+        }
+        let opened = Arc::new(Region::open(path)?);
+        regions.insert(region, opened.clone());
+        Ok(Some(opened))
+    }
+
+    pub fn load(&self, coords: &ChunkCoordinates) -> Result<Option<Chunk>, RegionError> {
+        match self.region(region_slot(chunk_position(coords)).0, false)? {
+            Some(region) => region.read_chunk(coords),
+            None => Ok(None),
+        }
+    }
+
+    /// Encoded chunk at `coords`, for callers that inspect the format before decoding
+    pub fn load_raw(&self, coords: &ChunkCoordinates) -> Result<Option<Vec<u8>>, RegionError> {
+        let (region, slot) = region_slot(chunk_position(coords));
+        match self.region(region, false)? {
+            Some(region) => region.read_raw(slot),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save_raw(&self, coords: &ChunkCoordinates, data: &[u8]) -> Result<(), RegionError> {
+        let (region, slot) = region_slot(chunk_position(coords));
+        self.region(region, true)?.unwrap().write_raw(slot, data)
+    }
+
+    pub fn encode_options(&self) -> &EncodeOptions {
+        &self.options
+    }
+
+    pub fn contains(&self, coords: &ChunkCoordinates) -> Result<bool, RegionError> {
+        let (region, slot) = region_slot(chunk_position(coords));
+        Ok(self.region(region, false)?.map_or(false, |region| region.contains(slot)))
+    }
+
+    pub fn save(&self, coords: &ChunkCoordinates, chunk: &Chunk) -> Result<(), RegionError> {
+        let region = self.region(region_slot(chunk_position(coords)).0, true)?.unwrap();
+        region.write_chunk(coords, chunk, &self.options)
+    }
+
+    pub fn remove(&self, coords: &ChunkCoordinates) -> Result<(), RegionError> {
+        let (region, slot) = region_slot(chunk_position(coords));
+        match self.region(region, false)? {
+            Some(region) => region.remove(slot),
+            None => Ok(()),
+        }
+    }
+
+    /// Compacts every region file in the directory.
+    pub fn compact(&self) -> Result<(), RegionError> {
+        let entries = fs::read_dir(&self.directory).map_err(|err| RegionError::Io(self.directory.clone(), err))?;
+        for entry in entries {
+            let path = entry.map_err(|err| RegionError::Io(self.directory.clone(), err))?.path();
+            if let Some(region) = parse_region_name(&path) {
+                if let Some(region) = self.region(region, false)? {
+                    region.compact()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<(), RegionError> {
+        for region in self.regions.read().unwrap().values() {
+            region.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_region_name(path: &Path) -> Option<[i32; 3]> {
+    let name = path.file_name()?.to_str()?;
+    let mut parts = name.strip_prefix("r.")?.strip_suffix(".region")?.split('.');
+    let mut coordinate = || parts.next()?.parse::<i32>().ok();
+    Some([coordinate()?, coordinate()?, coordinate()?])
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("gog-{}-{}.region", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_region_slot() {
+        assert_eq!(region_slot([0, 0, 0]), ([0, 0, 0], 0));
+        assert_eq!(region_slot([-1, 17, 3]), ([-1, 1, 0], (3 * 16 + 1) * 16 + 15));
+    }
+
+    #[test]
+    fn test_write_read_compact() {
+        let path = temp_path("compact");
+        let region = Region::open(&path).unwrap();
+        region.write_raw(0, &[1; 100]).unwrap();
+        region.write_raw(1, &[2; 5000]).unwrap();
+        region.write_raw(2, &[3; 10]).unwrap();
+        // Outgrows its sector and moves to the end, leaving a hole
+        region.write_raw(0, &[4; 9000]).unwrap();
+        region.remove(2).unwrap();
+        assert_eq!(region.chunk_count(), 2);
+        assert_eq!(region.read_raw(0).unwrap(), Some(vec![4; 9000]));
+        assert_eq!(region.read_raw(2).unwrap(), None);
+
+        let before = fs::metadata(&path).unwrap().len();
+        region.compact().unwrap();
+        let after = fs::metadata(&path).unwrap().len();
+        assert_eq!(after, (HEADER_SECTORS as u64 + 2 + 3) * SECTOR_SIZE);
+        assert!(after < before);
+        drop(region);
+
+        let region = Region::open(&path).unwrap();
+        assert_eq!(region.read_raw(0).unwrap(), Some(vec![4; 9000]));
+        assert_eq!(region.read_raw(1).unwrap(), Some(vec![2; 5000]));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_reads() {
+        let path = temp_path("concurrent");
+        let region = Arc::new(Region::open(&path).unwrap());
+        for slot in 0..32 {
+            region.write_raw(slot, &vec![slot as u8; 100 + slot * 300]).unwrap();
+        }
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let region = region.clone();
+                std::thread::spawn(move || {
+                    for slot in 0..32 {
+                        assert_eq!(region.read_raw(slot).unwrap(), Some(vec![slot as u8; 100 + slot * 300]));
+                    }
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
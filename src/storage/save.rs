@@ -0,0 +1,479 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::material::{MaterialDefinition, MaterialError, MaterialRegistry};
+use crate::octree::world::ChunkCoordinates;
+use crate::octree::{chunk_position, Chunk};
+use crate::storage::chunk::{self, ChunkFormatError, EncodeOptions, Tree};
+use crate::storage::region::{RegionError, RegionStore};
+use crate::worldgen::biome::{BiomeError, BiomeMap, BiomeSettings};
+use crate::worldgen::caves::{CaveSettings, Caves};
+
+/// Version of the save directory layout and manifest
+pub const WORLD_FORMAT_VERSION: u32 = 1;
+pub const MANIFEST_FILE: &str = "world.ron";
+pub const REGION_DIRECTORY: &str = "regions";
+
+// Decorrelates the cave noise from the biome noise when both derive from the world seed
+const CAVE_SEED_OFFSET: u64 = 0x9e37_79b9_7f4a_7c15;
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, ron::de::Error),
+    Serialize(ron::ser::Error),
+    /// Written by a newer version of the game
+    UnsupportedVersion(u32),
+    /// No migration is registered to upgrade a manifest from this version
+    MissingMigration(u32),
+    Migration { from: u32, reason: String },
+    Material(MaterialError),
+    Region(RegionError),
+    Chunk(ChunkFormatError),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io(path, err) => write!(f, "failed to access {}: {}", path.display(), err),
+            SaveError::Parse(path, err) => write!(f, "failed to parse {}: {}", path.display(), err),
+            SaveError::Serialize(err) => write!(f, "failed to serialize the world manifest: {}", err),
+            SaveError::UnsupportedVersion(version) => {
+                write!(f, "world format version {} is newer than the supported {}", version, WORLD_FORMAT_VERSION)
+            }
+            SaveError::MissingMigration(version) => write!(f, "no migration from world format version {}", version),
+            SaveError::Migration { from, reason } => write!(f, "migration from version {} failed: {}", from, reason),
+            SaveError::Material(err) => write!(f, "{}", err),
+            SaveError::Region(err) => write!(f, "{}", err),
+            SaveError::Chunk(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<RegionError> for SaveError {
+    fn from(err: RegionError) -> Self {
+        SaveError::Region(err)
+    }
+}
+
+impl From<ChunkFormatError> for SaveError {
+    fn from(err: ChunkFormatError) -> Self {
+        SaveError::Chunk(err)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GeneratorSettings {
+    pub biomes: BiomeSettings,
+    pub caves: CaveSettings,
+}
+
+impl GeneratorSettings {
+    /// Builds the generator with all noise seeded from the world seed.
+    pub fn build(&self, seed: u64, materials: &MaterialRegistry) -> Result<Caves<BiomeMap>, BiomeError> {
+        let biomes = BiomeSettings { seed, ..self.biomes.clone() };
+        let caves = CaveSettings { seed: seed ^ CAVE_SEED_OFFSET, ..self.caves.clone() };
+        Ok(Caves::new(BiomeMap::new(biomes, materials)?, caves))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CameraState {
+    pub position: [f32; 3],
+    /// Unit quaternion as (x, y, z, w)
+    pub rotation: [f32; 4],
+}
+
+impl Default for CameraState {
+    fn default() -> Self {
+        Self {
+            position: [0.0, 0.5, 2.0],
+            rotation: [0.0, 0.0, 0.0, 1.0],
+        }
+    }
+}
+
+/// Contents of `world.ron`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WorldManifest {
+    pub format_version: u32,
+    pub seed: u64,
+    pub generator: GeneratorSettings,
+    /// The registry the world was generated with, so saved material ids keep their meaning
+    pub materials: Vec<MaterialDefinition>,
+    #[serde(default)]
+    pub camera: CameraState,
+    #[serde(default)]
+    pub chunk_encoding: EncodeOptions,
+}
+
+impl WorldManifest {
+    pub fn new(seed: u64, generator: GeneratorSettings, materials: &MaterialRegistry) -> Self {
+        Self {
+            format_version: WORLD_FORMAT_VERSION,
+            seed,
+            generator,
+            materials: materials.iter().cloned().collect(),
+            camera: CameraState::default(),
+            chunk_encoding: EncodeOptions::default(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct VersionProbe {
+    format_version: u32,
+}
+
+pub type ManifestMigration = Box<dyn Fn(String) -> Result<String, String> + Send + Sync>;
+pub type ChunkMigration = Box<dyn Fn(Tree) -> Result<Tree, String> + Send + Sync>;
+
+/**
+ Upgrade steps applied while loading older saves.
+ A manifest migration registered for version `n` turns the RON source of a version `n` manifest into
+ that of a version `n + 1` one, and every step between the saved and the current version must exist.
+ Migrations work on the source rather than a `ron::Value`, which can't represent enum variants; the
+ usual migration parses the source into a struct mirroring the old version and writes the new one.
+ Chunk migrations are optional, since the chunk decoder reads all older versions. Chunks stored in
+ an older format are re-encoded in the current one the first time they are loaded.
+ */
+#[derive(Default)]
+pub struct Migrations {
+    manifest: BTreeMap<u32, ManifestMigration>,
+    chunk: BTreeMap<u16, ChunkMigration>,
+}
+
+impl Migrations {
+    pub fn with_manifest_migration(
+        mut self,
+        from: u32,
+        migration: impl Fn(String) -> Result<String, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.manifest.insert(from, Box::new(migration));
+        self
+    }
+
+    pub fn with_chunk_migration(
+        mut self,
+        from: u16,
+        migration: impl Fn(Tree) -> Result<Tree, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.chunk.insert(from, Box::new(migration));
+        self
+    }
+
+    fn migrate_manifest(&self, mut source: String, from: u32) -> Result<String, SaveError> {
+        for version in from..WORLD_FORMAT_VERSION {
+            let migration = self.manifest.get(&version).ok_or(SaveError::MissingMigration(version))?;
+            source = migration(source).map_err(|reason| SaveError::Migration { from: version, reason })?;
+        }
+        Ok(source)
+    }
+
+    fn migrate_chunk(&self, mut tree: Tree, from: u16) -> Result<Tree, SaveError> {
+        for (version, migration) in self.chunk.range(from..chunk::VERSION) {
+            tree = migration(tree).map_err(|reason| SaveError::Migration { from: *version as u32, reason })?;
+        }
+        Ok(tree)
+    }
+}
+
+/**
+ A world saved to disk:
+
+ ```text
+ <root>/world.ron       manifest
+ <root>/regions/        region files holding the chunks
+ ```
+ */
+pub struct WorldSave {
+    root: PathBuf,
+    manifest: WorldManifest,
+    regions: RegionStore,
+    migrations: Migrations,
+}
+
+impl WorldSave {
+    pub fn exists(root: impl AsRef<Path>) -> bool {
+        root.as_ref().join(MANIFEST_FILE).is_file()
+    }
+
+    /// Starts a new save at `root`, replacing any manifest there.
+    pub fn create(root: impl AsRef<Path>, manifest: WorldManifest) -> Result<Self, SaveError> {
+        let root = root.as_ref().to_path_buf();
+        let regions = RegionStore::open(root.join(REGION_DIRECTORY))?.with_encode_options(manifest.chunk_encoding);
+        let save = Self {
+            root,
+            manifest,
+            regions,
+            migrations: Migrations::default(),
+        };
+        save.save_manifest()?;
+        Ok(save)
+    }
+
+    /**
+     Opens an existing save, upgrading its manifest if it is older than `WORLD_FORMAT_VERSION`.
+     The original manifest of an upgraded save is kept as `world.v<version>.ron`.
+     */
+    pub fn open(root: impl AsRef<Path>, migrations: Migrations) -> Result<Self, SaveError> {
+        let root = root.as_ref().to_path_buf();
+        let path = root.join(MANIFEST_FILE);
+        let source = fs::read_to_string(&path).map_err(|err| SaveError::Io(path.clone(), err))?;
+        let probe: VersionProbe = ron::de::from_str(&source).map_err(|err| SaveError::Parse(path.clone(), err))?;
+        if probe.format_version > WORLD_FORMAT_VERSION {
+            return Err(SaveError::UnsupportedVersion(probe.format_version));
+        }
+
+        let manifest = if probe.format_version < WORLD_FORMAT_VERSION {
+            let migrated = migrations.migrate_manifest(source, probe.format_version)?;
+            let mut manifest: WorldManifest = ron::de::from_str(&migrated).map_err(|err| SaveError::Parse(path.clone(), err))?;
+            manifest.format_version = WORLD_FORMAT_VERSION;
+            let backup = root.join(format!("world.v{}.ron", probe.format_version));
+            fs::copy(&path, &backup).map_err(|err| SaveError::Io(backup, err))?;
+            log::info!("Upgraded {} from format version {}", path.display(), probe.format_version);
+            manifest
+        } else {
+            ron::de::from_str(&source).map_err(|err| SaveError::Parse(path.clone(), err))?
+        };
+
+        let regions = RegionStore::open(root.join(REGION_DIRECTORY))?.with_encode_options(manifest.chunk_encoding);
+        let save = Self { root, manifest, regions, migrations };
+        if probe.format_version < WORLD_FORMAT_VERSION {
+            save.save_manifest()?;
+        }
+        Ok(save)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn manifest(&self) -> &WorldManifest {
+        &self.manifest
+    }
+
+    /// Changes are written by the next `save_manifest`.
+    pub fn manifest_mut(&mut self) -> &mut WorldManifest {
+        &mut self.manifest
+    }
+
+    pub fn save_manifest(&self) -> Result<(), SaveError> {
+        let path = self.root.join(MANIFEST_FILE);
+        let source = ron::ser::to_string_pretty(&self.manifest, PrettyConfig::default()).map_err(SaveError::Serialize)?;
+        // Write then rename, so a crash never leaves a half written manifest behind
+        let temp = self.root.join(format!("{}.tmp", MANIFEST_FILE));
+        fs::write(&temp, source).map_err(|err| SaveError::Io(temp.clone(), err))?;
+        fs::rename(&temp, &path).map_err(|err| SaveError::Io(path, err))
+    }
+
+    /// The material registry snapshot, with textures checked against `assets_dir` if given
+    pub fn materials(&self, assets_dir: Option<&Path>) -> Result<MaterialRegistry, SaveError> {
+        MaterialRegistry::from_definitions(self.manifest.materials.clone(), assets_dir).map_err(SaveError::Material)
+    }
+
+    pub fn generator(&self, materials: &MaterialRegistry) -> Result<Caves<BiomeMap>, BiomeError> {
+        self.manifest.generator.build(self.manifest.seed, materials)
+    }
+
+    pub fn regions(&self) -> &RegionStore {
+        &self.regions
+    }
+
+    pub fn load_chunk(&self, coords: &ChunkCoordinates) -> Result<Option<Chunk>, SaveError> {
+        let data = match self.regions.load_raw(coords)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let version = chunk::format_version(&data)?;
+        let (tree, position) = Tree::decode(&data)?;
+        if position != chunk_position(coords) {
+            return Err(SaveError::Chunk(ChunkFormatError::Corrupted(format!(
+                "expected chunk {:?}, found {:?}",
+                chunk_position(coords),
+                position
+            ))));
+        }
+        let tree = if version < chunk::VERSION {
+            let tree = self.migrations.migrate_chunk(tree, version)?;
+            let encoded = tree.encode_with(position, self.regions.encode_options())?;
+            self.regions.save_raw(coords, &encoded)?;
+            tree
+        } else {
+            tree
+        };
+        Ok(Some(tree.to_chunk(position)))
+    }
+
+    pub fn save_chunk(&self, coords: &ChunkCoordinates, chunk: &Chunk) -> Result<(), SaveError> {
+        Ok(self.regions.save(coords, chunk)?)
+    }
+
+    /// Writes the manifest and flushes all region files.
+    pub fn flush(&self) -> Result<(), SaveError> {
+        self.save_manifest()?;
+        Ok(self.regions.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> WorldManifest {
+        let biomes = BiomeSettings {
+            seed: 0,
+            climate_frequency: 0.01,
+            octaves: 3,
+            frequency: 0.05,
+            persistence: 0.5,
+            lacunarity: 2.0,
+            chunk_size: 64.0,
+            voxel_size: 0.5,
+            height_blend: 0.2,
+            biomes: Vec::new(),
+        };
+        let generator = GeneratorSettings { biomes, caves: CaveSettings::default() };
+        WorldManifest::new(42, generator, &MaterialRegistry::default())
+    }
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("gog-save-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn write_manifest(root: &Path, format_version: u32) {
+        let manifest = WorldManifest { format_version, ..manifest() };
+        let source = ron::ser::to_string_pretty(&manifest, PrettyConfig::default()).unwrap();
+        fs::write(root.join(MANIFEST_FILE), source).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let root = temp_root("roundtrip");
+        let mut save = WorldSave::create(&root, manifest()).unwrap();
+        save.manifest_mut().camera.position = [1.0, 2.0, 3.0];
+        save.save_manifest().unwrap();
+        let opened = WorldSave::open(&root, Migrations::default()).unwrap();
+        assert_eq!(opened.manifest(), save.manifest());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_migrations() {
+        let root = temp_root("migrations");
+        write_manifest(&root, 0);
+        assert!(matches!(WorldSave::open(&root, Migrations::default()), Err(SaveError::MissingMigration(0))));
+
+        let migrations = Migrations::default().with_manifest_migration(0, Ok);
+        let save = WorldSave::open(&root, migrations).unwrap();
+        assert_eq!(save.manifest().format_version, WORLD_FORMAT_VERSION);
+        assert!(root.join("world.v0.ron").is_file());
+        // The upgraded manifest was written back
+        assert!(WorldSave::open(&root, Migrations::default()).is_ok());
+
+        write_manifest(&root, WORLD_FORMAT_VERSION + 1);
+        assert!(matches!(WorldSave::open(&root, Migrations::default()), Err(SaveError::UnsupportedVersion(_))));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// A manifest as it might have looked before the seed was renamed and the chunk encoding stored
+    #[derive(Serialize, Deserialize)]
+    struct ManifestV0 {
+        format_version: u32,
+        world_seed: u64,
+        generator: GeneratorSettings,
+        materials: Vec<MaterialDefinition>,
+        camera: CameraState,
+    }
+
+    fn migrate_v0(source: String) -> Result<String, String> {
+        let old: ManifestV0 = ron::de::from_str(&source).map_err(|err| err.to_string())?;
+        let manifest = WorldManifest {
+            format_version: 1,
+            seed: old.world_seed,
+            generator: old.generator,
+            materials: old.materials,
+            camera: old.camera,
+            chunk_encoding: EncodeOptions::default(),
+        };
+        ron::ser::to_string_pretty(&manifest, PrettyConfig::default()).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn test_migrate_realistic_manifest() {
+        use crate::material::Albedo;
+        use crate::worldgen::biome::{BiomeDefinition, Layer};
+
+        let root = temp_root("migrate-realistic");
+        let materials = vec![
+            MaterialDefinition {
+                id: 1,
+                name: "stone".to_string(),
+                albedo: Albedo::Color([0.5, 0.5, 0.5, 1.0]),
+                roughness: 0.9,
+                metallic: 0.0,
+                transparent: false,
+                solid: true,
+            },
+            MaterialDefinition {
+                id: 2,
+                name: "grass".to_string(),
+                albedo: Albedo::Texture("textures/grass.png".to_string()),
+                roughness: 0.8,
+                metallic: 0.0,
+                transparent: false,
+                solid: true,
+            },
+        ];
+        let mut generator = manifest().generator;
+        generator.biomes.biomes.push(BiomeDefinition {
+            name: "plains".to_string(),
+            temperature: 0.0,
+            humidity: 0.0,
+            base_height: 16.0,
+            amplitude: 4.0,
+            layers: vec![Layer { material: "grass".to_string(), depth: 1.0 }],
+            base: "stone".to_string(),
+            vegetation_density: 0.1,
+            vegetation: Some("grass".to_string()),
+        });
+        let camera = CameraState { position: [1.0, 20.0, -3.0], rotation: [0.0, 0.6, 0.0, 0.8] };
+        let old = ManifestV0 {
+            format_version: 0,
+            world_seed: 1234,
+            generator: generator.clone(),
+            materials: materials.clone(),
+            camera: camera.clone(),
+        };
+        let source = ron::ser::to_string_pretty(&old, PrettyConfig::default()).unwrap();
+        fs::write(root.join(MANIFEST_FILE), source).unwrap();
+
+        let save = WorldSave::open(&root, Migrations::default().with_manifest_migration(0, migrate_v0)).unwrap();
+        let expected = WorldManifest {
+            format_version: WORLD_FORMAT_VERSION,
+            seed: 1234,
+            generator,
+            materials,
+            camera,
+            chunk_encoding: EncodeOptions::default(),
+        };
+        assert_eq!(save.manifest(), &expected);
+        // Written back in the current format
+        assert_eq!(WorldSave::open(&root, Migrations::default()).unwrap().manifest(), &expected);
+
+        let failing = Migrations::default().with_manifest_migration(0, |_| Err("no".to_string()));
+        write_manifest(&root, 0);
+        assert!(matches!(WorldSave::open(&root, failing), Err(SaveError::Migration { from: 0, .. })));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
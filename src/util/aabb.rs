@@ -0,0 +1,62 @@
+use crate::octree::bounds::Bounds;
+use crate::octree::chunk_position;
+use crate::octree::world::ChunkCoordinates;
+
+/// Axis aligned bounding box in world units.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+impl Aabb {
+    pub fn new(min: [f32; 3], max: [f32; 3]) -> Self {
+        Self { min, max }
+    }
+
+    pub fn from_center(center: [f32; 3], half_extents: [f32; 3]) -> Self {
+        Self {
+            min: [center[0] - half_extents[0], center[1] - half_extents[1], center[2] - half_extents[2]],
+            max: [center[0] + half_extents[0], center[1] + half_extents[1], center[2] + half_extents[2]],
+        }
+    }
+
+    /// World space extents of an octree node. `Bounds` are normalized to the chunk,
+    /// and each chunk spans `chunk_size` world units.
+    pub fn from_bounds(chunk: &ChunkCoordinates, bounds: &Bounds, chunk_size: f32) -> Self {
+        let chunk = chunk_position(chunk);
+        let position: [f32; 3] = bounds.get_position().into();
+        let width = bounds.get_width() * chunk_size;
+        let mut min = [0.0; 3];
+        let mut max = [0.0; 3];
+        for i in 0..3 {
+            min[i] = (chunk[i] as f32 + position[i]) * chunk_size;
+            max[i] = min[i] + width;
+        }
+        Self { min, max }
+    }
+
+    pub fn center(&self) -> [f32; 3] {
+        [
+            (self.min[0] + self.max[0]) * 0.5,
+            (self.min[1] + self.max[1]) * 0.5,
+            (self.min[2] + self.max[2]) * 0.5,
+        ]
+    }
+
+    pub fn size(&self) -> [f32; 3] {
+        [self.max[0] - self.min[0], self.max[1] - self.min[1], self.max[2] - self.min[2]]
+    }
+
+    pub fn contains_point(&self, point: [f32; 3]) -> bool {
+        (0..3).all(|i| self.min[i] <= point[i] && point[i] <= self.max[i])
+    }
+
+    pub fn overlaps(&self, other: &Aabb) -> bool {
+        (0..3).all(|i| self.min[i] < other.max[i] && other.min[i] < self.max[i])
+    }
+
+    pub fn contains(&self, other: &Aabb) -> bool {
+        (0..3).all(|i| self.min[i] <= other.min[i] && other.max[i] <= self.max[i])
+    }
+}
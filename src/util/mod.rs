@@ -1,2 +1,3 @@
 pub mod gridline;
 pub mod tuple_strip;
+pub mod aabb;
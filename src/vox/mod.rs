@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::material::{Albedo, MaterialRegistry};
+use crate::octree::{Chunk, VoxelData};
+use crate::worldgen::grid::VoxelGrid;
+
+pub mod reader;
+pub mod writer;
+
+/// A single MagicaVoxel model. Coordinates are in MagicaVoxel space, where Z is up.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Model {
+    pub size: [u32; 3],
+    /// (x, y, z, palette index). Index 0 is never stored, it means empty.
+    pub voxels: Vec<[u8; 4]>,
+}
+
+/**
+ Rotation and translation of a model within the scene.
+ `rotation` is a signed permutation matrix, stored row by row.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transform {
+    pub rotation: [[i32; 3]; 3],
+    pub translation: [i32; 3],
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            rotation: [[1, 0, 0], [0, 1, 0], [0, 0, 1]],
+            translation: [0, 0, 0],
+        }
+    }
+}
+
+impl Transform {
+    /// Decodes the packed `_r` rotation byte of a transform node
+    pub fn rotation_from_byte(byte: u8) -> Result<[[i32; 3]; 3], VoxError> {
+        let first = (byte & 0b11) as usize;
+        let second = ((byte >> 2) & 0b11) as usize;
+        // The two columns are stored, the third is whichever is left
+        if first > 2 || second > 2 || first == second {
+            return Err(VoxError::Malformed(format!("rotation {} is not a permutation", byte)));
+        }
+        let third = 3 - first - second;
+        let mut rotation = [[0; 3]; 3];
+        for (row, (column, sign_bit)) in [(first, 4), (second, 5), (third, 6)].iter().enumerate() {
+            rotation[row][*column] = if byte & (1 << sign_bit) == 0 { 1 } else { -1 };
+        }
+        Ok(rotation)
+    }
+
+    /// Inverse of `rotation_from_byte`
+    pub fn rotation_to_byte(rotation: &[[i32; 3]; 3]) -> u8 {
+        let column = |row: &[i32; 3]| row.iter().position(|v| *v != 0).unwrap_or(0) as u8;
+        let mut byte = column(&rotation[0]) | (column(&rotation[1]) << 2);
+        for (row, sign_bit) in rotation.iter().zip(&[4, 5, 6]) {
+            if row.iter().any(|v| *v < 0) {
+                byte |= 1 << sign_bit;
+            }
+        }
+        byte
+    }
+
+    /// `self` applied after `child`
+    pub fn then(&self, child: &Transform) -> Transform {
+        let mut rotation = [[0; 3]; 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                rotation[i][j] = (0..3).map(|k| self.rotation[i][k] * child.rotation[k][j]).sum();
+            }
+        }
+        Transform {
+            rotation,
+            translation: add(self.apply_rotation(child.translation), self.translation),
+        }
+    }
+
+    pub fn apply_rotation(&self, p: [i32; 3]) -> [i32; 3] {
+        let mut out = [0; 3];
+        for i in 0..3 {
+            out[i] = (0..3).map(|k| self.rotation[i][k] * p[k]).sum();
+        }
+        out
+    }
+}
+
+/// Saturates instead of overflowing on absurd translations, which `VoxScene::to_grid` then rejects
+fn add(a: [i32; 3], b: [i32; 3]) -> [i32; 3] {
+    [a[0].saturating_add(b[0]), a[1].saturating_add(b[1]), a[2].saturating_add(b[2])]
+}
+
+/// A model placed into the scene
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Instance {
+    pub model: usize,
+    pub transform: Transform,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoxScene {
+    pub models: Vec<Model>,
+    pub instances: Vec<Instance>,
+    /// RGBA colors. Palette index `i` of a voxel refers to `palette[i - 1]`.
+    pub palette: [[u8; 4]; 256],
+}
+
+#[derive(Debug)]
+pub enum VoxError {
+    Io(std::io::Error),
+    InvalidMagic,
+    UnexpectedChunk { expected: &'static str, found: String },
+    Malformed(String),
+    /// Referenced palette entries that the palette mapping has no material for
+    UnmappedColor(u8),
+    /// A palette only has room for 255 materials
+    TooManyMaterials(usize),
+    /// The instances span more than `MAX_SCENE_EXTENT` voxels along an axis
+    SceneTooLarge(i64),
+}
+
+impl fmt::Display for VoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoxError::Io(err) => write!(f, "{}", err),
+            VoxError::InvalidMagic => write!(f, "not a MagicaVoxel file"),
+            VoxError::UnexpectedChunk { expected, found } => write!(f, "expected a {} chunk, found {}", expected, found),
+            VoxError::Malformed(reason) => write!(f, "malformed vox file: {}", reason),
+            VoxError::UnmappedColor(index) => write!(f, "palette index {} has no material", index),
+            VoxError::TooManyMaterials(count) => write!(f, "{} materials do not fit into a palette of 255", count),
+            VoxError::SceneTooLarge(extent) => {
+                write!(f, "scene spans {} voxels, at most {} can be imported", extent, MAX_SCENE_EXTENT)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VoxError {}
+
+/// Largest scene `VoxScene::to_grid` rasterizes, along any axis. The grid is dense, so this bounds its memory.
+pub const MAX_SCENE_EXTENT: i64 = 512;
+
+impl From<std::io::Error> for VoxError {
+    fn from(err: std::io::Error) -> Self {
+        VoxError::Io(err)
+    }
+}
+
+/// Translates palette indices into voxels
+#[derive(Clone, Debug)]
+pub struct PaletteMapping {
+    /// Indexed by palette index, entry 0 is always empty
+    voxels: [VoxelData; 256],
+}
+
+impl PaletteMapping {
+    /**
+     Maps every palette color to the registry material with the closest albedo color.
+     Textured materials are never picked.
+     */
+    pub fn nearest(palette: &[[u8; 4]; 256], materials: &MaterialRegistry) -> Self {
+        let mut voxels = [VoxelData::EMPTY; 256];
+        for index in 1..256 {
+            let color = palette[index - 1];
+            let nearest = materials
+                .iter()
+                .filter_map(|material| match material.albedo {
+                    Albedo::Color(albedo) => Some((material.id, color_distance(color, albedo))),
+                    Albedo::Texture(_) => None,
+                })
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+            if let Some((id, _)) = nearest {
+                voxels[index] = materials.voxel(id);
+            }
+        }
+        Self { voxels }
+    }
+
+    pub fn from_overrides(overrides: &HashMap<u8, VoxelData>) -> Self {
+        let mut voxels = [VoxelData::EMPTY; 256];
+        for (index, voxel) in overrides {
+            if *index != 0 {
+                voxels[*index as usize] = *voxel;
+            }
+        }
+        Self { voxels }
+    }
+
+    pub fn get(&self, index: u8) -> VoxelData {
+        self.voxels[index as usize]
+    }
+
+    pub fn set(&mut self, index: u8, voxel: VoxelData) {
+        if index != 0 {
+            self.voxels[index as usize] = voxel;
+        }
+    }
+}
+
+fn color_distance(color: [u8; 4], albedo: [f32; 4]) -> f32 {
+    (0..3)
+        .map(|i| (color[i] as f32 / 255.0 - albedo[i]).powi(2))
+        .sum()
+}
+
+impl VoxScene {
+    /// Minimum and maximum corner of all instances, in MagicaVoxel space
+    pub fn extents(&self) -> Option<([i64; 3], [i64; 3])> {
+        let mut extents: Option<([i64; 3], [i64; 3])> = None;
+        for instance in &self.instances {
+            let model = &self.models[instance.model];
+            if model.voxels.is_empty() {
+                continue;
+            }
+            for corner in 0..8 {
+                let p = [
+                    if corner & 1 == 0 { 0 } else { model.size[0] as i32 - 1 },
+                    if corner & 2 == 0 { 0 } else { model.size[1] as i32 - 1 },
+                    if corner & 4 == 0 { 0 } else { model.size[2] as i32 - 1 },
+                ];
+                let p = place(instance, model, p);
+                extents = Some(match extents {
+                    None => (p, p),
+                    Some((min, max)) => (
+                        [min[0].min(p[0]), min[1].min(p[1]), min[2].min(p[2])],
+                        [max[0].max(p[0]), max[1].max(p[1]), max[2].max(p[2])],
+                    ),
+                });
+            }
+        }
+        extents
+    }
+
+    /**
+     Rasterizes all instances into a grid, converting from MagicaVoxel's Z-up space to Y-up by a
+     quarter turn around X: (x, y, z) becomes (x, z, -y). Simply swapping Y and Z would mirror the scene.
+     The scene is shifted so that its minimum corner lands on the grid origin.
+     Scenes wider than `MAX_SCENE_EXTENT` are rejected.
+     */
+    pub fn to_grid(&self, mapping: &PaletteMapping) -> Result<VoxelGrid, VoxError> {
+        let (min, max) = match self.extents() {
+            Some(extents) => extents,
+            None => return Ok(VoxelGrid::with_extent(1)),
+        };
+        let extent = (0..3).map(|i| max[i] - min[i] + 1).max().unwrap();
+        if extent > MAX_SCENE_EXTENT {
+            return Err(VoxError::SceneTooLarge(extent));
+        }
+        let mut grid = VoxelGrid::with_extent(extent as u32);
+        for instance in &self.instances {
+            let model = &self.models[instance.model];
+            for voxel in &model.voxels {
+                if (0..3).any(|axis| voxel[axis] as u32 >= model.size[axis]) {
+                    return Err(VoxError::Malformed(format!(
+                        "voxel at {:?} lies outside of a model of size {:?}",
+                        &voxel[..3],
+                        model.size
+                    )));
+                }
+                let value = mapping.get(voxel[3]);
+                if value.is_empty() {
+                    return Err(VoxError::UnmappedColor(voxel[3]));
+                }
+                let p = place(instance, model, [voxel[0] as i32, voxel[1] as i32, voxel[2] as i32]);
+                grid.set((p[0] - min[0]) as u32, (p[2] - min[2]) as u32, (max[1] - p[1]) as u32, value);
+            }
+        }
+        Ok(grid)
+    }
+
+    pub fn to_chunk(&self, mapping: &PaletteMapping) -> Result<Chunk, VoxError> {
+        Ok(self.to_grid(mapping)?.build_chunk([0, 0, 0]))
+    }
+}
+
+/// Scene position of a model voxel. MagicaVoxel rotates models around their center.
+fn place(instance: &Instance, model: &Model, p: [i32; 3]) -> [i64; 3] {
+    let half = [model.size[0] as i32 / 2, model.size[1] as i32 / 2, model.size[2] as i32 / 2];
+    let centered = [p[0] - half[0], p[1] - half[1], p[2] - half[2]];
+    let rotated = instance.transform.apply_rotation(centered);
+    let translation = instance.transform.translation;
+    [
+        rotated[0] as i64 + translation[0] as i64,
+        rotated[1] as i64 + translation[1] as i64,
+        rotated[2] as i64 + translation[2] as i64,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vox::reader::default_palette;
+
+    fn scene(model: Model) -> VoxScene {
+        VoxScene {
+            models: vec![model],
+            instances: vec![Instance { model: 0, transform: Transform::default() }],
+            palette: default_palette(),
+        }
+    }
+
+    fn mapping() -> PaletteMapping {
+        let mut mapping = PaletteMapping::from_overrides(&HashMap::new());
+        mapping.set(1, VoxelData::solid(1));
+        mapping
+    }
+
+    #[test]
+    fn test_import_is_not_mirrored() {
+        // Arms of different lengths along MagicaVoxel's +X, +Y and +Z from one corner
+        let model = Model {
+            size: [4, 3, 2],
+            voxels: vec![
+                [0, 0, 0, 1],
+                [1, 0, 0, 1], [2, 0, 0, 1], [3, 0, 0, 1],
+                [0, 1, 0, 1], [0, 2, 0, 1],
+                [0, 0, 1, 1],
+            ],
+        };
+        let grid = scene(model).to_grid(&mapping()).unwrap();
+        let mut solid = Vec::new();
+        for x in 0..grid.size() {
+            for y in 0..grid.size() {
+                for z in 0..grid.size() {
+                    if !grid.get(x, y, z).is_empty() {
+                        solid.push([x, y, z]);
+                    }
+                }
+            }
+        }
+        // +X stays +X, +Z (up) becomes +Y and +Y (forward) becomes -Z. X × -Z = Y just like
+        // X × Y = Z before, so the corner keeps its handedness. A mirror image would grow +Y towards +Z.
+        let mut expected = vec![
+            [0, 0, 2],
+            [1, 0, 2], [2, 0, 2], [3, 0, 2],
+            [0, 0, 1], [0, 0, 0],
+            [0, 1, 2],
+        ];
+        expected.sort();
+        assert_eq!(solid, expected);
+    }
+
+    #[test]
+    fn test_voxel_outside_model() {
+        let model = Model { size: [2, 2, 2], voxels: vec![[0, 0, 0, 1], [0, 2, 0, 1]] };
+        assert!(matches!(scene(model).to_grid(&mapping()), Err(VoxError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_rotation_byte_roundtrip() {
+        // Every signed permutation matrix
+        for &(first, second) in &[(0, 1), (0, 2), (1, 0), (1, 2), (2, 0), (2, 1)] {
+            for signs in 0..8u8 {
+                let byte = first | (second << 2) | (signs << 4);
+                let rotation = Transform::rotation_from_byte(byte).unwrap();
+                assert_eq!(Transform::rotation_to_byte(&rotation), byte);
+            }
+        }
+        assert_eq!(Transform::rotation_from_byte(0b0000100).unwrap(), Transform::default().rotation);
+    }
+
+    #[test]
+    fn test_invalid_rotation_byte() {
+        // Column 3 doesn't exist, and both rows can't pick the same column
+        for &byte in &[0b0000_0000, 0b0000_0011, 0b0000_1010, 0b0000_1100] {
+            assert!(matches!(Transform::rotation_from_byte(byte), Err(VoxError::Malformed(_))));
+        }
+    }
+
+    #[test]
+    fn test_scene_too_large() {
+        let model = Model { size: [1, 1, 1], voxels: vec![[0, 0, 0, 1]] };
+        let mut scene = scene(model);
+        scene.instances.push(Instance {
+            model: 0,
+            transform: Transform { translation: [10_000, 0, 0], ..Transform::default() },
+        });
+        assert!(matches!(scene.to_grid(&mapping()), Err(VoxError::SceneTooLarge(10_001))));
+
+        // Extreme translations don't overflow either
+        scene.instances[1].transform.translation = [std::i32::MAX, 0, std::i32::MIN];
+        assert!(matches!(scene.to_grid(&mapping()), Err(VoxError::SceneTooLarge(_))));
+    }
+
+    #[test]
+    fn test_compose() {
+        let quarter_turn = Transform {
+            rotation: [[0, -1, 0], [1, 0, 0], [0, 0, 1]],
+            translation: [10, 0, 0],
+        };
+        let child = Transform { translation: [1, 2, 3], ..Transform::default() };
+        let combined = quarter_turn.then(&child);
+        assert_eq!(combined.translation, [8, 1, 3]);
+        assert_eq!(combined.rotation, quarter_turn.rotation);
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::vox::{Instance, Model, Transform, VoxError, VoxScene};
+
+/// Scene graph nodes, keyed by node id
+enum Node {
+    Transform { child: i32, transform: Transform },
+    Group { children: Vec<i32> },
+    Shape { models: Vec<i32> },
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], VoxError> {
+        let end = self.position.checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| VoxError::Malformed("unexpected end of file".to_string()))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, VoxError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn i32(&mut self) -> Result<i32, VoxError> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn string(&mut self) -> Result<String, VoxError> {
+        let len = self.u32()? as usize;
+        let bytes = self.bytes(len)?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    fn dict(&mut self) -> Result<HashMap<String, String>, VoxError> {
+        let len = self.u32()?;
+        let mut dict = HashMap::new();
+        for _ in 0..len {
+            let key = self.string()?;
+            let value = self.string()?;
+            dict.insert(key, value);
+        }
+        Ok(dict)
+    }
+
+    /// (id, content, children)
+    fn chunk(&mut self) -> Result<(String, &'a [u8], &'a [u8]), VoxError> {
+        let id = String::from_utf8_lossy(self.bytes(4)?).into_owned();
+        let content_len = self.u32()? as usize;
+        let children_len = self.u32()? as usize;
+        let content = self.bytes(content_len)?;
+        let children = self.bytes(children_len)?;
+        Ok((id, content, children))
+    }
+}
+
+pub fn read_file(path: impl AsRef<Path>) -> Result<VoxScene, VoxError> {
+    read(File::open(path)?)
+}
+
+pub fn read(mut reader: impl Read) -> Result<VoxScene, VoxError> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    parse(&data)
+}
+
+pub fn parse(data: &[u8]) -> Result<VoxScene, VoxError> {
+    let mut cursor = Cursor::new(data);
+    if cursor.bytes(4).map_err(|_| VoxError::InvalidMagic)? != b"VOX " {
+        return Err(VoxError::InvalidMagic);
+    }
+    let _version = cursor.u32()?;
+    let (id, _, children) = cursor.chunk()?;
+    if id != "MAIN" {
+        return Err(VoxError::UnexpectedChunk { expected: "MAIN", found: id });
+    }
+
+    let mut models = Vec::new();
+    let mut size = None;
+    let mut palette = None;
+    let mut nodes = HashMap::new();
+
+    let mut cursor = Cursor::new(children);
+    while !cursor.is_empty() {
+        let (id, content, _) = cursor.chunk()?;
+        let mut content = Cursor::new(content);
+        match id.as_str() {
+            "SIZE" => {
+                size = Some([content.u32()?, content.u32()?, content.u32()?]);
+            }
+            "XYZI" => {
+                let size = size.take().ok_or_else(|| VoxError::UnexpectedChunk {
+                    expected: "SIZE",
+                    found: id.clone(),
+                })?;
+                let count = content.u32()? as usize;
+                let bytes = content.bytes(count.checked_mul(4).unwrap_or(std::usize::MAX))?;
+                let voxels = bytes
+                    .chunks_exact(4)
+                    .map(|v| [v[0], v[1], v[2], v[3]])
+                    .filter(|v| v[3] != 0)
+                    .collect();
+                models.push(Model { size, voxels });
+            }
+            "RGBA" => {
+                let mut colors = [[0; 4]; 256];
+                for color in colors.iter_mut() {
+                    let bytes = content.bytes(4)?;
+                    *color = [bytes[0], bytes[1], bytes[2], bytes[3]];
+                }
+                palette = Some(colors);
+            }
+            "nTRN" => {
+                let node = content.i32()?;
+                let _attributes = content.dict()?;
+                let child = content.i32()?;
+                let _reserved = content.i32()?;
+                let _layer = content.i32()?;
+                let frames = content.u32()?;
+                let mut transform = Transform::default();
+                // Animated transforms are not supported, only the first frame is used
+                for frame in 0..frames {
+                    let attributes = content.dict()?;
+                    if frame > 0 {
+                        continue;
+                    }
+                    if let Some(rotation) = attributes.get("_r") {
+                        let byte = rotation.trim().parse::<u8>()
+                            .map_err(|_| VoxError::Malformed(format!("invalid rotation {}", rotation)))?;
+                        transform.rotation = Transform::rotation_from_byte(byte)?;
+                    }
+                    if let Some(translation) = attributes.get("_t") {
+                        let values = translation
+                            .split_whitespace()
+                            .map(|v| v.parse::<i32>())
+                            .collect::<Result<Vec<_>, _>>()
+                            .ok()
+                            .filter(|v| v.len() == 3)
+                            .ok_or_else(|| VoxError::Malformed(format!("invalid translation {}", translation)))?;
+                        transform.translation = [values[0], values[1], values[2]];
+                    }
+                }
+                nodes.insert(node, Node::Transform { child, transform });
+            }
+            "nGRP" => {
+                let node = content.i32()?;
+                let _attributes = content.dict()?;
+                let count = content.u32()?;
+                let children = (0..count).map(|_| content.i32()).collect::<Result<Vec<_>, _>>()?;
+                nodes.insert(node, Node::Group { children });
+            }
+            "nSHP" => {
+                let node = content.i32()?;
+                let _attributes = content.dict()?;
+                let count = content.u32()?;
+                let shape_models = (0..count)
+                    .map(|_| {
+                        let model = content.i32()?;
+                        let _attributes = content.dict()?;
+                        Ok(model)
+                    })
+                    .collect::<Result<Vec<_>, VoxError>>()?;
+                nodes.insert(node, Node::Shape { models: shape_models });
+            }
+            // Materials, layers, cameras and render settings don't affect the voxels
+            _ => {}
+        }
+    }
+
+    let instances = if nodes.is_empty() {
+        (0..models.len())
+            .map(|model| Instance { model, transform: Transform::default() })
+            .collect()
+    } else {
+        let mut instances = Vec::new();
+        collect_instances(&nodes, 0, Transform::default(), models.len(), &mut instances, 0)?;
+        instances
+    };
+
+    Ok(VoxScene {
+        models,
+        instances,
+        palette: palette.unwrap_or_else(default_palette),
+    })
+}
+
+fn collect_instances(
+    nodes: &HashMap<i32, Node>,
+    id: i32,
+    transform: Transform,
+    model_count: usize,
+    instances: &mut Vec<Instance>,
+    depth: usize,
+) -> Result<(), VoxError> {
+    // A well formed scene graph is a tree, so this only trips on cycles
+    if depth > nodes.len() {
+        return Err(VoxError::Malformed("scene graph contains a cycle".to_string()));
+    }
+    match nodes.get(&id) {
+        None => Err(VoxError::Malformed(format!("missing scene node {}", id))),
+        Some(Node::Transform { child, transform: local }) => {
+            collect_instances(nodes, *child, transform.then(local), model_count, instances, depth + 1)
+        }
+        Some(Node::Group { children }) => {
+            for child in children {
+                collect_instances(nodes, *child, transform, model_count, instances, depth + 1)?;
+            }
+            Ok(())
+        }
+        Some(Node::Shape { models }) => {
+            for model in models {
+                if *model < 0 || *model as usize >= model_count {
+                    return Err(VoxError::Malformed(format!("shape references missing model {}", model)));
+                }
+                instances.push(Instance { model: *model as usize, transform });
+            }
+            Ok(())
+        }
+    }
+}
+
+/**
+ The palette MagicaVoxel uses for files without an RGBA chunk: a 6x6x6 color cube without black,
+ followed by ramps of red, green, blue and gray.
+ */
+pub fn default_palette() -> [[u8; 4]; 256] {
+    const CUBE: [u8; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    const RAMP: [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+    let mut colors = Vec::with_capacity(256);
+    for r in CUBE.iter() {
+        for g in CUBE.iter() {
+            for b in CUBE.iter() {
+                if (*r, *g, *b) != (0, 0, 0) {
+                    colors.push([*r, *g, *b, 0xff]);
+                }
+            }
+        }
+    }
+    for channel in 0..3 {
+        for value in RAMP.iter() {
+            let mut color = [0, 0, 0, 0xff];
+            color[channel] = *value;
+            colors.push(color);
+        }
+    }
+    for value in RAMP.iter() {
+        colors.push([*value, *value, *value, 0xff]);
+    }
+    let mut palette = [[0; 4]; 256];
+    palette[..colors.len()].copy_from_slice(&colors);
+    palette
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], content: &[u8], children: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(children.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(content);
+        bytes.extend_from_slice(children);
+        bytes
+    }
+
+    #[test]
+    fn test_parse_single_model() {
+        let mut size = Vec::new();
+        for v in &[2u32, 3, 4] {
+            size.extend_from_slice(&v.to_le_bytes());
+        }
+        let mut xyzi = 2u32.to_le_bytes().to_vec();
+        xyzi.extend_from_slice(&[0, 0, 0, 1, 1, 2, 3, 7]);
+        let mut children = chunk(b"SIZE", &size, &[]);
+        children.extend(chunk(b"XYZI", &xyzi, &[]));
+        let mut file = b"VOX ".to_vec();
+        file.extend_from_slice(&150u32.to_le_bytes());
+        file.extend(chunk(b"MAIN", &[], &children));
+
+        let scene = parse(&file).unwrap();
+        assert_eq!(scene.models.len(), 1);
+        assert_eq!(scene.models[0].size, [2, 3, 4]);
+        assert_eq!(scene.models[0].voxels, vec![[0, 0, 0, 1], [1, 2, 3, 7]]);
+        assert_eq!(scene.instances.len(), 1);
+        assert_eq!(scene.palette, default_palette());
+        assert_eq!(scene.palette[0], [0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut file = b"VOX ".to_vec();
+        file.extend_from_slice(&150u32.to_le_bytes());
+        file.extend_from_slice(b"MAIN");
+        assert!(matches!(parse(&file), Err(VoxError::Malformed(_))));
+        assert!(matches!(parse(b"PNG"), Err(VoxError::InvalidMagic)));
+    }
+}
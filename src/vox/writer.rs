@@ -0,0 +1,337 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::material::{Albedo, MaterialRegistry};
+use crate::octree::bounds::Bounds;
+use crate::octree::{chunk_coordinates, Chunk};
+use crate::util::aabb::Aabb;
+use crate::vox::{Instance, Model, Transform, VoxError, VoxScene};
+
+/// MagicaVoxel refuses models larger than this along any axis
+pub const MAX_MODEL_SIZE: u32 = 256;
+
+// Color for materials without a plain albedo color
+const FALLBACK_COLOR: [u8; 4] = [0x80, 0x80, 0x80, 0xff];
+
+/**
+ Converts a chunk, or the part of it within `region`, into a MagicaVoxel scene.
+ The chunk is sampled at `resolution` voxels along every axis, and split into as many
+ 256³ models as needed. Each material becomes one palette entry.
+ Y-up is turned into MagicaVoxel's Z-up by the inverse of the rotation `VoxScene::to_grid` applies.
+ */
+pub fn scene_from_chunk(
+    chunk: &Chunk,
+    region: Option<&Bounds>,
+    resolution: u32,
+    materials: &MaterialRegistry,
+) -> Result<VoxScene, VoxError> {
+    let origin = chunk_coordinates([0, 0, 0]);
+    let region = region.map(|bounds| Aabb::from_bounds(&origin, bounds, resolution as f32));
+    let to_cells = |min: f32, max: f32| (min.max(0.0).round() as u32, (max.round() as u32).min(resolution));
+
+    let mut palette_indices: HashMap<u16, u8> = HashMap::new();
+    let mut palette = [[0; 4]; 256];
+    // Keyed by the model's block position, in MagicaVoxel's Z-up space
+    let mut blocks: BTreeMap<[u32; 3], Model> = BTreeMap::new();
+
+    for leaf in chunk.iter_leaf() {
+        let value = *leaf.get_value();
+        if value.is_empty() {
+            continue;
+        }
+        let mut aabb = Aabb::from_bounds(&origin, &leaf.get_bounds(), resolution as f32);
+        if let Some(region) = &region {
+            if !region.overlaps(&aabb) {
+                continue;
+            }
+            for i in 0..3 {
+                aabb.min[i] = aabb.min[i].max(region.min[i]);
+                aabb.max[i] = aabb.max[i].min(region.max[i]);
+            }
+        }
+
+        let next_index = palette_indices.len() + 1;
+        let index = match palette_indices.get(&value.material()) {
+            Some(index) => *index,
+            None => {
+                if next_index > 255 {
+                    return Err(VoxError::TooManyMaterials(next_index));
+                }
+                palette[next_index - 1] = match materials.get(value.material()).map(|m| &m.albedo) {
+                    Some(Albedo::Color(color)) => {
+                        let channel = |c: f32| (c.max(0.0).min(1.0) * 255.0).round() as u8;
+                        [channel(color[0]), channel(color[1]), channel(color[2]), channel(color[3])]
+                    }
+                    _ => FALLBACK_COLOR,
+                };
+                palette_indices.insert(value.material(), next_index as u8);
+                next_index as u8
+            }
+        };
+
+        let (x0, x1) = to_cells(aabb.min[0], aabb.max[0]);
+        let (y0, y1) = to_cells(aabb.min[1], aabb.max[1]);
+        let (z0, z1) = to_cells(aabb.min[2], aabb.max[2]);
+        for y in y0..y1 {
+            for z in z0..z1 {
+                for x in x0..x1 {
+                    // Y-up to Z-up: (x, y, z) becomes (x, -z, y), shifted back into the chunk
+                    let p = [x, resolution - 1 - z, y];
+                    let block = [p[0] / MAX_MODEL_SIZE, p[1] / MAX_MODEL_SIZE, p[2] / MAX_MODEL_SIZE];
+                    let model = blocks.entry(block).or_insert_with(|| {
+                        let size = |axis: usize| (resolution - block[axis] * MAX_MODEL_SIZE).min(MAX_MODEL_SIZE);
+                        Model { size: [size(0), size(1), size(2)], voxels: Vec::new() }
+                    });
+                    model.voxels.push([
+                        (p[0] % MAX_MODEL_SIZE) as u8,
+                        (p[1] % MAX_MODEL_SIZE) as u8,
+                        (p[2] % MAX_MODEL_SIZE) as u8,
+                        index,
+                    ]);
+                }
+            }
+        }
+    }
+
+    let mut models = Vec::with_capacity(blocks.len());
+    let mut instances = Vec::with_capacity(blocks.len());
+    for (block, model) in blocks {
+        // MagicaVoxel places a model by its center
+        let translation = [
+            (block[0] * MAX_MODEL_SIZE + model.size[0] / 2) as i32,
+            (block[1] * MAX_MODEL_SIZE + model.size[1] / 2) as i32,
+            (block[2] * MAX_MODEL_SIZE + model.size[2] / 2) as i32,
+        ];
+        instances.push(Instance {
+            model: models.len(),
+            transform: Transform { translation, ..Transform::default() },
+        });
+        models.push(model);
+    }
+    Ok(VoxScene { models, instances, palette })
+}
+
+pub fn write_file(path: impl AsRef<Path>, scene: &VoxScene) -> Result<(), VoxError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write(&mut writer, scene)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn write(mut writer: impl Write, scene: &VoxScene) -> Result<(), VoxError> {
+    writer.write_all(&to_bytes(scene))?;
+    Ok(())
+}
+
+pub fn to_bytes(scene: &VoxScene) -> Vec<u8> {
+    let mut children = Vec::new();
+    for model in &scene.models {
+        let mut size = Vec::with_capacity(12);
+        for axis in &model.size {
+            size.extend_from_slice(&axis.to_le_bytes());
+        }
+        chunk(&mut children, b"SIZE", &size);
+
+        let mut xyzi = Vec::with_capacity(4 + model.voxels.len() * 4);
+        xyzi.extend_from_slice(&(model.voxels.len() as u32).to_le_bytes());
+        for voxel in &model.voxels {
+            xyzi.extend_from_slice(voxel);
+        }
+        chunk(&mut children, b"XYZI", &xyzi);
+    }
+
+    // Scene graph: root transform -> group -> (transform -> shape) per instance
+    let mut content = Vec::new();
+    transform_node(&mut content, 0, 1, &Transform::default());
+    chunk(&mut children, b"nTRN", &content);
+
+    let mut content = Vec::new();
+    push_i32(&mut content, 1);
+    push_u32(&mut content, 0);
+    push_u32(&mut content, scene.instances.len() as u32);
+    for i in 0..scene.instances.len() {
+        push_i32(&mut content, 2 + i as i32 * 2);
+    }
+    chunk(&mut children, b"nGRP", &content);
+
+    for (i, instance) in scene.instances.iter().enumerate() {
+        let mut content = Vec::new();
+        transform_node(&mut content, 2 + i as i32 * 2, 3 + i as i32 * 2, &instance.transform);
+        chunk(&mut children, b"nTRN", &content);
+
+        let mut content = Vec::new();
+        push_i32(&mut content, 3 + i as i32 * 2);
+        push_u32(&mut content, 0);
+        push_u32(&mut content, 1);
+        push_i32(&mut content, instance.model as i32);
+        push_u32(&mut content, 0);
+        chunk(&mut children, b"nSHP", &content);
+    }
+
+    let mut rgba = Vec::with_capacity(256 * 4);
+    for color in scene.palette.iter() {
+        rgba.extend_from_slice(color);
+    }
+    chunk(&mut children, b"RGBA", &rgba);
+
+    let mut bytes = b"VOX ".to_vec();
+    push_u32(&mut bytes, 150);
+    bytes.extend_from_slice(b"MAIN");
+    push_u32(&mut bytes, 0);
+    push_u32(&mut bytes, children.len() as u32);
+    bytes.extend(children);
+    bytes
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_i32(out: &mut Vec<u8>, value: i32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_string(out: &mut Vec<u8>, value: &str) {
+    push_u32(out, value.len() as u32);
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8]) {
+    out.extend_from_slice(id);
+    push_u32(out, content.len() as u32);
+    push_u32(out, 0);
+    out.extend_from_slice(content);
+}
+
+fn transform_node(out: &mut Vec<u8>, id: i32, child: i32, transform: &Transform) {
+    push_i32(out, id);
+    push_u32(out, 0);
+    push_i32(out, child);
+    push_i32(out, -1);
+    push_i32(out, 0);
+    push_u32(out, 1);
+
+    let rotation = Transform::rotation_to_byte(&transform.rotation).to_string();
+    let t = transform.translation;
+    let translation = format!("{} {} {}", t[0], t[1], t[2]);
+    push_u32(out, 2);
+    push_string(out, "_r");
+    push_string(out, &rotation);
+    push_string(out, "_t");
+    push_string(out, &translation);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::VoxelData;
+    use crate::storage::chunk::Tree;
+    use crate::vox::reader::{default_palette, parse};
+    use crate::vox::PaletteMapping;
+
+    fn chunk(depth: u8, cells: &[[u32; 3]]) -> Chunk {
+        let mut tree = Tree::Leaf(VoxelData::EMPTY);
+        for cell in cells {
+            tree.set_node(depth, *cell, Tree::Leaf(VoxelData::solid(1)));
+        }
+        tree.to_chunk([0, 0, 0])
+    }
+
+    fn solid_cells(scene: &VoxScene) -> Vec<[u32; 3]> {
+        let mut mapping = PaletteMapping::from_overrides(&HashMap::new());
+        mapping.set(1, VoxelData::solid(1));
+        let grid = scene.to_grid(&mapping).unwrap();
+        let mut cells = Vec::new();
+        for x in 0..grid.size() {
+            for y in 0..grid.size() {
+                for z in 0..grid.size() {
+                    if !grid.get(x, y, z).is_empty() {
+                        cells.push([x, y, z]);
+                    }
+                }
+            }
+        }
+        cells
+    }
+
+    #[test]
+    fn test_export_import_keeps_orientation() {
+        // A corner with arms of different lengths along X, Y and Z, which mirroring would show
+        let mut cells = vec![[0, 0, 0], [1, 0, 0], [2, 0, 0], [0, 1, 0], [0, 0, 1], [0, 0, 2], [0, 0, 3]];
+        // The far corner, so the scene spans the whole chunk and isn't shifted on import
+        cells.push([7, 7, 7]);
+        let scene = scene_from_chunk(&chunk(3, &cells), None, 8, &MaterialRegistry::default()).unwrap();
+        assert_eq!(scene.models.len(), 1);
+        assert_eq!(scene.models[0].size, [8, 8, 8]);
+        // Up is Z in MagicaVoxel
+        assert!(scene.models[0].voxels.contains(&[0, 7, 1, 1]));
+        cells.sort();
+        assert_eq!(solid_cells(&scene), cells);
+    }
+
+    #[test]
+    fn test_region() {
+        let stone = chunk(1, &[[0, 0, 0]]);
+        let region = Bounds::from_discrete_grid((2, 2, 2), 4, 8);
+        let scene = scene_from_chunk(&stone, Some(&region), 8, &MaterialRegistry::default()).unwrap();
+        // The octant covers [0, 4) and the region [2, 6) along every axis
+        let voxels = &scene.models[0].voxels;
+        assert_eq!(voxels.len(), 8);
+        for voxel in voxels {
+            assert!(voxel[..3].iter().all(|v| *v >= 2 && *v < 6));
+        }
+        assert_eq!(scene.palette[0], FALLBACK_COLOR);
+    }
+
+    #[test]
+    fn test_split_into_models() {
+        let resolution = 2 * MAX_MODEL_SIZE;
+        let stone = chunk(9, &[[0, 0, 0], [300, 5, 10], [10, 400, 300]]);
+        let scene = scene_from_chunk(&stone, None, resolution, &MaterialRegistry::default()).unwrap();
+        assert_eq!(scene.models.len(), 3);
+        assert_eq!(scene.instances.len(), 3);
+        // Every model is placed by its center at its block, so voxels land where they were
+        let half = MAX_MODEL_SIZE as i32 / 2;
+        let mut positions = Vec::new();
+        for instance in &scene.instances {
+            let model = &scene.models[instance.model];
+            assert_eq!(model.size, [MAX_MODEL_SIZE; 3]);
+            assert_eq!(model.voxels.len(), 1);
+            let voxel = model.voxels[0];
+            let t = instance.transform.translation;
+            positions.push([
+                t[0] - half + voxel[0] as i32,
+                t[1] - half + voxel[1] as i32,
+                t[2] - half + voxel[2] as i32,
+            ]);
+        }
+        positions.sort();
+        let last = resolution as i32 - 1;
+        assert_eq!(positions, vec![[0, last, 0], [10, last - 300, 400], [300, last - 10, 5]]);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let scene = VoxScene {
+            models: vec![
+                Model { size: [4, 4, 4], voxels: vec![[0, 0, 0, 1], [3, 2, 1, 200]] },
+                Model { size: [1, 2, 3], voxels: vec![[0, 1, 2, 5]] },
+            ],
+            instances: vec![
+                Instance { model: 0, transform: Transform::default() },
+                Instance {
+                    model: 1,
+                    transform: Transform {
+                        rotation: Transform::rotation_from_byte(0b0010001).unwrap(),
+                        translation: [5, -3, 8],
+                    },
+                },
+                Instance { model: 0, transform: Transform { translation: [0, 0, 40], ..Transform::default() } },
+            ],
+            palette: default_palette(),
+        };
+        assert_eq!(parse(&to_bytes(&scene)).unwrap(), scene);
+    }
+}
@@ -0,0 +1,249 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use amethyst::core::math::{Matrix4, Point3};
+
+use crate::voxelize::{Triangle, TriangleMesh};
+
+#[derive(Debug)]
+pub enum MeshError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    Gltf(PathBuf, gltf::Error),
+    UnsupportedFormat(PathBuf),
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MeshError::Io(path, err) => write!(f, "failed to read {}: {}", path.display(), err),
+            MeshError::Parse(path, reason) => write!(f, "failed to parse {}: {}", path.display(), reason),
+            MeshError::Gltf(path, err) => write!(f, "failed to load {}: {}", path.display(), err),
+            MeshError::UnsupportedFormat(path) => write!(f, "unsupported mesh format: {}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for MeshError {}
+
+/// Loads a triangle mesh, picking the format by file extension.
+pub fn load(path: impl AsRef<Path>) -> Result<TriangleMesh, MeshError> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("obj") => load_obj(path),
+        Some("stl") => load_stl(path),
+        Some("gltf") | Some("glb") => load_gltf(path),
+        _ => Err(MeshError::UnsupportedFormat(path.to_path_buf())),
+    }
+}
+
+pub fn load_obj(path: &Path) -> Result<TriangleMesh, MeshError> {
+    let source = fs::read_to_string(path).map_err(|err| MeshError::Io(path.to_path_buf(), err))?;
+    parse_obj(&source).map_err(|reason| MeshError::Parse(path.to_path_buf(), reason))
+}
+
+pub fn load_stl(path: &Path) -> Result<TriangleMesh, MeshError> {
+    let data = fs::read(path).map_err(|err| MeshError::Io(path.to_path_buf(), err))?;
+    parse_stl(&data).map_err(|reason| MeshError::Parse(path.to_path_buf(), reason))
+}
+
+/// Loads all meshes of the default scene, with node transforms applied.
+pub fn load_gltf(path: &Path) -> Result<TriangleMesh, MeshError> {
+    let (document, buffers, _) = gltf::import(path).map_err(|err| MeshError::Gltf(path.to_path_buf(), err))?;
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| MeshError::Parse(path.to_path_buf(), "no scene".to_string()))?;
+    let mut mesh = TriangleMesh::default();
+    for node in scene.nodes() {
+        collect_gltf_node(&node, Matrix4::identity(), &buffers, &mut mesh);
+    }
+    if !mesh.triangles.iter().flatten().all(is_finite) {
+        return Err(MeshError::Parse(path.to_path_buf(), "vertex positions are not finite".to_string()));
+    }
+    Ok(mesh)
+}
+
+/// NaN or infinite coordinates can't be sorted into the voxelizer's BVH, loaders reject them
+fn is_finite(point: &Point3<f32>) -> bool {
+    point.coords.iter().all(|v| v.is_finite())
+}
+
+fn collect_gltf_node(node: &gltf::Node, parent: Matrix4<f32>, buffers: &[gltf::buffer::Data], mesh: &mut TriangleMesh) {
+    let transform = parent * Matrix4::from(node.transform().matrix());
+    if let Some(node_mesh) = node.mesh() {
+        for primitive in node_mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                continue;
+            }
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let positions: Vec<Point3<f32>> = match reader.read_positions() {
+                Some(positions) => positions
+                    .map(|p| transform.transform_point(&Point3::from(p)))
+                    .collect(),
+                None => continue,
+            };
+            let indices: Vec<usize> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().map(|i| i as usize).collect(),
+                None => (0..positions.len()).collect(),
+            };
+            mesh.triangles.extend(
+                indices
+                    .chunks_exact(3)
+                    .filter(|t| t.iter().all(|i| *i < positions.len()))
+                    .map(|t| [positions[t[0]], positions[t[1]], positions[t[2]]]),
+            );
+        }
+    }
+    for child in node.children() {
+        collect_gltf_node(&child, transform, buffers, mesh);
+    }
+}
+
+/// Vertices and faces of a Wavefront OBJ file. Polygons are triangulated as fans.
+pub fn parse_obj(source: &str) -> Result<TriangleMesh, String> {
+    let mut vertices: Vec<Point3<f32>> = Vec::new();
+    let mut mesh = TriangleMesh::default();
+    for (number, line) in source.lines().enumerate() {
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("v") => {
+                let coordinates = tokens
+                    .take(3)
+                    .map(|t| t.parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()
+                    .filter(|c| c.len() == 3 && c.iter().all(|v| v.is_finite()))
+                    .ok_or_else(|| format!("invalid vertex on line {}", number + 1))?;
+                vertices.push(Point3::new(coordinates[0], coordinates[1], coordinates[2]));
+            }
+            Some("f") => {
+                let face = tokens
+                    .map(|t| {
+                        // "v", "v/vt", "v//vn" or "v/vt/vn", negative indices count from the end
+                        let index = t.split('/').next().and_then(|i| i.parse::<i64>().ok())?;
+                        let index = if index < 0 { vertices.len() as i64 + index } else { index - 1 };
+                        vertices.get(index as usize).copied()
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| format!("invalid face on line {}", number + 1))?;
+                for i in 1..face.len().saturating_sub(1) {
+                    mesh.triangles.push([face[0], face[i], face[i + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(mesh)
+}
+
+/// Binary or ASCII STL
+pub fn parse_stl(data: &[u8]) -> Result<TriangleMesh, String> {
+    // ASCII files start with "solid", but so do some binary exporters. The size settles it.
+    if data.len() >= 84 {
+        let count = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+        if count.checked_mul(50).and_then(|len| len.checked_add(84)) == Some(data.len()) {
+            return parse_binary_stl(&data[84..], count);
+        }
+    }
+    if data.starts_with(b"solid") {
+        return parse_ascii_stl(&String::from_utf8_lossy(data));
+    }
+    Err("neither a binary nor an ASCII STL file".to_string())
+}
+
+fn parse_binary_stl(data: &[u8], count: usize) -> Result<TriangleMesh, String> {
+    let float = |bytes: &[u8]| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let triangles = data
+        .chunks_exact(50)
+        .take(count)
+        .enumerate()
+        .map(|(number, record)| {
+            // 12 bytes of normal, three vertices, and two bytes of attributes
+            let vertex = |i: usize| {
+                let offset = 12 + i * 12;
+                Point3::new(float(&record[offset..]), float(&record[offset + 4..]), float(&record[offset + 8..]))
+            };
+            let triangle = [vertex(0), vertex(1), vertex(2)];
+            if triangle.iter().all(is_finite) {
+                Ok(triangle)
+            } else {
+                Err(format!("triangle {} has a vertex that is not finite", number))
+            }
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(TriangleMesh { triangles })
+}
+
+fn parse_ascii_stl(source: &str) -> Result<TriangleMesh, String> {
+    let mut mesh = TriangleMesh::default();
+    let mut facet: Vec<Point3<f32>> = Vec::with_capacity(3);
+    for (number, line) in source.lines().enumerate() {
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("vertex") => {
+                let coordinates = tokens
+                    .map(|t| t.parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()
+                    .filter(|c| c.len() == 3 && c.iter().all(|v| v.is_finite()))
+                    .ok_or_else(|| format!("invalid vertex on line {}", number + 1))?;
+                facet.push(Point3::new(coordinates[0], coordinates[1], coordinates[2]));
+            }
+            Some("endfacet") => {
+                if facet.len() != 3 {
+                    return Err(format!("facet ending on line {} has {} vertices", number + 1, facet.len()));
+                }
+                let triangle: Triangle = [facet[0], facet[1], facet[2]];
+                mesh.triangles.push(triangle);
+                facet.clear();
+            }
+            _ => {}
+        }
+    }
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_obj() {
+        let source = "# quad\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1 4//1\nf -4 -3 -2\n";
+        let mesh = parse_obj(source).unwrap();
+        assert_eq!(mesh.triangles.len(), 3);
+        assert_eq!(mesh.triangles[1][2], Point3::new(0.0, 1.0, 0.0));
+        assert!(parse_obj("f 1 2 3").is_err());
+    }
+
+    #[test]
+    fn test_parse_stl() {
+        let ascii = "solid t\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\nvertex 0 1 0\nendloop\nendfacet\nendsolid t\n";
+        let mesh = parse_stl(ascii.as_bytes()).unwrap();
+        assert_eq!(mesh.triangles.len(), 1);
+
+        let mut binary = vec![0u8; 80];
+        binary.extend_from_slice(&1u32.to_le_bytes());
+        binary.extend_from_slice(&[0; 12]);
+        for v in &[0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+            binary.extend_from_slice(&v.to_le_bytes());
+        }
+        binary.extend_from_slice(&[0; 2]);
+        assert_eq!(parse_stl(&binary).unwrap().triangles, mesh.triangles);
+
+        binary[96..100].copy_from_slice(&std::f32::NAN.to_le_bytes());
+        assert!(parse_stl(&binary).is_err());
+        assert!(parse_stl(ascii.replace("vertex 1 0 0", "vertex 1 inf 0").as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_non_finite_obj_vertex() {
+        assert!(parse_obj("v 0 0 0\nv nan 0 0\nv 0 1 0\nf 1 2 3\n").is_err());
+    }
+}
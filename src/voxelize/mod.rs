@@ -0,0 +1,357 @@
+use amethyst::core::math::{Point3, Vector3};
+
+use crate::octree::bounds::Bounds;
+use crate::octree::world::ChunkCoordinates;
+use crate::octree::world_builder::Isosurface;
+use crate::octree::VoxelData;
+use crate::util::aabb::Aabb;
+use crate::worldgen::{is_voxel_sized, Oracle};
+
+pub mod loader;
+
+pub type Triangle = [Point3<f32>; 3];
+
+// Leaves of the triangle hierarchy hold at most this many triangles
+const BVH_LEAF_SIZE: usize = 4;
+
+#[derive(Clone, Debug, Default)]
+pub struct TriangleMesh {
+    pub triangles: Vec<Triangle>,
+}
+
+impl TriangleMesh {
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        let mut points = self.triangles.iter().flat_map(|triangle| triangle.iter());
+        let first = points.next()?;
+        let mut aabb = Aabb::new(first.coords.into(), first.coords.into());
+        for p in points {
+            for i in 0..3 {
+                aabb.min[i] = aabb.min[i].min(p[i]);
+                aabb.max[i] = aabb.max[i].max(p[i]);
+            }
+        }
+        Some(aabb)
+    }
+
+    /// Uniformly scales and moves the mesh so that it fits into `target`, centered.
+    pub fn fit_to(&mut self, target: &Aabb) {
+        let source = match self.bounding_box() {
+            Some(aabb) => aabb,
+            None => return,
+        };
+        let (source_size, target_size) = (source.size(), target.size());
+        let scale = (0..3)
+            .filter(|i| source_size[*i] > 0.0)
+            .map(|i| target_size[i] / source_size[i])
+            .fold(std::f32::INFINITY, f32::min);
+        let scale = if scale.is_finite() { scale } else { 1.0 };
+        let source_center = Vector3::from(source.center());
+        let target_center = Vector3::from(target.center());
+        for triangle in self.triangles.iter_mut() {
+            for p in triangle.iter_mut() {
+                *p = Point3::from((p.coords - source_center) * scale + target_center);
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InsideTest {
+    /// Generalized winding number. Robust against holes and self intersections.
+    WindingNumber,
+    /// Counts crossings of a ray along +X. Faster, but needs a watertight mesh.
+    RayParity,
+}
+
+enum BvhNode {
+    Leaf { aabb: Aabb, triangles: Vec<usize> },
+    Branch { aabb: Aabb, children: Box<[BvhNode; 2]> },
+}
+
+impl BvhNode {
+    fn aabb(&self) -> &Aabb {
+        match self {
+            BvhNode::Leaf { aabb, .. } => aabb,
+            BvhNode::Branch { aabb, .. } => aabb,
+        }
+    }
+
+    fn build(triangles: &[Triangle], mut indices: Vec<usize>) -> Self {
+        let mut aabb = triangle_aabb(&triangles[indices[0]]);
+        for index in &indices[1..] {
+            let other = triangle_aabb(&triangles[*index]);
+            for i in 0..3 {
+                aabb.min[i] = aabb.min[i].min(other.min[i]);
+                aabb.max[i] = aabb.max[i].max(other.max[i]);
+            }
+        }
+        if indices.len() <= BVH_LEAF_SIZE {
+            return BvhNode::Leaf { aabb, triangles: indices };
+        }
+        // Median split along the longest axis
+        let size = aabb.size();
+        let axis = (0..3).max_by(|a, b| size[*a].partial_cmp(&size[*b]).unwrap()).unwrap();
+        let centroid = |index: &usize| triangles[*index].iter().map(|p| p[axis]).sum::<f32>();
+        indices.sort_by(|a, b| centroid(a).partial_cmp(&centroid(b)).unwrap());
+        let right = indices.split_off(indices.len() / 2);
+        BvhNode::Branch {
+            aabb,
+            children: Box::new([Self::build(triangles, indices), Self::build(triangles, right)]),
+        }
+    }
+
+    fn any_overlap(&self, triangles: &[Triangle], aabb: &Aabb) -> bool {
+        if !overlaps_inclusive(self.aabb(), aabb) {
+            return false;
+        }
+        match self {
+            BvhNode::Leaf { triangles: indices, .. } => indices
+                .iter()
+                .any(|index| triangle_overlaps_box(&triangles[*index], aabb)),
+            BvhNode::Branch { children, .. } => children
+                .iter()
+                .any(|child| child.any_overlap(triangles, aabb)),
+        }
+    }
+
+    /// Number of triangles crossed by the ray from `origin` along +X
+    fn ray_crossings(&self, triangles: &[Triangle], origin: &Point3<f32>) -> usize {
+        let aabb = self.aabb();
+        if aabb.max[0] < origin.x
+            || origin.y < aabb.min[1] || origin.y > aabb.max[1]
+            || origin.z < aabb.min[2] || origin.z > aabb.max[2] {
+            return 0;
+        }
+        match self {
+            BvhNode::Leaf { triangles: indices, .. } => indices
+                .iter()
+                .filter(|index| ray_crosses_triangle(&triangles[**index], origin))
+                .count(),
+            BvhNode::Branch { children, .. } => children
+                .iter()
+                .map(|child| child.ray_crossings(triangles, origin))
+                .sum(),
+        }
+    }
+}
+
+/**
+ `WorldBuilder` oracle for a solid triangle mesh.
+ Nodes crossed by a triangle subdivide, all others are filled or left empty by an inside test
+ of their center.
+ */
+pub struct MeshOracle {
+    mesh: TriangleMesh,
+    bvh: Option<BvhNode>,
+    material: VoxelData,
+    inside_test: InsideTest,
+    /// World units spanned by one chunk
+    chunk_size: f32,
+    /// Voxel size in world units, see `worldgen::is_voxel_sized`
+    voxel_size: f32,
+}
+
+impl MeshOracle {
+    pub fn new(mesh: TriangleMesh, material: VoxelData, chunk_size: f32, voxel_size: f32) -> Self {
+        let bvh = if mesh.triangles.is_empty() {
+            None
+        } else {
+            Some(BvhNode::build(&mesh.triangles, (0..mesh.triangles.len()).collect()))
+        };
+        Self {
+            mesh,
+            bvh,
+            material,
+            inside_test: InsideTest::WindingNumber,
+            chunk_size,
+            voxel_size,
+        }
+    }
+
+    pub fn with_inside_test(mut self, inside_test: InsideTest) -> Self {
+        self.inside_test = inside_test;
+        self
+    }
+
+    pub fn is_inside(&self, p: &Point3<f32>) -> bool {
+        let bvh = match &self.bvh {
+            Some(bvh) => bvh,
+            None => return false,
+        };
+        match self.inside_test {
+            InsideTest::WindingNumber => winding_number(&self.mesh.triangles, p).abs() >= 0.5,
+            InsideTest::RayParity => bvh.ray_crossings(&self.mesh.triangles, p) % 2 == 1,
+        }
+    }
+
+    fn sample(&self, p: &Point3<f32>) -> VoxelData {
+        if self.is_inside(p) {
+            self.material
+        } else {
+            VoxelData::EMPTY
+        }
+    }
+}
+
+impl Oracle for MeshOracle {
+    fn classify(&self, chunk: &ChunkCoordinates, bounds: &Bounds) -> Isosurface<VoxelData> {
+        let aabb = Aabb::from_bounds(chunk, bounds, self.chunk_size);
+        let bvh = match &self.bvh {
+            Some(bvh) => bvh,
+            None => return Isosurface::Uniform(VoxelData::EMPTY),
+        };
+        let center = Point3::from(aabb.center());
+        if is_voxel_sized(&aabb, self.voxel_size) {
+            return Isosurface::Uniform(self.sample(&center));
+        }
+        if !overlaps_inclusive(bvh.aabb(), &aabb) {
+            return Isosurface::Uniform(VoxelData::EMPTY);
+        }
+        if bvh.any_overlap(&self.mesh.triangles, &aabb) {
+            return Isosurface::Surface;
+        }
+        // No surface inside the node, so all of it is on the same side as its center
+        Isosurface::Uniform(self.sample(&center))
+    }
+}
+
+fn triangle_aabb(triangle: &Triangle) -> Aabb {
+    let mut aabb = Aabb::new(triangle[0].coords.into(), triangle[0].coords.into());
+    for p in &triangle[1..] {
+        for i in 0..3 {
+            aabb.min[i] = aabb.min[i].min(p[i]);
+            aabb.max[i] = aabb.max[i].max(p[i]);
+        }
+    }
+    aabb
+}
+
+fn overlaps_inclusive(a: &Aabb, b: &Aabb) -> bool {
+    (0..3).all(|i| a.min[i] <= b.max[i] && b.min[i] <= a.max[i])
+}
+
+/// Separating axis test of Akenine-Möller
+pub fn triangle_overlaps_box(triangle: &Triangle, aabb: &Aabb) -> bool {
+    let center = Vector3::from(aabb.center());
+    let half = Vector3::from(aabb.size()) * 0.5;
+    let v = [triangle[0].coords - center, triangle[1].coords - center, triangle[2].coords - center];
+    let edges = [v[1] - v[0], v[2] - v[1], v[0] - v[2]];
+
+    let separated = |axis: Vector3<f32>| {
+        let p = [axis.dot(&v[0]), axis.dot(&v[1]), axis.dot(&v[2])];
+        let radius = half.x * axis.x.abs() + half.y * axis.y.abs() + half.z * axis.z.abs();
+        let min = p[0].min(p[1]).min(p[2]);
+        let max = p[0].max(p[1]).max(p[2]);
+        min > radius || max < -radius
+    };
+
+    // The nine cross products of box axes and triangle edges
+    let box_axes = [Vector3::x(), Vector3::y(), Vector3::z()];
+    for box_axis in &box_axes {
+        for edge in &edges {
+            let axis = box_axis.cross(edge);
+            if axis.norm_squared() > std::f32::EPSILON && separated(axis) {
+                return false;
+            }
+        }
+    }
+    // The box face normals
+    for box_axis in &box_axes {
+        if separated(*box_axis) {
+            return false;
+        }
+    }
+    // The triangle normal
+    let normal = edges[0].cross(&edges[1]);
+    !(normal.norm_squared() > std::f32::EPSILON && separated(normal))
+}
+
+/// Generalized winding number: the sum of solid angles of all triangles, divided by 4π
+fn winding_number(triangles: &[Triangle], p: &Point3<f32>) -> f32 {
+    let mut total = 0.0;
+    for triangle in triangles {
+        let a = triangle[0] - p;
+        let b = triangle[1] - p;
+        let c = triangle[2] - p;
+        let (la, lb, lc) = (a.norm(), b.norm(), c.norm());
+        let numerator = a.dot(&b.cross(&c));
+        let denominator = la * lb * lc + a.dot(&b) * lc + b.dot(&c) * la + c.dot(&a) * lb;
+        total += 2.0 * numerator.atan2(denominator);
+    }
+    total / (4.0 * std::f32::consts::PI)
+}
+
+/// Möller–Trumbore intersection of the +X ray with a triangle
+fn ray_crosses_triangle(triangle: &Triangle, origin: &Point3<f32>) -> bool {
+    let direction = Vector3::x();
+    let e1 = triangle[1] - triangle[0];
+    let e2 = triangle[2] - triangle[0];
+    let h = direction.cross(&e2);
+    let det = e1.dot(&h);
+    if det.abs() < std::f32::EPSILON {
+        return false;
+    }
+    let s = origin - triangle[0];
+    let u = s.dot(&h) / det;
+    if u < 0.0 || u > 1.0 {
+        return false;
+    }
+    let q = s.cross(&e1);
+    let v = direction.dot(&q) / det;
+    if v < 0.0 || u + v > 1.0 {
+        return false;
+    }
+    e2.dot(&q) / det > 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube() -> TriangleMesh {
+        let p = |x: f32, y: f32, z: f32| Point3::new(x, y, z);
+        let quads = [
+            [p(0.0, 0.0, 0.0), p(0.0, 1.0, 0.0), p(1.0, 1.0, 0.0), p(1.0, 0.0, 0.0)],
+            [p(0.0, 0.0, 1.0), p(1.0, 0.0, 1.0), p(1.0, 1.0, 1.0), p(0.0, 1.0, 1.0)],
+            [p(0.0, 0.0, 0.0), p(1.0, 0.0, 0.0), p(1.0, 0.0, 1.0), p(0.0, 0.0, 1.0)],
+            [p(0.0, 1.0, 0.0), p(0.0, 1.0, 1.0), p(1.0, 1.0, 1.0), p(1.0, 1.0, 0.0)],
+            [p(0.0, 0.0, 0.0), p(0.0, 0.0, 1.0), p(0.0, 1.0, 1.0), p(0.0, 1.0, 0.0)],
+            [p(1.0, 0.0, 0.0), p(1.0, 1.0, 0.0), p(1.0, 1.0, 1.0), p(1.0, 0.0, 1.0)],
+        ];
+        let triangles = quads
+            .iter()
+            .flat_map(|q| vec![[q[0], q[1], q[2]], [q[0], q[2], q[3]]])
+            .collect();
+        TriangleMesh { triangles }
+    }
+
+    #[test]
+    fn test_inside_tests() {
+        for &test in &[InsideTest::WindingNumber, InsideTest::RayParity] {
+            let oracle = MeshOracle::new(cube(), VoxelData::solid(1), 1.0, 0.1).with_inside_test(test);
+            assert!(oracle.is_inside(&Point3::new(0.5, 0.5, 0.5)));
+            assert!(oracle.is_inside(&Point3::new(0.1, 0.9, 0.3)));
+            assert!(!oracle.is_inside(&Point3::new(1.5, 0.5, 0.5)));
+            assert!(!oracle.is_inside(&Point3::new(-0.5, 0.2, 0.7)));
+        }
+    }
+
+    #[test]
+    fn test_triangle_box_overlap() {
+        let triangle = [Point3::new(0.0, 0.0, 0.0), Point3::new(2.0, 0.0, 0.0), Point3::new(0.0, 2.0, 0.0)];
+        assert!(triangle_overlaps_box(&triangle, &Aabb::new([0.5, 0.5, -0.1], [0.6, 0.6, 0.1])));
+        assert!(!triangle_overlaps_box(&triangle, &Aabb::new([0.5, 0.5, 0.1], [0.6, 0.6, 0.2])));
+        // Beyond the hypotenuse
+        assert!(!triangle_overlaps_box(&triangle, &Aabb::new([1.2, 1.2, -0.1], [1.4, 1.4, 0.1])));
+    }
+
+    #[test]
+    fn test_fit_to() {
+        let mut mesh = cube();
+        mesh.fit_to(&Aabb::new([10.0, 10.0, 10.0], [14.0, 12.0, 14.0]));
+        // Scaled by 2 to fit the height, centered on (12, 11, 12)
+        let aabb = mesh.bounding_box().unwrap();
+        assert_eq!(aabb.min, [11.0, 10.0, 11.0]);
+        assert_eq!(aabb.max, [13.0, 12.0, 13.0]);
+    }
+}
@@ -0,0 +1,540 @@
+use amethyst::{
+    controls::{FlyControlTag, HideCursor},
+    core::{
+        math::{UnitQuaternion, Vector3},
+        transform::Transform,
+        Time,
+    },
+    derive::SystemDesc,
+    ecs::{Join, Read, ReadExpect, ReadStorage, System, SystemData, Write, WriteStorage},
+    input::{InputHandler, StringBindings},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::material::MaterialRegistry;
+use crate::octree::chunk_coordinates;
+use crate::octree::collision::{resolve_sphere, Sphere};
+use crate::util::aabb::Aabb;
+use crate::world::streaming::StreamingSettings;
+use crate::world::World;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControllerSettings {
+    /// Radius of the sphere kept out of solid terrain around the camera, in render units
+    pub camera_radius: f32,
+    /// Size of the walking character's box, in render units
+    pub height: f32,
+    pub width: f32,
+    /// Height of the camera above the character's feet
+    pub eye_height: f32,
+    /// Horizontal speed in render units per second
+    pub walk_speed: f32,
+    /// Upward speed when jumping off the ground
+    pub jump_speed: f32,
+    /// Downward acceleration in render units per second squared
+    pub gravity: f32,
+    /// Fastest falling speed
+    pub max_fall_speed: f32,
+    /// Ledges up to this height are climbed without jumping
+    pub step_height: f32,
+    /// Orbit radians per pixel the mouse is dragged
+    pub orbit_sensitivity: f32,
+    /// Fraction of the orbit distance panned per pixel the mouse is dragged
+    pub pan_sensitivity: f32,
+    /// Fraction of the orbit distance zoomed per mouse wheel step
+    pub zoom_step: f32,
+    /// Vertical field of view of the camera in radians, used to fit a chunk into view
+    pub field_of_view: f32,
+}
+
+impl Default for ControllerSettings {
+    fn default() -> Self {
+        Self {
+            camera_radius: 0.02,
+            height: 0.028,
+            width: 0.012,
+            eye_height: 0.025,
+            walk_speed: 0.1,
+            jump_speed: 0.12,
+            gravity: 0.4,
+            max_fall_speed: 1.0,
+            step_height: 0.01,
+            orbit_sensitivity: 0.01,
+            pan_sensitivity: 0.002,
+            zoom_step: 0.1,
+            field_of_view: 1.0,
+        }
+    }
+}
+
+/// How the `FlyControlTag` camera moves. The mouse turns it in both modes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CameraMode {
+    /// Free flight through `FlyControlBundle`
+    Fly,
+    /// Walking on the terrain through `WalkingSystem`
+    Walk,
+    /// Turning around a chunk through `OrbitSystem`
+    Orbit,
+}
+
+impl Default for CameraMode {
+    fn default() -> Self {
+        CameraMode::Fly
+    }
+}
+
+/// Pushes the flying `FlyControlTag` camera out of solid terrain after it moved
+#[derive(SystemDesc)]
+pub struct CameraCollisionSystem;
+
+impl<'a> System<'a> for CameraCollisionSystem {
+    type SystemData = (
+        ReadStorage<'a, FlyControlTag>,
+        WriteStorage<'a, Transform>,
+        Read<'a, World>,
+        ReadExpect<'a, MaterialRegistry>,
+        Read<'a, StreamingSettings>,
+        Read<'a, ControllerSettings>,
+        Read<'a, CameraMode>,
+    );
+
+    fn run(&mut self, (cameras, mut transforms, world, registry, streaming, settings, mode): Self::SystemData) {
+        if *mode != CameraMode::Fly {
+            return;
+        }
+        // Collision is resolved in chunks
+        let scale = streaming.chunk_size;
+        for (_, transform) in (&cameras, &mut transforms).join() {
+            let position = *transform.translation();
+            let sphere = Sphere::new(
+                [position.x / scale, position.y / scale, position.z / scale],
+                settings.camera_radius / scale,
+            );
+            let push = resolve_sphere(&sphere, |aabb| world.leaf_boxes(aabb, |value| registry.is_solid(value)));
+            let translation = transform.translation_mut();
+            translation.x += push[0] * scale;
+            translation.y += push[1] * scale;
+            translation.z += push[2] * scale;
+        }
+    }
+}
+
+/// Terrain the walking character collides with, in render units
+struct Terrain<'s> {
+    world: &'s World,
+    registry: &'s MaterialRegistry,
+    /// Render units per chunk
+    scale: f32,
+}
+
+impl Terrain<'_> {
+    fn to_chunks(&self, aabb: &Aabb) -> Aabb {
+        let scale = |v: [f32; 3]| [v[0] / self.scale, v[1] / self.scale, v[2] / self.scale];
+        Aabb::new(scale(aabb.min), scale(aabb.max))
+    }
+
+    fn overlaps(&self, aabb: &Aabb) -> bool {
+        !self.world.leaf_boxes(&self.to_chunks(aabb), |value| self.registry.is_solid(value)).is_empty()
+    }
+
+    /// Top of the highest solid leaf overlapping a box
+    fn floor(&self, aabb: &Aabb) -> Option<f32> {
+        self.world
+            .leaf_boxes(&self.to_chunks(aabb), |value| self.registry.is_solid(value))
+            .iter()
+            .map(|leaf| leaf.max[1] * self.scale)
+            .fold(None, |floor: Option<f32>, top| Some(floor.map_or(top, |floor| floor.max(top))))
+    }
+
+    /// Whether the chunk at a point is in memory, without which there is nothing to stand on
+    fn is_loaded(&self, point: [f32; 3]) -> bool {
+        let chunk = [
+            (point[0] / self.scale).floor() as i32,
+            (point[1] / self.scale).floor() as i32,
+            (point[2] / self.scale).floor() as i32,
+        ];
+        self.world.contains(&chunk_coordinates(chunk))
+    }
+}
+
+/**
+ First person walking for the `FlyControlTag` camera: gravity, jumping, climbing small ledges and
+ sliding along walls. The `toggle_walk` action switches between walking and flying.
+
+ The character is a box standing on `feet`. Flight moves the camera before this system runs,
+ so while walking the camera is put back on top of the character every frame.
+ */
+#[derive(Default)]
+pub struct WalkingSystem {
+    /// Bottom center of the character's box, `None` while flying
+    feet: Option<[f32; 3]>,
+    velocity: [f32; 3],
+    grounded: bool,
+    /// Whether `toggle_walk` was held last frame
+    toggle_held: bool,
+}
+
+impl WalkingSystem {
+    fn body(&self, settings: &ControllerSettings, feet: [f32; 3]) -> Aabb {
+        let half = settings.width / 2.0;
+        Aabb::new(
+            [feet[0] - half, feet[1], feet[2] - half],
+            [feet[0] + half, feet[1] + settings.height, feet[2] + half],
+        )
+    }
+
+    /// Moves horizontally, climbing ledges up to `step_height` and otherwise sliding along walls
+    fn walk(&self, terrain: &Terrain, settings: &ControllerSettings, feet: [f32; 3], offset: [f32; 2]) -> [f32; 3] {
+        let moved = [feet[0] + offset[0], feet[1], feet[2] + offset[1]];
+        if !terrain.overlaps(&self.body(settings, moved)) {
+            return moved;
+        }
+        if self.grounded {
+            let raised = [moved[0], moved[1] + settings.step_height, moved[2]];
+            if !terrain.overlaps(&self.body(settings, raised)) {
+                // Settle onto the ledge instead of falling onto it next frame
+                let mut span = self.body(settings, raised);
+                span.min[1] = feet[1];
+                let floor = terrain.floor(&span).unwrap_or(feet[1]);
+                return [raised[0], floor.max(feet[1]).min(raised[1]), raised[2]];
+            }
+        }
+        let slide_x = [feet[0] + offset[0], feet[1], feet[2]];
+        let feet = if terrain.overlaps(&self.body(settings, slide_x)) { feet } else { slide_x };
+        let slide_z = [feet[0], feet[1], feet[2] + offset[1]];
+        if terrain.overlaps(&self.body(settings, slide_z)) { feet } else { slide_z }
+    }
+
+    /// Applies gravity for `delta` seconds, landing on the floor or stopping under a ceiling
+    fn fall(&mut self, terrain: &Terrain, settings: &ControllerSettings, mut feet: [f32; 3], delta: f32) -> [f32; 3] {
+        self.velocity[1] = (self.velocity[1] - settings.gravity * delta).max(-settings.max_fall_speed);
+        let fallen = [feet[0], feet[1] + self.velocity[1] * delta, feet[2]];
+        if terrain.overlaps(&self.body(settings, fallen)) {
+            if self.velocity[1] <= 0.0 {
+                // Land on top of whatever was hit between here and there
+                let mut span = self.body(settings, feet);
+                span.min[1] = fallen[1];
+                let floor = terrain.floor(&span).unwrap_or(fallen[1]);
+                feet[1] = floor.min(feet[1]).max(fallen[1]);
+                self.grounded = true;
+            }
+            // Hitting a ceiling stops the jump where it is
+            self.velocity[1] = 0.0;
+        } else {
+            feet = fallen;
+            // Walking off a ledge
+            let below = [feet[0], feet[1] - settings.step_height * 0.1, feet[2]];
+            self.grounded = self.velocity[1] <= 0.0 && terrain.overlaps(&self.body(settings, below));
+        }
+        feet
+    }
+}
+
+impl<'a> System<'a> for WalkingSystem {
+    type SystemData = (
+        ReadStorage<'a, FlyControlTag>,
+        WriteStorage<'a, Transform>,
+        Read<'a, InputHandler<StringBindings>>,
+        Read<'a, Time>,
+        Read<'a, World>,
+        ReadExpect<'a, MaterialRegistry>,
+        Read<'a, StreamingSettings>,
+        Read<'a, ControllerSettings>,
+        Write<'a, CameraMode>,
+    );
+
+    fn run(
+        &mut self,
+        (cameras, mut transforms, input, time, world, registry, streaming, settings, mut mode): Self::SystemData,
+    ) {
+        let transform = match (&cameras, &mut transforms).join().next() {
+            Some((_, transform)) => transform,
+            None => return,
+        };
+
+        let toggle = input.action_is_down("toggle_walk").unwrap_or(false);
+        if toggle && !self.toggle_held {
+            *mode = match *mode {
+                CameraMode::Walk => CameraMode::Fly,
+                CameraMode::Fly | CameraMode::Orbit => CameraMode::Walk,
+            };
+        }
+        self.toggle_held = toggle;
+        if *mode != CameraMode::Walk {
+            self.feet = None;
+            return;
+        }
+
+        let terrain = Terrain { world: &world, registry: &registry, scale: streaming.chunk_size };
+        let mut feet = match self.feet {
+            Some(feet) => feet,
+            None => {
+                let eye = transform.translation();
+                self.velocity = [0.0; 3];
+                self.grounded = false;
+                [eye.x, eye.y - settings.eye_height, eye.z]
+            }
+        };
+        let delta = time.delta_seconds();
+
+        // Fly axes, turned by the camera's heading only
+        let local = Vector3::new(
+            input.axis_value("move_x").unwrap_or(0.0),
+            0.0,
+            input.axis_value("move_z").unwrap_or(0.0),
+        );
+        let mut heading = transform.rotation() * local;
+        heading.y = 0.0;
+        let length = heading.norm();
+        if length > 0.0 {
+            heading *= settings.walk_speed * local.norm().min(1.0) / length;
+        }
+        self.velocity[0] = heading.x;
+        self.velocity[2] = heading.z;
+
+        // Hold still above chunks that aren't loaded yet rather than falling through them
+        if !terrain.is_loaded(feet) {
+            self.feet = Some(feet);
+            return;
+        }
+        if self.grounded && input.action_is_down("jump").unwrap_or(false) {
+            self.velocity[1] = settings.jump_speed;
+            self.grounded = false;
+        }
+        feet = self.walk(&terrain, &settings, feet, [self.velocity[0] * delta, self.velocity[2] * delta]);
+        feet = self.fall(&terrain, &settings, feet, delta);
+
+        self.feet = Some(feet);
+        transform.set_translation_xyz(feet[0], feet[1] + settings.eye_height, feet[2]);
+    }
+}
+
+/**
+ Inspection camera turning around the center of a chunk, taken from its root `Bounds`.
+ `toggle_orbit` switches between orbiting and flying. While orbiting, the cursor is released,
+ dragging with `orbit_rotate` turns, dragging with `orbit_pan` moves the center, the mouse wheel
+ zooms and `orbit_fit` frames the whole chunk again.
+ */
+#[derive(Default)]
+pub struct OrbitSystem {
+    /// Point the camera looks at, in render units
+    target: [f32; 3],
+    /// Radians around the vertical axis
+    yaw: f32,
+    /// Radians above the horizon, negative looking down
+    pitch: f32,
+    distance: f32,
+    /// Chunk being inspected
+    chunk: [i32; 3],
+    /// Mouse position last frame, to drag by its difference
+    cursor: Option<(f32, f32)>,
+    /// Whether `toggle_orbit` and `orbit_fit` were held last frame
+    held: (bool, bool),
+    /// Whether the cursor was released for orbiting and has to be grabbed again
+    released: bool,
+}
+
+impl OrbitSystem {
+    /// Frames the whole chunk, looking at it from above at an angle
+    fn fit(&mut self, world: &World, streaming: &StreamingSettings, settings: &ControllerSettings) {
+        let scale = streaming.chunk_size;
+        let origin = streaming.chunk_origin(self.chunk);
+        let (center, width): ([f32; 3], f32) = match world.get(&chunk_coordinates(self.chunk)) {
+            Some(chunk) => {
+                let bounds = chunk.get_root().get_bounds();
+                (bounds.center().into(), bounds.get_width())
+            }
+            None => ([0.5; 3], 1.0),
+        };
+        self.target = [
+            origin[0] + center[0] * scale,
+            origin[1] + center[1] * scale,
+            origin[2] + center[2] * scale,
+        ];
+        // Far enough for the sphere around the chunk to fit in the field of view
+        let radius = width * scale * 3f32.sqrt() / 2.0;
+        self.distance = radius / (settings.field_of_view / 2.0).sin();
+        self.yaw = std::f32::consts::FRAC_PI_4;
+        self.pitch = -std::f32::consts::FRAC_PI_6;
+    }
+
+    fn rotation(&self) -> UnitQuaternion<f32> {
+        UnitQuaternion::from_axis_angle(&Vector3::y_axis(), self.yaw)
+            * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), self.pitch)
+    }
+}
+
+impl<'a> System<'a> for OrbitSystem {
+    type SystemData = (
+        ReadStorage<'a, FlyControlTag>,
+        WriteStorage<'a, Transform>,
+        Read<'a, InputHandler<StringBindings>>,
+        Read<'a, World>,
+        Read<'a, StreamingSettings>,
+        Read<'a, ControllerSettings>,
+        Write<'a, CameraMode>,
+        Write<'a, HideCursor>,
+    );
+
+    fn run(
+        &mut self,
+        (cameras, mut transforms, input, world, streaming, settings, mut mode, mut hide_cursor): Self::SystemData,
+    ) {
+        let transform = match (&cameras, &mut transforms).join().next() {
+            Some((_, transform)) => transform,
+            None => return,
+        };
+
+        let toggle = input.action_is_down("toggle_orbit").unwrap_or(false);
+        let fit = input.action_is_down("orbit_fit").unwrap_or(false);
+        let pressed = (toggle && !self.held.0, fit && !self.held.1);
+        self.held = (toggle, fit);
+        if pressed.0 {
+            if *mode == CameraMode::Orbit {
+                *mode = CameraMode::Fly;
+            } else {
+                *mode = CameraMode::Orbit;
+                hide_cursor.hide = false;
+                self.released = true;
+                self.chunk = streaming.chunk_at((*transform.translation()).into());
+                self.fit(&world, &streaming, &settings);
+            }
+        }
+        if *mode != CameraMode::Orbit {
+            if self.released {
+                // The other modes turn with the grabbed mouse
+                hide_cursor.hide = true;
+                self.released = false;
+            }
+            self.cursor = None;
+            return;
+        }
+        if pressed.1 {
+            self.fit(&world, &streaming, &settings);
+        }
+
+        let cursor = input.mouse_position();
+        let (dx, dy) = match (cursor, self.cursor) {
+            (Some(now), Some(before)) => (now.0 - before.0, now.1 - before.1),
+            _ => (0.0, 0.0),
+        };
+        self.cursor = cursor;
+        if input.action_is_down("orbit_rotate").unwrap_or(false) {
+            self.yaw -= dx * settings.orbit_sensitivity;
+            let limit = std::f32::consts::FRAC_PI_2 - 0.01;
+            self.pitch = (self.pitch - dy * settings.orbit_sensitivity).max(-limit).min(limit);
+        } else if input.action_is_down("orbit_pan").unwrap_or(false) {
+            // Drag the chunk along with the cursor
+            let step = self.distance * settings.pan_sensitivity;
+            let pan = self.rotation() * Vector3::new(-dx * step, dy * step, 0.0);
+            for i in 0..3 {
+                self.target[i] += pan[i];
+            }
+        }
+        let wheel = input.mouse_wheel_value(false);
+        if wheel != 0.0 {
+            self.distance = (self.distance * (1.0 - wheel * settings.zoom_step).max(0.1)).max(settings.camera_radius);
+        }
+
+        let rotation = self.rotation();
+        let offset = rotation * Vector3::new(0.0, 0.0, self.distance);
+        transform.set_translation_xyz(self.target[0] + offset.x, self.target[1] + offset.y, self.target[2] + offset.z);
+        transform.set_rotation(rotation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::VoxelData;
+    use crate::storage::chunk::test_utils::floor;
+    use crate::storage::chunk::Tree;
+
+    /// Solid below half the chunk's height, and a block on the floor from x = 0.5 up to `top`
+    fn world(top: u32) -> World {
+        let mut tree = floor();
+        for x in 4..8 {
+            for y in 4..top {
+                for z in 0..8 {
+                    tree.set_node(3, [x, y, z], Tree::Leaf(VoxelData::solid(1)));
+                }
+            }
+        }
+        let mut world = World::default();
+        world.insert(&chunk_coordinates([0, 0, 0]), tree.to_chunk([0, 0, 0]), false);
+        world
+    }
+
+    /// A character sized to the eighths of the test chunk, which is one render unit wide
+    fn settings() -> ControllerSettings {
+        ControllerSettings { width: 0.125, height: 0.375, step_height: 0.1875, ..ControllerSettings::default() }
+    }
+
+    #[test]
+    fn test_step_up() {
+        // A ledge one eighth high, below `step_height`
+        let world = world(5);
+        let registry = MaterialRegistry::default();
+        let terrain = Terrain { world: &world, registry: &registry, scale: 1.0 };
+        let settings = settings();
+        let mut walking = WalkingSystem::default();
+
+        walking.grounded = true;
+        let feet = walking.walk(&terrain, &settings, [0.375, 0.5, 0.5], [0.125, 0.0]);
+        assert_eq!(feet, [0.5, 0.625, 0.5]);
+
+        // Nothing to climb from while in the air
+        walking.grounded = false;
+        let feet = walking.walk(&terrain, &settings, [0.375, 0.5, 0.5], [0.125, 0.0]);
+        assert_eq!(feet, [0.375, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_wall_is_not_climbed() {
+        // A wall half the chunk high, above `step_height`
+        let world = world(8);
+        let registry = MaterialRegistry::default();
+        let terrain = Terrain { world: &world, registry: &registry, scale: 1.0 };
+        let settings = settings();
+        let mut walking = WalkingSystem::default();
+        walking.grounded = true;
+
+        // Only the part of the move along the wall is kept
+        let feet = walking.walk(&terrain, &settings, [0.375, 0.5, 0.5], [0.125, 0.0625]);
+        assert_eq!(feet, [0.375, 0.5, 0.5625]);
+    }
+
+    #[test]
+    fn test_landing() {
+        let world = world(4);
+        let registry = MaterialRegistry::default();
+        let terrain = Terrain { world: &world, registry: &registry, scale: 1.0 };
+        let settings = settings();
+        let mut walking = WalkingSystem::default();
+
+        let mut feet = [0.25, 0.75, 0.5];
+        for _ in 0..100 {
+            feet = walking.fall(&terrain, &settings, feet, 0.05);
+            if walking.grounded {
+                break;
+            }
+        }
+        assert!(walking.grounded);
+        assert_eq!(feet, [0.25, 0.5, 0.5]);
+        assert_eq!(walking.velocity[1], 0.0);
+
+        // Standing still on the floor
+        feet = walking.fall(&terrain, &settings, feet, 0.05);
+        assert!(walking.grounded);
+        assert_eq!(feet, [0.25, 0.5, 0.5]);
+
+        // Falling further than the floor in one frame still lands on it
+        walking.grounded = false;
+        walking.velocity[1] = -settings.max_fall_speed;
+        feet = walking.fall(&terrain, &settings, [0.25, 0.75, 0.5], 0.5);
+        assert!(walking.grounded);
+        assert_eq!(feet, [0.25, 0.5, 0.5]);
+    }
+}
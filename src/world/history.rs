@@ -0,0 +1,491 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write as _};
+use std::path::Path;
+
+use amethyst::{
+    ecs::{Read as ReadResource, ReadExpect, System, Write},
+    input::{InputHandler, StringBindings},
+};
+
+use crate::octree::chunk_coordinates;
+use crate::storage::chunk::{ChunkFormatError, Tree, MAX_DEPTH};
+use crate::world::{ChunkSource, World};
+
+pub const MAGIC: &[u8; 4] = b"GOGH";
+pub const VERSION: u16 = 1;
+/// Written next to the manifest of a world save
+pub const HISTORY_FILE: &str = "history.bin";
+
+#[derive(Debug)]
+pub enum HistoryError {
+    Io(std::io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    Chunk(ChunkFormatError),
+    Corrupted(String),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryError::Io(err) => write!(f, "{}", err),
+            HistoryError::InvalidMagic => write!(f, "not an edit history file"),
+            HistoryError::UnsupportedVersion(version) => write!(f, "unsupported edit history version {}", version),
+            HistoryError::Chunk(err) => write!(f, "invalid subtree in edit history: {}", err),
+            HistoryError::Corrupted(reason) => write!(f, "corrupted edit history: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+impl From<std::io::Error> for HistoryError {
+    fn from(err: std::io::Error) -> Self {
+        HistoryError::Io(err)
+    }
+}
+
+impl From<ChunkFormatError> for HistoryError {
+    fn from(err: ChunkFormatError) -> Self {
+        HistoryError::Chunk(err)
+    }
+}
+
+/// The smallest node of a chunk that contains every voxel an edit changed, before and after it
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkChange {
+    pub position: [i32; 3],
+    pub depth: u8,
+    pub cell: [u32; 3],
+    pub before: Tree,
+    pub after: Tree,
+}
+
+impl ChunkChange {
+    /// `None` if the trees are the same
+    pub fn diff(position: [i32; 3], before: &Tree, after: &Tree) -> Option<Self> {
+        let (depth, cell) = differing_node(before, after, 0, [0, 0, 0])?;
+        Some(Self {
+            position,
+            depth,
+            cell,
+            before: before.node(depth, cell).clone(),
+            after: after.node(depth, cell).clone(),
+        })
+    }
+
+    /// Estimated memory held by the change
+    pub fn footprint(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + (self.before.node_count() + self.after.node_count()) * std::mem::size_of::<Tree>()
+    }
+
+    /// Writes the `before` subtree if `undo`, the `after` one otherwise
+    fn apply(&self, world: &mut World, source: &ChunkSource, undo: bool) -> Result<(), ChunkFormatError> {
+        let subtree = if undo { &self.before } else { &self.after };
+        world.edit(&chunk_coordinates(self.position), self.depth, source, |editor| {
+            editor.set_node(self.depth, self.cell, subtree.clone())
+        })
+    }
+}
+
+fn differing_node(before: &Tree, after: &Tree, depth: u8, cell: [u32; 3]) -> Option<(u8, [u32; 3])> {
+    if before == after {
+        return None;
+    }
+    if let (Tree::Branch(before), Tree::Branch(after)) = (before, after) {
+        let mut differing = (0..8).filter(|i| before[*i] != after[*i]);
+        if let (Some(i), None) = (differing.next(), differing.next()) {
+            let child = [
+                cell[0] * 2 + (i & 1) as u32,
+                cell[1] * 2 + ((i >> 1) & 1) as u32,
+                cell[2] * 2 + ((i >> 2) & 1) as u32,
+            ];
+            return differing_node(&before[i], &after[i], depth + 1, child);
+        }
+    }
+    Some((depth, cell))
+}
+
+/// One brush stroke or fill, possibly spanning several chunks. Changes are undone in reverse order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Edit {
+    pub changes: Vec<ChunkChange>,
+}
+
+impl Edit {
+    pub fn footprint(&self) -> usize {
+        self.changes.iter().map(ChunkChange::footprint).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+/**
+ Undo and redo stacks of edits. Once the recorded subtrees take more memory than the budget,
+ the oldest edits are forgotten. Inserted into the ECS world as a resource.
+ */
+#[derive(Default)]
+pub struct History {
+    undo: VecDeque<Edit>,
+    redo: Vec<Edit>,
+    /// Bytes of recorded subtrees to keep at most, 0 for no limit
+    budget: usize,
+    bytes: usize,
+}
+
+impl History {
+    pub fn with_budget(budget: usize) -> Self {
+        Self { budget, ..Self::default() }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Memory held by both stacks
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Records a new edit, which makes everything that was undone unreachable
+    pub fn record(&mut self, edit: Edit) {
+        if edit.is_empty() {
+            return;
+        }
+        for dropped in self.redo.drain(..) {
+            self.bytes -= dropped.footprint();
+        }
+        self.bytes += edit.footprint();
+        self.undo.push_back(edit);
+        self.trim();
+    }
+
+    /**
+     Forgets the oldest undo edits while over the budget, then the redo edits that would be redone
+     last. The newest undo edit is kept even if it is over the budget on its own.
+     */
+    fn trim(&mut self) {
+        if self.budget == 0 {
+            return;
+        }
+        while self.bytes > self.budget && self.undo.len() > 1 {
+            if let Some(dropped) = self.undo.pop_front() {
+                self.bytes -= dropped.footprint();
+            }
+        }
+        while self.bytes > self.budget && !self.redo.is_empty() {
+            let dropped = self.redo.remove(0);
+            self.bytes -= dropped.footprint();
+        }
+    }
+
+    /**
+     Restores the chunks changed by the last edit. Returns whether there was anything to undo.
+     If a chunk can't be edited, the edit stays on the undo stack.
+     */
+    pub fn undo(&mut self, world: &mut World, source: &ChunkSource) -> Result<bool, ChunkFormatError> {
+        let edit = match self.undo.pop_back() {
+            Some(edit) => edit,
+            None => return Ok(false),
+        };
+        if let Err(err) = edit.changes.iter().rev().try_for_each(|change| change.apply(world, source, true)) {
+            self.undo.push_back(edit);
+            return Err(err);
+        }
+        self.redo.push(edit);
+        Ok(true)
+    }
+
+    /// Repeats the last undone edit. Returns whether there was anything to redo.
+    pub fn redo(&mut self, world: &mut World, source: &ChunkSource) -> Result<bool, ChunkFormatError> {
+        let edit = match self.redo.pop() {
+            Some(edit) => edit,
+            None => return Ok(false),
+        };
+        if let Err(err) = edit.changes.iter().try_for_each(|change| change.apply(world, source, false)) {
+            self.redo.push(edit);
+            return Err(err);
+        }
+        self.undo.push_back(edit);
+        Ok(true)
+    }
+
+    /**
+     Writes both stacks, with every subtree in the chunk format.
+
+     | content                                       |
+     |-----------------------------------------------|
+     | magic, version u16                            |
+     | undo count u32, redo count u32                |
+     | per edit: change count u32, then its changes  |
+     | per change: depth u8, cell 3x u32, then the `before` and `after` subtrees, each a u32 length and encoded chunk |
+
+     Undo edits are stored oldest first, redo edits in stack order.
+     */
+    pub fn write(&self, mut writer: impl std::io::Write) -> Result<(), HistoryError> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(self.undo.len() as u32).to_le_bytes())?;
+        writer.write_all(&(self.redo.len() as u32).to_le_bytes())?;
+        for edit in self.undo.iter().chain(self.redo.iter()) {
+            writer.write_all(&(edit.changes.len() as u32).to_le_bytes())?;
+            for change in &edit.changes {
+                writer.write_all(&[change.depth])?;
+                for axis in &change.cell {
+                    writer.write_all(&axis.to_le_bytes())?;
+                }
+                for subtree in &[&change.before, &change.after] {
+                    let bytes = subtree.encode(change.position);
+                    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+                    writer.write_all(&bytes)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read(mut reader: impl Read, budget: usize) -> Result<Self, HistoryError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(HistoryError::InvalidMagic);
+        }
+        let mut version = [0; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version != VERSION {
+            return Err(HistoryError::UnsupportedVersion(version));
+        }
+
+        let mut history = Self::with_budget(budget);
+        let undo = read_u32(&mut reader)?;
+        let redo = read_u32(&mut reader)?;
+        let edits = undo
+            .checked_add(redo)
+            .ok_or_else(|| HistoryError::Corrupted(format!("{} undo and {} redo entries", undo, redo)))?;
+        for i in 0..edits {
+            let mut edit = Edit::default();
+            for _ in 0..read_u32(&mut reader)? {
+                let mut depth = [0; 1];
+                reader.read_exact(&mut depth)?;
+                let cell = [read_u32(&mut reader)?, read_u32(&mut reader)?, read_u32(&mut reader)?];
+                // Replaying a change outside of its chunk would overflow the tree's cell arithmetic
+                if depth[0] > MAX_DEPTH || cell.iter().any(|axis| *axis >= 1 << depth[0]) {
+                    return Err(HistoryError::Corrupted(format!("cell {:?} at depth {}", cell, depth[0])));
+                }
+                let (before, position) = read_subtree(&mut reader)?;
+                let (after, _) = read_subtree(&mut reader)?;
+                edit.changes.push(ChunkChange { position, depth: depth[0], cell, before, after });
+            }
+            history.bytes += edit.footprint();
+            if i < undo {
+                history.undo.push_back(edit);
+            } else {
+                history.redo.push(edit);
+            }
+        }
+        // The budget may have shrunk since the history was written
+        history.trim();
+        Ok(history)
+    }
+
+    /// Saves the history to a file, writing a temporary file first so a crash can't leave half of it
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), HistoryError> {
+        let path = path.as_ref();
+        let temporary = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /// Loads a saved history, or an empty one if there is none
+    pub fn load(path: impl AsRef<Path>, budget: usize) -> Result<Self, HistoryError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::with_budget(budget));
+        }
+        Self::read(BufReader::new(File::open(path)?), budget)
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, HistoryError> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_subtree(reader: &mut impl Read) -> Result<(Tree, [i32; 3]), HistoryError> {
+    let length = read_u32(reader)? as usize;
+    // Grows with the data actually there, so a corrupted length can't allocate gigabytes up front
+    let mut bytes = Vec::new();
+    reader.by_ref().take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Tree::decode(&bytes)?)
+}
+
+/// Undoes and redoes edits when their actions are pressed
+#[derive(Default)]
+pub struct HistorySystem {
+    /// Whether undo and redo were held last frame, so holding them only acts once
+    held: (bool, bool),
+}
+
+impl<'a> System<'a> for HistorySystem {
+    type SystemData = (
+        ReadResource<'a, InputHandler<StringBindings>>,
+        Write<'a, History>,
+        Write<'a, World>,
+        ReadExpect<'a, ChunkSource>,
+    );
+
+    fn run(&mut self, (input, mut history, mut world, source): Self::SystemData) {
+        let undo = input.action_is_down("undo").unwrap_or(false);
+        let redo = input.action_is_down("redo").unwrap_or(false);
+        let result = if undo && !self.held.0 {
+            history.undo(&mut world, &source)
+        } else if redo && !self.held.1 {
+            history.redo(&mut world, &source)
+        } else {
+            Ok(false)
+        };
+        self.held = (undo, redo);
+        if let Err(err) = result {
+            log::error!("Failed to undo or redo an edit: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::VoxelData;
+
+    fn change(position: [i32; 3], value: u16) -> ChunkChange {
+        let before = Tree::Leaf(VoxelData::EMPTY);
+        let mut after = before.clone();
+        after.set_node(3, [5, 2, 7], Tree::Leaf(VoxelData::solid(value)));
+        ChunkChange::diff(position, &before, &after).unwrap()
+    }
+
+    #[test]
+    fn test_diff_finds_smallest_node() {
+        let change = change([1, 2, 3], 1);
+        assert_eq!((change.depth, change.cell), (3, [5, 2, 7]));
+        assert_eq!(change.before, Tree::Leaf(VoxelData::EMPTY));
+        assert_eq!(change.after, Tree::Leaf(VoxelData::solid(1)));
+
+        let tree = Tree::Leaf(VoxelData::solid(4));
+        assert_eq!(ChunkChange::diff([0, 0, 0], &tree, &tree), None);
+    }
+
+    #[test]
+    fn test_budget_drops_oldest() {
+        let edit = |value| Edit { changes: vec![change([0, 0, 0], value)] };
+        let mut history = History::with_budget(edit(1).footprint() * 2);
+        history.record(edit(1));
+        history.record(edit(2));
+        history.record(edit(3));
+        assert_eq!(history.undo.len(), 2);
+        assert_eq!(history.undo[0], edit(2));
+        assert_eq!(history.bytes(), edit(1).footprint() * 2);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut history = History::default();
+        history.record(Edit { changes: vec![change([0, 0, 0], 1), change([-1, 4, 2], 2)] });
+        history.record(Edit { changes: vec![change([3, 3, 3], 3)] });
+        history.redo.push(history.undo.pop_back().unwrap());
+
+        let mut bytes = Vec::new();
+        history.write(&mut bytes).unwrap();
+        let read = History::read(&bytes[..], 0).unwrap();
+        assert_eq!(read.undo, history.undo);
+        assert_eq!(read.redo, history.redo);
+        assert_eq!(read.bytes(), history.bytes());
+
+        assert!(matches!(History::read(&bytes[..bytes.len() - 1], 0), Err(HistoryError::Io(_))));
+        assert!(matches!(History::read(&b"GOGC\x01\x00"[..], 0), Err(HistoryError::InvalidMagic)));
+    }
+
+    #[test]
+    fn test_read_trims_like_record() {
+        let edit = |value| Edit { changes: vec![change([0, 0, 0], value)] };
+        let footprint = edit(1).footprint();
+        let mut history = History::default();
+        for value in 1..=3 {
+            history.record(edit(value));
+        }
+        history.redo.push(history.undo.pop_back().unwrap());
+        let mut bytes = Vec::new();
+        history.write(&mut bytes).unwrap();
+
+        // The oldest undo edit goes first
+        let read = History::read(&bytes[..], footprint * 2).unwrap();
+        assert_eq!(read.undo, vec![edit(2)]);
+        assert_eq!(read.redo, vec![edit(3)]);
+        assert_eq!(read.bytes(), footprint * 2);
+
+        // Then redo edits, but never the newest undo edit
+        let read = History::read(&bytes[..], 1).unwrap();
+        assert_eq!(read.undo, vec![edit(2)]);
+        assert!(read.redo.is_empty());
+        assert_eq!(read.bytes(), footprint);
+    }
+
+    #[test]
+    fn test_read_corrupted_length() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        for count in &[1u32, 0, 1] {
+            bytes.extend_from_slice(&count.to_le_bytes());
+        }
+        bytes.push(3);
+        bytes.extend_from_slice(&[0; 12]);
+        // A subtree claiming 4 GiB, followed by a few bytes
+        bytes.extend_from_slice(&std::u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&[0; 16]);
+        assert!(matches!(History::read(&bytes[..], 0), Err(HistoryError::Io(_))));
+    }
+
+    /// A header with the given entry counts, followed by one edit holding a single change at `depth` and `cell`
+    fn corrupted_change(counts: [u32; 2], depth: u8, cell: [u32; 3]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        for count in &[counts[0], counts[1], 1] {
+            bytes.extend_from_slice(&count.to_le_bytes());
+        }
+        bytes.push(depth);
+        for axis in &cell {
+            bytes.extend_from_slice(&axis.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_read_corrupted_change() {
+        let read = |bytes: Vec<u8>| History::read(&bytes[..], 0);
+        assert!(matches!(read(corrupted_change([1, 0], 40, [0; 3])), Err(HistoryError::Corrupted(_))));
+        assert!(matches!(read(corrupted_change([1, 0], 2, [1, 4, 0])), Err(HistoryError::Corrupted(_))));
+        let counts = [std::u32::MAX, 1];
+        assert!(matches!(read(corrupted_change(counts, 2, [0; 3])), Err(HistoryError::Corrupted(_))));
+        // Valid changes only fail later, on the missing subtrees
+        assert!(matches!(read(corrupted_change([1, 0], 2, [3, 3, 3])), Err(HistoryError::Io(_))));
+    }
+}
@@ -0,0 +1,590 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use amethyst::ecs::Entity;
+
+use crate::octree::collision::{leaf_aabb, overlapping_leaves};
+use crate::octree::edit::ChunkEditor;
+use crate::octree::raycast::{chunks_along, raycast_filtered, RayHit};
+use crate::octree::world::ChunkCoordinates;
+use crate::octree::{chunk_coordinates, chunk_position, leaf_at, Chunk, VoxelData};
+use crate::storage::chunk::ChunkFormatError;
+use crate::storage::save::{SaveError, WorldSave};
+use crate::util::aabb::Aabb;
+use crate::worldgen::{world_builder, Oracle};
+
+pub mod controller;
+pub mod history;
+pub mod picking;
+pub mod sculpt;
+pub mod streaming;
+
+/// Estimated size of one octree node: its value plus links to its children and parent
+pub const NODE_SIZE: usize = std::mem::size_of::<VoxelData>() + 2 * std::mem::size_of::<usize>();
+
+/// Estimated memory held by a chunk
+pub fn chunk_footprint(chunk: &Chunk) -> usize {
+    let leaves = chunk.iter_leaf().count();
+    // Every branch has eight children, so a tree with n leaves has (n - 1) / 7 branches
+    let nodes = leaves + leaves.saturating_sub(1) / 7;
+    nodes * NODE_SIZE
+}
+
+/// A chunk in memory
+pub struct CachedChunk {
+    pub chunk: Chunk,
+    /// Changed since it was loaded or last saved
+    pub dirty: bool,
+    pub footprint: usize,
+    last_used: AtomicU64,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheMetrics {
+    /// `load` calls answered from memory
+    pub hits: u64,
+    /// `load` calls that went to the save or the generator
+    pub misses: u64,
+    pub evictions: u64,
+    pub chunks: usize,
+    pub bytes: usize,
+    /// 0 if unlimited
+    pub budget: usize,
+}
+
+impl CacheMetrics {
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+impl fmt::Display for CacheMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} chunks in {} KiB, {} hits, {} misses ({:.1}% hit rate), {} evictions",
+            self.chunks,
+            self.bytes / 1024,
+            self.hits,
+            self.misses,
+            self.hit_rate() * 100.0,
+            self.evictions,
+        )
+    }
+}
+
+/**
+ All chunks currently in memory, keyed by chunk position, and the entities rendering them.
+ Inserted into the ECS world as a resource and kept up to date by `ChunkStreamingSystem`.
+
+ Chunk data is a cache bounded by a memory budget: once it is exceeded, the least recently used
+ chunks are dropped, saving them first if they changed. Meshes are tracked separately, so a chunk
+ that is still on screen can be evicted and is only loaded again when something reads it.
+ */
+#[derive(Default)]
+pub struct World {
+    chunks: HashMap<[i32; 3], CachedChunk>,
+    meshes: HashMap<[i32; 3], Vec<Entity>>,
+    /// Chunks whose meshes are out of date
+    remesh: HashSet<[i32; 3]>,
+    /// Bytes of chunk data to keep at most, 0 for no limit
+    budget: usize,
+    bytes: usize,
+    clock: AtomicU64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl World {
+    pub fn with_budget(budget: usize) -> Self {
+        Self { budget, ..Self::default() }
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+    }
+
+    pub fn metrics(&self) -> CacheMetrics {
+        CacheMetrics {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            chunks: self.chunks.len(),
+            bytes: self.bytes,
+            budget: self.budget,
+        }
+    }
+
+    fn touch(&self, cached: &CachedChunk) {
+        cached.last_used.store(self.clock.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    pub fn contains(&self, coords: &ChunkCoordinates) -> bool {
+        self.chunks.contains_key(&chunk_position(coords))
+    }
+
+    /// The chunk if it is in memory. Never loads anything.
+    pub fn get(&self, coords: &ChunkCoordinates) -> Option<&Chunk> {
+        self.chunks.get(&chunk_position(coords)).map(|cached| {
+            self.touch(cached);
+            &cached.chunk
+        })
+    }
+
+    pub fn get_cached(&self, coords: &ChunkCoordinates) -> Option<&CachedChunk> {
+        self.chunks.get(&chunk_position(coords))
+    }
+
+    /// The chunk, loading it from `source` if it isn't in memory
+    pub fn load(&mut self, coords: &ChunkCoordinates, source: &ChunkSource) -> &Chunk {
+        let position = chunk_position(coords);
+        if self.chunks.contains_key(&position) {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+            let chunk = source.load(coords);
+            self.insert(coords, chunk, false);
+            self.evict_to_budget(source, Some(position));
+        }
+        let cached = &self.chunks[&position];
+        self.touch(cached);
+        &cached.chunk
+    }
+
+    pub fn insert(&mut self, coords: &ChunkCoordinates, chunk: Chunk, dirty: bool) {
+        let footprint = chunk_footprint(&chunk);
+        let cached = CachedChunk {
+            chunk,
+            dirty,
+            footprint,
+            last_used: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+        };
+        self.bytes += footprint;
+        if let Some(previous) = self.chunks.insert(chunk_position(coords), cached) {
+            self.bytes -= previous.footprint;
+        }
+    }
+
+    /**
+     Swaps in an edited chunk, which is saved with the next `save_dirty` or eviction.
+     If the chunk is meshed, it is queued for remeshing. Edits can grow a chunk, so other chunks
+     may be evicted to stay within the budget.
+     */
+    pub fn replace(&mut self, coords: &ChunkCoordinates, chunk: Chunk, source: &ChunkSource) {
+        let position = chunk_position(coords);
+        self.insert(coords, chunk, true);
+        if self.is_meshed(coords) {
+            self.remesh.insert(position);
+        }
+        self.evict_to_budget(source, Some(position));
+    }
+
+    /// Voxel at a point measured in chunks, if its chunk is in memory
+    pub fn voxel_at(&self, point: [f32; 3]) -> Option<VoxelData> {
+        let position = [point[0].floor() as i32, point[1].floor() as i32, point[2].floor() as i32];
+        let chunk = self.get(&chunk_coordinates(position))?;
+        let local = [
+            point[0] - position[0] as f32,
+            point[1] - position[1] as f32,
+            point[2] - position[2] as f32,
+        ];
+        Some(*leaf_at(chunk, local).get_value())
+    }
+
+    /**
+     Edits a chunk at `2^depth` voxels per axis, loading it first if needed.
+     If the edit changed anything, the edited chunk replaces the cached one and is marked for saving.
+     */
+    pub fn edit<R>(
+        &mut self,
+        coords: &ChunkCoordinates,
+        depth: u8,
+        source: &ChunkSource,
+        edit: impl FnOnce(&mut ChunkEditor) -> R,
+    ) -> Result<R, ChunkFormatError> {
+        let chunk = self.load(coords, source);
+        let mut editor = ChunkEditor::new(chunk, chunk_position(coords), depth)?;
+        let result = edit(&mut editor);
+        if editor.is_changed() {
+            self.replace(coords, editor.finish(), source);
+        }
+        Ok(result)
+    }
+
+    /**
+     Casts a ray measured in chunks through the chunks in memory, returning the chunk and the first
+     leaf accepted by `hits`. Chunks that aren't in memory are passed through.
+     The hit position is in chunks as well, while its bounds are normalized to the chunk.
+     */
+    pub fn raycast(
+        &self,
+        origin: [f32; 3],
+        direction: [f32; 3],
+        max_distance: f32,
+        hits: impl Fn(VoxelData) -> bool,
+    ) -> Option<([i32; 3], RayHit)> {
+        chunks_along(origin, direction, max_distance).into_iter().find_map(|(position, _)| {
+            let chunk = self.get(&chunk_coordinates(position))?;
+            let local = [
+                origin[0] - position[0] as f32,
+                origin[1] - position[1] as f32,
+                origin[2] - position[2] as f32,
+            ];
+            let mut hit = raycast_filtered(chunk, local, direction, max_distance, &hits)?;
+            for i in 0..3 {
+                hit.position[i] += position[i] as f32;
+            }
+            Some((position, hit))
+        })
+    }
+
+    /**
+     Extents of the leaves accepted by `solid` that overlap a box, all measured in chunks.
+     Chunks that aren't in memory are treated as empty.
+     */
+    pub fn leaf_boxes(&self, aabb: &Aabb, solid: impl Fn(VoxelData) -> bool) -> Vec<Aabb> {
+        let min = [aabb.min[0].floor() as i32, aabb.min[1].floor() as i32, aabb.min[2].floor() as i32];
+        let max = [aabb.max[0].floor() as i32, aabb.max[1].floor() as i32, aabb.max[2].floor() as i32];
+        let mut boxes = Vec::new();
+        for x in min[0]..=max[0] {
+            for y in min[1]..=max[1] {
+                for z in min[2]..=max[2] {
+                    let chunk = match self.get(&chunk_coordinates([x, y, z])) {
+                        Some(chunk) => chunk,
+                        None => continue,
+                    };
+                    let origin = [x as f32, y as f32, z as f32];
+                    let local = Aabb::new(
+                        [aabb.min[0] - origin[0], aabb.min[1] - origin[1], aabb.min[2] - origin[2]],
+                        [aabb.max[0] - origin[0], aabb.max[1] - origin[1], aabb.max[2] - origin[2]],
+                    );
+                    for leaf in overlapping_leaves(chunk, &local, &solid) {
+                        let leaf = leaf_aabb(&leaf.get_bounds());
+                        boxes.push(Aabb::new(
+                            [leaf.min[0] + origin[0], leaf.min[1] + origin[1], leaf.min[2] + origin[2]],
+                            [leaf.max[0] + origin[0], leaf.max[1] + origin[1], leaf.max[2] + origin[2]],
+                        ));
+                    }
+                }
+            }
+        }
+        boxes
+    }
+
+    pub fn remove(&mut self, coords: &ChunkCoordinates) -> Option<CachedChunk> {
+        let removed = self.chunks.remove(&chunk_position(coords));
+        if let Some(cached) = &removed {
+            self.bytes -= cached.footprint;
+        }
+        removed
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ChunkCoordinates, &CachedChunk)> {
+        self.chunks.iter().map(|(position, cached)| (chunk_coordinates(*position), cached))
+    }
+
+    /**
+     Drops least recently used chunks until the budget is met, except `keep`.
+     Changed chunks are saved first; chunks that can't be saved stay in memory.
+     */
+    pub fn evict_to_budget(&mut self, source: &ChunkSource, keep: Option<[i32; 3]>) {
+        if self.budget == 0 || self.bytes <= self.budget {
+            return;
+        }
+        let mut candidates: Vec<([i32; 3], u64)> = self.chunks
+            .iter()
+            .filter(|(position, _)| Some(**position) != keep)
+            .map(|(position, cached)| (*position, cached.last_used.load(Ordering::Relaxed)))
+            .collect();
+        candidates.sort_by_key(|(_, last_used)| *last_used);
+
+        for (position, _) in candidates {
+            if self.bytes <= self.budget {
+                break;
+            }
+            let coords = chunk_coordinates(position);
+            let cached = &self.chunks[&position];
+            if cached.dirty {
+                match source.save(&coords, &cached.chunk) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(err) => {
+                        log::error!("Failed to save chunk {:?}, keeping it in memory: {}", position, err);
+                        continue;
+                    }
+                }
+            }
+            self.remove(&coords);
+            self.evictions += 1;
+        }
+        if self.bytes > self.budget {
+            log::warn!("Chunk cache is over its budget: {}", self.metrics());
+        }
+    }
+
+    /// Saves a changed chunk right away
+    pub fn save_chunk(&mut self, coords: &ChunkCoordinates, source: &ChunkSource) -> Result<(), SaveError> {
+        if let Some(cached) = self.chunks.get_mut(&chunk_position(coords)) {
+            if cached.dirty && source.save(coords, &cached.chunk)? {
+                cached.dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes every changed chunk to the save. Chunks that fail to save stay dirty.
+    pub fn save_dirty(&mut self, source: &ChunkSource) -> Result<usize, SaveError> {
+        let mut saved = 0;
+        for (position, cached) in self.chunks.iter_mut().filter(|(_, cached)| cached.dirty) {
+            if source.save(&chunk_coordinates(*position), &cached.chunk)? {
+                cached.dirty = false;
+                saved += 1;
+            }
+        }
+        Ok(saved)
+    }
+
+    pub fn is_meshed(&self, coords: &ChunkCoordinates) -> bool {
+        self.meshes.contains_key(&chunk_position(coords))
+    }
+
+    /// Records the entities rendering a chunk, returning the ones they replace
+    pub fn set_meshes(&mut self, coords: &ChunkCoordinates, entities: Vec<Entity>) -> Option<Vec<Entity>> {
+        self.meshes.insert(chunk_position(coords), entities)
+    }
+
+    pub fn take_meshes(&mut self, coords: &ChunkCoordinates) -> Option<Vec<Entity>> {
+        let position = chunk_position(coords);
+        self.remesh.remove(&position);
+        self.meshes.remove(&position)
+    }
+
+    /// Meshed chunks that changed since they were meshed, clearing the queue
+    pub fn take_remesh_requests(&mut self) -> Vec<[i32; 3]> {
+        self.remesh.drain().collect()
+    }
+
+    /// Positions of all chunks with meshes
+    pub fn meshed_positions(&self) -> impl Iterator<Item = [i32; 3]> + '_ {
+        self.meshes.keys().copied()
+    }
+}
+
+/// Where chunks come from: the save if it has them, the generator otherwise.
+pub struct ChunkSource {
+    generator: Box<dyn Oracle + Send + Sync>,
+    save: Option<WorldSave>,
+}
+
+impl ChunkSource {
+    pub fn new(generator: impl Oracle + Send + Sync + 'static, save: Option<WorldSave>) -> Self {
+        Self {
+            generator: Box::new(generator),
+            save,
+        }
+    }
+
+    pub fn save_data(&self) -> Option<&WorldSave> {
+        self.save.as_ref()
+    }
+
+    pub fn save_data_mut(&mut self) -> Option<&mut WorldSave> {
+        self.save.as_mut()
+    }
+
+    pub fn generate(&self, coords: &ChunkCoordinates) -> Chunk {
+        world_builder(&*self.generator).build(coords)
+    }
+
+    /// The saved chunk, or a freshly generated one if it was never saved or can't be read
+    pub fn load(&self, coords: &ChunkCoordinates) -> Chunk {
+        if let Some(save) = &self.save {
+            match save.load_chunk(coords) {
+                Ok(Some(chunk)) => return chunk,
+                Ok(None) => {}
+                Err(err) => log::error!(
+                    "Failed to load chunk {:?}, generating it again: {}",
+                    chunk_position(coords),
+                    err
+                ),
+            }
+        }
+        self.generate(coords)
+    }
+
+    /// Whether the chunk was saved. Without a save nothing is persisted.
+    pub fn save(&self, coords: &ChunkCoordinates, chunk: &Chunk) -> Result<bool, SaveError> {
+        match &self.save {
+            Some(save) => save.save_chunk(coords, chunk).map(|_| true),
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::MaterialRegistry;
+    use crate::octree::bounds::Bounds;
+    use crate::octree::world_builder::Isosurface;
+    use crate::storage::chunk::Tree;
+    use crate::storage::save::{GeneratorSettings, WorldManifest};
+    use crate::worldgen::biome::BiomeSettings;
+    use crate::worldgen::caves::CaveSettings;
+
+    fn stone(_: &ChunkCoordinates, _: &Bounds) -> Isosurface<VoxelData> {
+        Isosurface::Uniform(VoxelData::solid(1))
+    }
+
+    /// Generates single leaf chunks, and persists nothing
+    fn source() -> ChunkSource {
+        ChunkSource::new(stone, None)
+    }
+
+    fn coords(x: i32) -> ChunkCoordinates {
+        chunk_coordinates([x, 0, 0])
+    }
+
+    /// Eight leaves under one branch
+    fn detailed_tree() -> Tree {
+        let mut tree = Tree::Leaf(VoxelData::solid(1));
+        tree.set_node(1, [0, 0, 0], Tree::Leaf(VoxelData::EMPTY));
+        tree
+    }
+
+    #[test]
+    fn test_footprint_accounting() {
+        let source = source();
+        let mut world = World::default();
+        world.load(&coords(0), &source);
+        world.load(&coords(1), &source);
+        assert_eq!(world.metrics().bytes, 2 * NODE_SIZE);
+
+        let detailed = detailed_tree().to_chunk([0, 0, 0]);
+        assert_eq!(chunk_footprint(&detailed), 9 * NODE_SIZE);
+        world.replace(&coords(0), detailed, &source);
+        assert_eq!(world.metrics().bytes, 10 * NODE_SIZE);
+        assert!(world.get_cached(&coords(0)).unwrap().dirty);
+
+        world.remove(&coords(0));
+        assert_eq!(world.metrics().bytes, NODE_SIZE);
+        assert_eq!(world.metrics().chunks, 1);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let source = source();
+        let mut world = World::with_budget(2 * NODE_SIZE);
+        world.load(&coords(0), &source);
+        world.load(&coords(1), &source);
+        // Chunk 0 is now more recent than chunk 1
+        world.load(&coords(0), &source);
+        world.load(&coords(2), &source);
+        assert!(world.contains(&coords(0)) && world.contains(&coords(2)));
+        assert!(!world.contains(&coords(1)));
+        assert_eq!(
+            world.metrics(),
+            CacheMetrics { hits: 1, misses: 3, evictions: 1, chunks: 2, bytes: 2 * NODE_SIZE, budget: 2 * NODE_SIZE },
+        );
+    }
+
+    #[test]
+    fn test_keeps_the_chunk_just_loaded() {
+        let source = source();
+        // Not even one chunk fits
+        let mut world = World::with_budget(1);
+        world.load(&coords(0), &source);
+        assert!(world.contains(&coords(0)));
+        world.load(&coords(1), &source);
+        assert!(!world.contains(&coords(0)) && world.contains(&coords(1)));
+        assert_eq!(world.metrics().evictions, 1);
+    }
+
+    #[test]
+    fn test_replace_evicts() {
+        let source = source();
+        let mut world = World::with_budget(9 * NODE_SIZE);
+        world.load(&coords(0), &source);
+        world.load(&coords(1), &source);
+        world.replace(&coords(1), detailed_tree().to_chunk([1, 0, 0]), &source);
+        assert!(!world.contains(&coords(0)) && world.contains(&coords(1)));
+        assert_eq!(world.metrics().bytes, 9 * NODE_SIZE);
+    }
+
+    #[test]
+    fn test_unsaved_chunks_are_kept() {
+        let source = source();
+        let mut world = World::with_budget(2 * NODE_SIZE);
+        world.load(&coords(0), &source);
+        world.replace(&coords(0), source.generate(&coords(0)), &source);
+        world.load(&coords(1), &source);
+        world.load(&coords(2), &source);
+        // Chunk 0 is the oldest, but there is nowhere to save its changes
+        assert!(world.contains(&coords(0)) && world.get_cached(&coords(0)).unwrap().dirty);
+        assert!(!world.contains(&coords(1)) && world.contains(&coords(2)));
+        assert_eq!(world.metrics().evictions, 1);
+    }
+
+    #[test]
+    fn test_edit_replaces_changed_chunks_only() {
+        let source = source();
+        let mut world = World::default();
+        world.set_meshes(&coords(0), Vec::new());
+        world.edit(&coords(0), 2, &source, |editor| editor.set_voxel([1, 1, 1], VoxelData::solid(1))).unwrap();
+        assert!(!world.get_cached(&coords(0)).unwrap().dirty);
+        assert!(world.take_remesh_requests().is_empty());
+
+        world.edit(&coords(0), 2, &source, |editor| editor.set_voxel([1, 1, 1], VoxelData::EMPTY)).unwrap();
+        assert!(world.get_cached(&coords(0)).unwrap().dirty);
+        assert_eq!(world.take_remesh_requests(), vec![[0, 0, 0]]);
+    }
+
+    #[test]
+    fn test_dirty_chunks_are_saved_before_eviction() {
+        let root = std::env::temp_dir().join(format!("gog-world-evict-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let biomes = BiomeSettings {
+            seed: 0,
+            climate_frequency: 0.01,
+            octaves: 1,
+            frequency: 0.05,
+            persistence: 0.5,
+            lacunarity: 2.0,
+            chunk_size: 64.0,
+            voxel_size: 0.5,
+            height_blend: 0.2,
+            biomes: Vec::new(),
+        };
+        let generator = GeneratorSettings { biomes, caves: CaveSettings::default() };
+        let manifest = WorldManifest::new(0, generator, &MaterialRegistry::default());
+        let source = ChunkSource::new(stone, Some(WorldSave::create(&root, manifest).unwrap()));
+
+        let mut world = World::with_budget(2 * NODE_SIZE);
+        world.load(&coords(0), &source);
+        world.replace(&coords(0), detailed_tree().to_chunk([0, 0, 0]), &source);
+        world.load(&coords(1), &source);
+        assert!(!world.contains(&coords(0)));
+        assert_eq!(world.metrics().evictions, 1);
+
+        let saved = source.save_data().unwrap().load_chunk(&coords(0)).unwrap().unwrap();
+        assert_eq!(Tree::from_chunk(&saved).unwrap(), detailed_tree());
+        // Loading it again reads the save instead of generating it
+        let loaded = world.load(&coords(0), &source);
+        assert_eq!(Tree::from_chunk(loaded).unwrap(), detailed_tree());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
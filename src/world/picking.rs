@@ -0,0 +1,65 @@
+use amethyst::{
+    controls::HideCursor,
+    core::{
+        geometry::Ray,
+        math::{Point2, Vector2},
+        transform::Transform,
+    },
+    input::{InputHandler, StringBindings},
+    renderer::camera::Camera,
+    window::ScreenDimensions,
+};
+
+use crate::octree::chunk_coordinates;
+use crate::octree::raycast::RayHit;
+use crate::octree::VoxelData;
+use crate::util::aabb::Aabb;
+use crate::world::streaming::StreamingSettings;
+use crate::world::World;
+
+/**
+ Ray from the camera through the mouse cursor. While the cursor is grabbed by the fly controls,
+ it goes through the center of the screen instead.
+ */
+pub fn cursor_ray(
+    input: &InputHandler<StringBindings>,
+    hide_cursor: &HideCursor,
+    screen: &ScreenDimensions,
+    camera: &Camera,
+    transform: &Transform,
+) -> Ray<f32> {
+    let cursor = match input.mouse_position() {
+        Some((x, y)) if !hide_cursor.hide => Point2::new(x, y),
+        _ => Point2::new(screen.width() / 2.0, screen.height() / 2.0),
+    };
+    camera.screen_ray(cursor, Vector2::new(screen.width(), screen.height()), transform)
+}
+
+/// The leaf a ray hit, with its position and distance in render units
+pub struct Pick {
+    pub chunk: [i32; 3],
+    pub hit: RayHit,
+    /// Extents of the leaf in render units
+    pub aabb: Aabb,
+}
+
+/// Casts a ray in render units against the chunks in memory, stopping at the first leaf accepted by `hits`
+pub fn pick(
+    world: &World,
+    settings: &StreamingSettings,
+    ray: &Ray<f32>,
+    reach: f32,
+    hits: impl Fn(VoxelData) -> bool,
+) -> Option<Pick> {
+    // The world is cast against in chunks
+    let scale = settings.chunk_size;
+    let origin: [f32; 3] = ray.origin.coords.into();
+    let origin = [origin[0] / scale, origin[1] / scale, origin[2] / scale];
+    let (chunk, mut hit) = world.raycast(origin, ray.direction.into(), reach / scale, hits)?;
+    for axis in &mut hit.position {
+        *axis *= scale;
+    }
+    hit.distance *= scale;
+    let aabb = Aabb::from_bounds(&chunk_coordinates(chunk), &hit.bounds, scale);
+    Some(Pick { chunk, hit, aabb })
+}
@@ -0,0 +1,385 @@
+use amethyst::{
+    controls::HideCursor,
+    core::{transform::Transform, Time},
+    ecs::{Join, Read, ReadExpect, ReadStorage, System, Write},
+    input::{InputHandler, StringBindings},
+    renderer::camera::Camera,
+    window::ScreenDimensions,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::material::MaterialRegistry;
+use crate::octree::edit::ChunkEditor;
+use crate::octree::{chunk_coordinates, VoxelData};
+use crate::world::controller::CameraMode;
+use crate::world::history::{ChunkChange, Edit, History};
+use crate::world::picking::{cursor_ray, pick};
+use crate::world::streaming::StreamingSettings;
+use crate::world::{ChunkSource, World};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BrushKind {
+    /// Fills empty voxels in a sphere with the brush material
+    Add,
+    /// Empties a sphere
+    Subtract,
+    /// Replaces every voxel in a sphere with the majority of its neighbours
+    Smooth,
+    /// Empties the sphere above the horizontal plane through its center and fills it below
+    Flatten,
+    /// Changes the material of the non-empty voxels in a sphere
+    Paint,
+}
+
+impl BrushKind {
+    /**
+     Every brush in the order their actions are checked. Combinations such as Shift + left click
+     also trigger the plain click, so brushes bound to them come first.
+     */
+    pub const ALL: [BrushKind; 5] = [
+        BrushKind::Flatten,
+        BrushKind::Paint,
+        BrushKind::Smooth,
+        BrushKind::Add,
+        BrushKind::Subtract,
+    ];
+
+    /// Name of the action in `config/input.ron` that applies the brush
+    pub fn action(self) -> &'static str {
+        match self {
+            BrushKind::Add => "brush_add",
+            BrushKind::Subtract => "brush_subtract",
+            BrushKind::Smooth => "brush_smooth",
+            BrushKind::Flatten => "brush_flatten",
+            BrushKind::Paint => "brush_paint",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BrushSettings {
+    /// Brush radius in voxels
+    pub radius: f32,
+    /// Chunks are edited at `2^depth` voxels per axis
+    pub depth: u8,
+    /// Material placed by the add, flatten and paint brushes
+    pub material: u16,
+    /// Seconds between strokes while a button is held
+    pub interval: f32,
+    /// How far from the camera the cursor ray looks for terrain, in render units
+    pub reach: f32,
+    /// Memory for undo history in MiB before the oldest edits are forgotten, 0 for no limit
+    pub history_budget_mb: usize,
+}
+
+impl Default for BrushSettings {
+    fn default() -> Self {
+        Self {
+            radius: 4.0,
+            depth: 7,
+            material: 1,
+            interval: 0.1,
+            reach: 8.0,
+            history_budget_mb: 64,
+        }
+    }
+}
+
+impl BrushSettings {
+    /// Width of a voxel in render units
+    pub fn voxel_size(&self, chunk_size: f32) -> f32 {
+        chunk_size / (1u32 << self.depth) as f32
+    }
+
+    pub fn history_budget(&self) -> usize {
+        self.history_budget_mb * 1024 * 1024
+    }
+}
+
+/// A single stroke of a brush, in render units
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Brush {
+    pub kind: BrushKind,
+    pub center: [f32; 3],
+    pub radius: f32,
+    pub material: VoxelData,
+}
+
+impl Brush {
+    /// Chunks the stroke reaches
+    pub fn chunks(&self, settings: &StreamingSettings) -> Vec<[i32; 3]> {
+        // Smoothing reads one voxel beyond the sphere, which is well within this
+        let min = settings.chunk_at([
+            self.center[0] - self.radius,
+            self.center[1] - self.radius,
+            self.center[2] - self.radius,
+        ]);
+        let max = settings.chunk_at([
+            self.center[0] + self.radius,
+            self.center[1] + self.radius,
+            self.center[2] + self.radius,
+        ]);
+        let mut chunks = Vec::new();
+        for x in min[0]..=max[0] {
+            for y in min[1]..=max[1] {
+                for z in min[2]..=max[2] {
+                    chunks.push([x, y, z]);
+                }
+            }
+        }
+        chunks
+    }
+
+    /**
+     Applies the stroke to the chunk whose minimum corner is `origin`, spanning `chunk_size` render units.
+     Returns the number of voxels changed.
+     */
+    pub fn apply(&self, editor: &mut ChunkEditor, origin: [f32; 3], chunk_size: f32) -> usize {
+        let resolution = editor.resolution();
+        let scale = resolution as f32 / chunk_size;
+        // In voxels, with voxel `i` centered on `i`
+        let center = [
+            (self.center[0] - origin[0]) * scale - 0.5,
+            (self.center[1] - origin[1]) * scale - 0.5,
+            (self.center[2] - origin[2]) * scale - 0.5,
+        ];
+        let radius = self.radius * scale;
+        let range = |c: f32| {
+            let first = (c - radius).ceil().max(0.0) as u32;
+            let last = ((c + radius).floor() + 1.0).max(0.0).min(resolution as f32) as u32;
+            first..last
+        };
+
+        let mut changes = Vec::new();
+        for x in range(center[0]) {
+            for y in range(center[1]) {
+                for z in range(center[2]) {
+                    let (dx, dy, dz) = (x as f32 - center[0], y as f32 - center[1], z as f32 - center[2]);
+                    if dx * dx + dy * dy + dz * dz > radius * radius {
+                        continue;
+                    }
+                    let voxel = [x, y, z];
+                    let current = editor.get_voxel(voxel);
+                    let value = match self.kind {
+                        BrushKind::Add if current.is_empty() => self.material,
+                        BrushKind::Add => current,
+                        BrushKind::Subtract => VoxelData::EMPTY,
+                        BrushKind::Smooth => majority(editor, voxel),
+                        BrushKind::Flatten if dy > 0.0 => VoxelData::EMPTY,
+                        BrushKind::Flatten if current.is_empty() => self.material,
+                        BrushKind::Flatten => current,
+                        BrushKind::Paint if current.is_empty() => current,
+                        BrushKind::Paint => self.material,
+                    };
+                    if value != current {
+                        changes.push((voxel, value));
+                    }
+                }
+            }
+        }
+        // Smoothing reads the neighbours as they were before the stroke
+        for (voxel, value) in &changes {
+            editor.set_voxel(*voxel, *value);
+        }
+        changes.len()
+    }
+}
+
+/// Empty if most of the 3x3x3 neighbourhood inside the chunk is, otherwise its most common voxel
+fn majority(editor: &ChunkEditor, voxel: [u32; 3]) -> VoxelData {
+    let resolution = editor.resolution() as i64;
+    let mut counts: Vec<(VoxelData, usize)> = Vec::new();
+    let mut total = 0;
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            for dz in -1..=1 {
+                let neighbour = [voxel[0] as i64 + dx, voxel[1] as i64 + dy, voxel[2] as i64 + dz];
+                if neighbour.iter().any(|v| *v < 0 || *v >= resolution) {
+                    continue;
+                }
+                total += 1;
+                let value = editor.get_voxel([neighbour[0] as u32, neighbour[1] as u32, neighbour[2] as u32]);
+                if value.is_empty() {
+                    continue;
+                }
+                match counts.iter_mut().find(|(counted, _)| *counted == value) {
+                    Some((_, count)) => *count += 1,
+                    None => counts.push((value, 1)),
+                }
+            }
+        }
+    }
+    let filled: usize = counts.iter().map(|(_, count)| count).sum();
+    if filled * 2 <= total {
+        return VoxelData::EMPTY;
+    }
+    counts
+        .into_iter()
+        .max_by_key(|(_, count)| *count)
+        .map(|(value, _)| value)
+        .unwrap_or(VoxelData::EMPTY)
+}
+
+/**
+ Applies brushes to the terrain under the cursor while their actions are held.
+ Edited chunks are remeshed by `ChunkStreamingSystem`, and everything done while a button is held
+ is recorded in the `History` as one edit.
+ */
+#[derive(Default)]
+pub struct SculptSystem {
+    /// Seconds until the next stroke
+    cooldown: f32,
+    /// Changes of the strokes since the button was pressed
+    edit: Edit,
+}
+
+impl<'a> System<'a> for SculptSystem {
+    type SystemData = (
+        Read<'a, InputHandler<StringBindings>>,
+        Read<'a, Time>,
+        Read<'a, HideCursor>,
+        ReadExpect<'a, ScreenDimensions>,
+        ReadStorage<'a, Camera>,
+        ReadStorage<'a, Transform>,
+        Write<'a, World>,
+        ReadExpect<'a, ChunkSource>,
+        ReadExpect<'a, MaterialRegistry>,
+        Read<'a, StreamingSettings>,
+        Read<'a, BrushSettings>,
+        Write<'a, History>,
+        Read<'a, CameraMode>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            input,
+            time,
+            hide_cursor,
+            screen,
+            cameras,
+            transforms,
+            mut world,
+            source,
+            registry,
+            settings,
+            brush,
+            mut history,
+            mode,
+        ): Self::SystemData,
+    ) {
+        self.cooldown = (self.cooldown - time.delta_seconds()).max(0.0);
+        // Dragging the mouse turns the orbit camera instead
+        if *mode == CameraMode::Orbit {
+            return;
+        }
+        let kind = match BrushKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| input.action_is_down(kind.action()).unwrap_or(false))
+        {
+            Some(kind) => kind,
+            None => {
+                // Every click strokes right away
+                self.cooldown = 0.0;
+                history.record(std::mem::take(&mut self.edit));
+                return;
+            }
+        };
+        if self.cooldown > 0.0 {
+            return;
+        }
+        self.cooldown = brush.interval;
+
+        let (camera, transform) = match (&cameras, &transforms).join().next() {
+            Some(camera) => camera,
+            None => return,
+        };
+        let ray = cursor_ray(&input, &hide_cursor, &screen, camera, transform);
+        let center = match pick(&world, &settings, &ray, brush.reach, |value| registry.is_solid(value)) {
+            Some(pick) => pick.hit.position,
+            None => return,
+        };
+        let stroke = Brush {
+            kind,
+            center,
+            radius: brush.radius * brush.voxel_size(settings.chunk_size),
+            material: registry.voxel(brush.material),
+        };
+
+        for position in stroke.chunks(&settings) {
+            let origin = settings.chunk_origin(position);
+            let result = world.edit(&chunk_coordinates(position), brush.depth, &source, |editor| {
+                stroke.apply(editor, origin, settings.chunk_size);
+                ChunkChange::diff(position, editor.original(), editor.tree())
+            });
+            match result {
+                Ok(change) => self.edit.changes.extend(change),
+                Err(err) => log::error!("Failed to sculpt chunk {:?}: {}", position, err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::chunk::Tree;
+
+    fn editor(value: VoxelData) -> ChunkEditor {
+        ChunkEditor::from_tree(Tree::Leaf(value), [0, 0, 0], 4)
+    }
+
+    fn brush(kind: BrushKind) -> Brush {
+        // Centered on voxel [8, 8, 8] of a 16^3 chunk spanning one render unit
+        Brush {
+            kind,
+            center: [8.5 / 16.0; 3],
+            radius: 3.0 / 16.0,
+            material: VoxelData::solid(2),
+        }
+    }
+
+    #[test]
+    fn test_add_and_subtract() {
+        let mut editor = editor(VoxelData::EMPTY);
+        let changed = brush(BrushKind::Add).apply(&mut editor, [0.0; 3], 1.0);
+        assert!(changed > 0);
+        assert_eq!(editor.get_voxel([8, 8, 8]), VoxelData::solid(2));
+        assert_eq!(editor.get_voxel([11, 8, 8]), VoxelData::solid(2));
+        assert_eq!(editor.get_voxel([11, 9, 8]), VoxelData::EMPTY);
+
+        let removed = brush(BrushKind::Subtract).apply(&mut editor, [0.0; 3], 1.0);
+        assert_eq!(removed, changed);
+        assert_eq!(editor.tree(), &Tree::Leaf(VoxelData::EMPTY));
+    }
+
+    #[test]
+    fn test_flatten_and_paint() {
+        let mut editor = editor(VoxelData::solid(1));
+        brush(BrushKind::Flatten).apply(&mut editor, [0.0; 3], 1.0);
+        assert_eq!(editor.get_voxel([8, 9, 8]), VoxelData::EMPTY);
+        assert_eq!(editor.get_voxel([8, 8, 8]), VoxelData::solid(1));
+
+        brush(BrushKind::Paint).apply(&mut editor, [0.0; 3], 1.0);
+        assert_eq!(editor.get_voxel([8, 8, 8]), VoxelData::solid(2));
+        assert_eq!(editor.get_voxel([8, 9, 8]), VoxelData::EMPTY);
+    }
+
+    #[test]
+    fn test_smooth_removes_spikes() {
+        let mut editor = editor(VoxelData::EMPTY);
+        editor.set_voxel([8, 8, 8], VoxelData::solid(1));
+        brush(BrushKind::Smooth).apply(&mut editor, [0.0; 3], 1.0);
+        assert_eq!(editor.tree(), &Tree::Leaf(VoxelData::EMPTY));
+    }
+
+    #[test]
+    fn test_chunks() {
+        let settings = StreamingSettings::default();
+        let stroke = Brush { center: [0.95, 0.5, 0.02], radius: 0.1, ..brush(BrushKind::Add) };
+        let chunks = stroke.chunks(&settings);
+        assert_eq!(chunks, vec![[0, 0, -1], [0, 0, 0], [1, 0, -1], [1, 0, 0]]);
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+
+use amethyst::{
+    assets::{AssetLoaderSystemData, Handle},
+    controls::FlyControlTag,
+    core::transform::Transform,
+    derive::SystemDesc,
+    ecs::{Entities, Join, Read, ReadExpect, ReadStorage, System, SystemData, Write, WriteStorage},
+    renderer::{mtl::Material, Mesh},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::material::MaterialRegistry;
+use crate::octree::mesher::dualmc::MeshGenerator;
+use crate::octree::mesher::Mesher;
+use crate::octree::{chunk_coordinates, Chunk};
+use crate::world::{ChunkSource, World};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamingSettings {
+    /// Chunks within this horizontal distance of the camera's chunk are loaded, measured in chunks
+    pub radius: i32,
+    /// Chunks this many layers above and below the camera's chunk are loaded
+    pub vertical_radius: i32,
+    /// Extra distance before a loaded chunk is dropped, so crossing a border back and forth
+    /// doesn't reload chunks
+    pub unload_margin: i32,
+    /// Save changed chunks when they leave the streaming radius, rather than when they are evicted
+    pub save_on_unload: bool,
+    /// Memory for chunk data in MiB before least recently used chunks are evicted, 0 for no limit
+    pub memory_budget_mb: usize,
+    /// Generating and meshing a chunk is slow, so only this many are loaded per frame
+    pub max_loads_per_frame: usize,
+    /// Render units spanned by one chunk
+    pub chunk_size: f32,
+}
+
+impl Default for StreamingSettings {
+    fn default() -> Self {
+        Self {
+            radius: 2,
+            vertical_radius: 1,
+            unload_margin: 1,
+            save_on_unload: true,
+            memory_budget_mb: 256,
+            max_loads_per_frame: 1,
+            chunk_size: 1.0,
+        }
+    }
+}
+
+impl StreamingSettings {
+    pub fn memory_budget(&self) -> usize {
+        self.memory_budget_mb * 1024 * 1024
+    }
+
+    /// Chunk containing a point in render units
+    pub fn chunk_at(&self, point: [f32; 3]) -> [i32; 3] {
+        let cell = |v: f32| (v / self.chunk_size).floor() as i32;
+        [cell(point[0]), cell(point[1]), cell(point[2])]
+    }
+
+    /// Minimum corner of a chunk in render units
+    pub fn chunk_origin(&self, chunk: [i32; 3]) -> [f32; 3] {
+        [
+            chunk[0] as f32 * self.chunk_size,
+            chunk[1] as f32 * self.chunk_size,
+            chunk[2] as f32 * self.chunk_size,
+        ]
+    }
+
+    fn within(&self, center: [i32; 3], chunk: [i32; 3], margin: i32) -> bool {
+        let (dx, dy, dz) = (chunk[0] - center[0], chunk[1] - center[1], chunk[2] - center[2]);
+        let radius = self.radius + margin;
+        dx * dx + dz * dz <= radius * radius && dy.abs() <= self.vertical_radius + margin
+    }
+
+    /// Chunks that should be loaded around `center`, nearest first
+    pub fn wanted(&self, center: [i32; 3]) -> Vec<[i32; 3]> {
+        let mut wanted = Vec::new();
+        for dy in -self.vertical_radius..=self.vertical_radius {
+            for dz in -self.radius..=self.radius {
+                for dx in -self.radius..=self.radius {
+                    let chunk = [center[0] + dx, center[1] + dy, center[2] + dz];
+                    if self.within(center, chunk, 0) {
+                        wanted.push(chunk);
+                    }
+                }
+            }
+        }
+        wanted.sort_by_key(|chunk| {
+            let (dx, dy, dz) = (chunk[0] - center[0], chunk[1] - center[1], chunk[2] - center[2]);
+            dx * dx + dy * dy + dz * dz
+        });
+        wanted
+    }
+}
+
+/// Render `Material` of every registry entry, as created by `MaterialRegistry::create_render_materials`
+#[derive(Default)]
+pub struct RenderMaterials(pub HashMap<u16, Handle<Material>>);
+
+/**
+ Loads and meshes chunks around the `FlyControlTag` camera through the `World` cache, deletes
+ the meshes of chunks that are left behind and remeshes edited chunks.
+ */
+#[derive(SystemDesc)]
+pub struct ChunkStreamingSystem;
+
+impl<'a> System<'a> for ChunkStreamingSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, FlyControlTag>,
+        WriteStorage<'a, Transform>,
+        WriteStorage<'a, Handle<Mesh>>,
+        WriteStorage<'a, Handle<Material>>,
+        Write<'a, World>,
+        ReadExpect<'a, ChunkSource>,
+        ReadExpect<'a, MaterialRegistry>,
+        Read<'a, RenderMaterials>,
+        Read<'a, StreamingSettings>,
+        AssetLoaderSystemData<'a, Mesh>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            cameras,
+            mut transforms,
+            mut meshes,
+            mut materials,
+            mut world,
+            source,
+            registry,
+            render_materials,
+            settings,
+            loader,
+        ): Self::SystemData,
+    ) {
+        let camera = match (&cameras, &transforms).join().next() {
+            Some((_, transform)) => settings.chunk_at((*transform.translation()).into()),
+            None => return,
+        };
+
+        let leaving: Vec<[i32; 3]> = world
+            .meshed_positions()
+            .filter(|position| !settings.within(camera, *position, settings.unload_margin))
+            .collect();
+        for position in leaving {
+            let coords = chunk_coordinates(position);
+            for entity in world.take_meshes(&coords).unwrap_or_default() {
+                if let Err(err) = entities.delete(entity) {
+                    log::warn!("Failed to delete an entity of chunk {:?}: {}", position, err);
+                }
+            }
+            // The chunk data stays cached until it is evicted
+            if settings.save_on_unload {
+                if let Err(err) = world.save_chunk(&coords, &source) {
+                    log::error!("Failed to save chunk {:?}: {}", position, err);
+                }
+            }
+        }
+
+        let mut spawn = |chunk: &Chunk, position: [i32; 3]| {
+            let mesh_builders = MeshGenerator::new(chunk, settings.chunk_size, &registry).into_mesh_builders();
+            let origin = settings.chunk_origin(position);
+            let mut spawned = Vec::new();
+            for (material_id, mesh_builder) in mesh_builders {
+                let material = match render_materials.0.get(&material_id) {
+                    Some(material) => material.clone(),
+                    None => {
+                        log::warn!("Material {} is missing from the registry, skipping its mesh", material_id);
+                        continue;
+                    }
+                };
+                let mesh = loader.load_from_data(mesh_builder.into(), ());
+                let mut transform = Transform::default();
+                transform.set_translation_xyz(origin[0], origin[1], origin[2]);
+                spawned.push(
+                    entities
+                        .build_entity()
+                        .with(transform, &mut transforms)
+                        .with(mesh, &mut meshes)
+                        .with(material, &mut materials)
+                        .build(),
+                );
+            }
+            spawned
+        };
+
+        // Edited chunks are remeshed right away, however many there are, so strokes show up in one frame
+        for position in world.take_remesh_requests() {
+            let coords = chunk_coordinates(position);
+            let spawned = spawn(world.load(&coords, &source), position);
+            for entity in world.set_meshes(&coords, spawned).unwrap_or_default() {
+                if let Err(err) = entities.delete(entity) {
+                    log::warn!("Failed to delete an entity of chunk {:?}: {}", position, err);
+                }
+            }
+        }
+
+        let missing = settings
+            .wanted(camera)
+            .into_iter()
+            .filter(|position| !world.is_meshed(&chunk_coordinates(*position)))
+            .take(settings.max_loads_per_frame)
+            .collect::<Vec<_>>();
+        for position in missing {
+            let coords = chunk_coordinates(position);
+            let spawned = spawn(world.load(&coords, &source), position);
+            world.set_meshes(&coords, spawned);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StreamingSettings;
+
+    #[test]
+    fn test_wanted() {
+        let settings = StreamingSettings { radius: 2, vertical_radius: 0, ..StreamingSettings::default() };
+        let wanted = settings.wanted([10, 0, -3]);
+        assert_eq!(wanted[0], [10, 0, -3]);
+        // A disc of radius 2 has 13 cells
+        assert_eq!(wanted.len(), 13);
+        assert!(!wanted.contains(&[12, 0, -1]));
+        assert_eq!(settings.chunk_at([-0.5, 0.2, 3.0]), [-1, 0, 3]);
+    }
+}
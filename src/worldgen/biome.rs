@@ -0,0 +1,506 @@
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::material::MaterialRegistry;
+use crate::octree::bounds::Bounds;
+use crate::octree::world::ChunkCoordinates;
+use crate::octree::world_builder::Isosurface;
+use crate::octree::VoxelData;
+use crate::util::aabb::Aabb;
+use crate::worldgen::noise::Fractal;
+use crate::worldgen::{density, is_voxel_sized, Oracle};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Layer {
+    /// Material name from the material registry
+    pub material: String,
+    pub depth: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BiomeDefinition {
+    pub name: String,
+    /// Preferred climate, both in [-1, 1]. Each point takes its materials from the biome with the
+    /// nearest climate, and blends its height with the biomes almost as near.
+    pub temperature: f32,
+    pub humidity: f32,
+    pub base_height: f32,
+    pub amplitude: f32,
+    /// Layers from the surface downwards
+    pub layers: Vec<Layer>,
+    /// Material below the last layer
+    pub base: String,
+    /// Fraction of surface columns that grow vegetation, in [0, 1]
+    #[serde(default)]
+    pub vegetation_density: f32,
+    /// Material of the shrubs on vegetated columns, no vegetation if not set
+    #[serde(default)]
+    pub vegetation: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BiomeSettings {
+    pub seed: u64,
+    /// Frequency of the temperature and humidity maps, in cycles per world unit
+    pub climate_frequency: f32,
+    pub octaves: u32,
+    pub frequency: f32,
+    pub persistence: f32,
+    pub lacunarity: f32,
+    /// World units spanned by one chunk
+    pub chunk_size: f32,
+    /// Voxel size in world units, see `worldgen::is_voxel_sized`
+    pub voxel_size: f32,
+    /// Biomes whose climate is at most this much farther than the nearest one's contribute to
+    /// the terrain height, so that it doesn't jump at biome borders
+    #[serde(default = "BiomeSettings::default_height_blend")]
+    pub height_blend: f32,
+    pub biomes: Vec<BiomeDefinition>,
+}
+
+impl BiomeSettings {
+    fn default_height_blend() -> f32 {
+        0.2
+    }
+}
+
+// Shrubs are one world unit cube per vegetated column
+const SHRUB_HEIGHT: f32 = 1.0;
+
+#[derive(Debug)]
+pub enum BiomeError {
+    Io(PathBuf, std::io::Error),
+    Parse(ron::de::Error),
+    NoBiomes,
+    UnknownMaterial { biome: String, material: String },
+}
+
+impl fmt::Display for BiomeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BiomeError::Io(path, err) => write!(f, "failed to read {}: {}", path.display(), err),
+            BiomeError::Parse(err) => write!(f, "failed to parse biome settings: {}", err),
+            BiomeError::NoBiomes => write!(f, "at least one biome must be defined"),
+            BiomeError::UnknownMaterial { biome, material } => {
+                write!(f, "biome {} uses material {} which is not in the registry", biome, material)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BiomeError {}
+
+/// A biome with its material names resolved to voxels.
+#[derive(Clone, Debug)]
+pub struct Biome {
+    pub definition: BiomeDefinition,
+    /// (voxel, depth of the bottom of the layer)
+    layers: Vec<(VoxelData, f32)>,
+    base: VoxelData,
+    vegetation: Option<VoxelData>,
+}
+
+impl Biome {
+    fn resolve(definition: BiomeDefinition, materials: &MaterialRegistry) -> Result<Self, BiomeError> {
+        let resolve = |name: &str| materials
+            .get_by_name(name)
+            .map(|material| materials.voxel(material.id))
+            .ok_or_else(|| BiomeError::UnknownMaterial {
+                biome: definition.name.clone(),
+                material: name.to_string(),
+            });
+        let mut bottom = 0.0;
+        let mut layers = Vec::with_capacity(definition.layers.len());
+        for layer in &definition.layers {
+            bottom += layer.depth;
+            layers.push((resolve(&layer.material)?, bottom));
+        }
+        let base = resolve(&definition.base)?;
+        let vegetation = definition.vegetation.as_ref().map(|name| resolve(name)).transpose()?;
+        Ok(Self { definition, layers, base, vegetation })
+    }
+
+    pub fn material_at_depth(&self, depth: f32) -> VoxelData {
+        if depth < 0.0 {
+            return VoxelData::EMPTY;
+        }
+        self.layers
+            .iter()
+            .find(|(_, bottom)| depth < *bottom)
+            .map(|(voxel, _)| *voxel)
+            .unwrap_or(self.base)
+    }
+
+    fn layers_depth(&self) -> f32 {
+        self.layers.last().map(|(_, bottom)| *bottom).unwrap_or(0.0)
+    }
+
+    fn climate_distance_squared(&self, temperature: f32, humidity: f32) -> f32 {
+        (self.definition.temperature - temperature).powi(2) + (self.definition.humidity - humidity).powi(2)
+    }
+
+    fn height(&self, noise: f32) -> f32 {
+        self.definition.base_height + self.definition.amplitude * noise
+    }
+
+    fn grows_vegetation(&self) -> bool {
+        self.vegetation.is_some() && self.definition.vegetation_density > 0.0
+    }
+}
+
+/**
+ Splits the world into biomes by temperature and humidity noise maps.
+ The biome decides the height profile, the material layers and how much vegetation grows.
+ Height profiles are blended across biome borders, materials and vegetation are not.
+ */
+pub struct BiomeMap {
+    settings: BiomeSettings,
+    biomes: Vec<Biome>,
+    temperature: Fractal,
+    humidity: Fractal,
+    height: Fractal,
+}
+
+impl BiomeMap {
+    pub fn load(path: impl AsRef<Path>, materials: &MaterialRegistry) -> Result<Self, BiomeError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|err| BiomeError::Io(path.to_path_buf(), err))?;
+        let settings: BiomeSettings = ron::de::from_reader(file).map_err(BiomeError::Parse)?;
+        Self::new(settings, materials)
+    }
+
+    pub fn new(settings: BiomeSettings, materials: &MaterialRegistry) -> Result<Self, BiomeError> {
+        if settings.biomes.is_empty() {
+            return Err(BiomeError::NoBiomes);
+        }
+        let biomes = settings.biomes
+            .iter()
+            .cloned()
+            .map(|definition| Biome::resolve(definition, materials))
+            .collect::<Result<Vec<_>, _>>()?;
+        let climate = |seed| Fractal::new(seed, 2, settings.climate_frequency, 0.5, 2.0);
+        Ok(Self {
+            temperature: climate(settings.seed.wrapping_add(1)),
+            humidity: climate(settings.seed.wrapping_add(2)),
+            height: Fractal::new(settings.seed, settings.octaves, settings.frequency, settings.persistence, settings.lacunarity),
+            settings,
+            biomes,
+        })
+    }
+
+    pub fn settings(&self) -> &BiomeSettings {
+        &self.settings
+    }
+
+    /// (temperature, humidity) at a column
+    pub fn climate(&self, x: f32, z: f32) -> (f32, f32) {
+        (self.temperature.get2(x, z), self.humidity.get2(x, z))
+    }
+
+    pub fn biome_at(&self, x: f32, z: f32) -> &Biome {
+        self.column(x, z).0
+    }
+
+    pub fn height(&self, x: f32, z: f32) -> f32 {
+        self.column(x, z).1
+    }
+
+    /**
+     The nearest biome at a column, and the terrain height there. Each biome within `height_blend`
+     of the nearest climate weighs in linearly less the farther it is, so the height is continuous
+     wherever the climate is.
+     */
+    fn column(&self, x: f32, z: f32) -> (&Biome, f32) {
+        let (temperature, humidity) = self.climate(x, z);
+        let distances: Vec<f32> = self.biomes
+            .iter()
+            .map(|biome| biome.climate_distance_squared(temperature, humidity).sqrt())
+            .collect();
+        let (nearest, nearest_distance) = distances
+            .iter()
+            .copied()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .unwrap();
+
+        let noise = self.height.get2(x, z);
+        let blend = self.settings.height_blend;
+        let (mut sum, mut total) = (0.0, 0.0);
+        for (i, (biome, distance)) in self.biomes.iter().zip(&distances).enumerate() {
+            let weight = if blend > 0.0 {
+                (1.0 - (distance - nearest_distance) / blend).max(0.0)
+            } else if i == nearest {
+                1.0
+            } else {
+                0.0
+            };
+            sum += weight * biome.height(noise);
+            total += weight;
+        }
+        (&self.biomes[nearest], sum / total)
+    }
+
+    /// Whether vegetation grows on the column. Deterministic per column.
+    pub fn has_vegetation(&self, x: i32, z: i32) -> bool {
+        let biome = self.biome_at(x as f32, z as f32);
+        biome.vegetation.is_some() && self.vegetation_roll(x, z) < biome.definition.vegetation_density
+    }
+
+    /// Uniform in [0, 1] per column
+    fn vegetation_roll(&self, x: i32, z: i32) -> f32 {
+        let mut hash = (x as u32).wrapping_mul(0x27d4_eb2d) ^ (z as u32).wrapping_mul(0x1656_67b1) ^ self.settings.seed as u32;
+        hash ^= hash >> 15;
+        hash = hash.wrapping_mul(0x85eb_ca6b);
+        hash ^= hash >> 13;
+        hash as f32 / std::u32::MAX as f32
+    }
+
+    pub fn sample(&self, position: [f32; 3]) -> VoxelData {
+        let (biome, height) = self.column(position[0], position[2]);
+        let depth = height - position[1];
+        let voxel = biome.material_at_depth(depth);
+        if voxel.is_empty() {
+            if let Some(vegetation) = biome.vegetation {
+                let (x, z) = (position[0].floor() as i32, position[2].floor() as i32);
+                if -depth < SHRUB_HEIGHT && self.vegetation_roll(x, z) < biome.definition.vegetation_density {
+                    return vegetation;
+                }
+            }
+            return voxel;
+        }
+        voxel.with_density(density(depth, self.settings.voxel_size))
+    }
+
+    /// Biomes that may be within `margin` of the nearest climate somewhere over the XZ rectangle.
+    /// With no margin, those that may be the nearest one.
+    fn candidate_biomes(&self, min: [f32; 2], max: [f32; 2], margin: f32) -> Vec<&Biome> {
+        let center = [(min[0] + max[0]) * 0.5, (min[1] + max[1]) * 0.5];
+        let half_diagonal = ((max[0] - min[0]).powi(2) + (max[1] - min[1]).powi(2)).sqrt() * 0.5;
+        let (temperature, humidity) = self.climate(center[0], center[1]);
+        let temperature_slack = self.temperature.lipschitz2() * half_diagonal;
+        let humidity_slack = self.humidity.lipschitz2() * half_diagonal;
+        let climate_min = [temperature - temperature_slack, humidity - humidity_slack];
+        let climate_max = [temperature + temperature_slack, humidity + humidity_slack];
+
+        let point = |biome: &Biome| [biome.definition.temperature, biome.definition.humidity];
+        let nearest_distance = |p: [f32; 2]| (0..2)
+            .map(|i| (climate_min[i] - p[i]).max(p[i] - climate_max[i]).max(0.0).powi(2))
+            .sum::<f32>();
+        let farthest_distance = |p: [f32; 2]| (0..2)
+            .map(|i| (p[i] - climate_min[i]).abs().max((p[i] - climate_max[i]).abs()).powi(2))
+            .sum::<f32>();
+        // No point of the climate rectangle can be nearest to a biome that is farther away
+        // than another biome is at worst
+        let bound = self.biomes
+            .iter()
+            .map(|biome| farthest_distance(point(biome)))
+            .fold(std::f32::INFINITY, f32::min)
+            .sqrt() + margin;
+        self.biomes
+            .iter()
+            .filter(|biome| nearest_distance(point(biome)).sqrt() <= bound)
+            .collect()
+    }
+}
+
+impl Oracle for BiomeMap {
+    fn classify(&self, chunk: &ChunkCoordinates, bounds: &Bounds) -> Isosurface<VoxelData> {
+        let aabb = Aabb::from_bounds(chunk, bounds, self.settings.chunk_size);
+        if is_voxel_sized(&aabb, self.settings.voxel_size) {
+            return Isosurface::Uniform(self.sample(aabb.center()));
+        }
+
+        let min = [aabb.min[0], aabb.min[2]];
+        let max = [aabb.max[0], aabb.max[2]];
+        let center = [(min[0] + max[0]) * 0.5, (min[1] + max[1]) * 0.5];
+        let half_diagonal = ((max[0] - min[0]).powi(2) + (max[1] - min[1]).powi(2)).sqrt() * 0.5;
+        let noise = self.height.get2(center[0], center[1]);
+        let slack = self.height.lipschitz2() * half_diagonal;
+        let (noise_min, noise_max) = ((noise - slack).max(-1.0), (noise + slack).min(1.0));
+
+        // The blended height is a weighted average of the heights of the blended biomes
+        let mut min_height = std::f32::INFINITY;
+        let mut max_height = std::f32::NEG_INFINITY;
+        for biome in self.candidate_biomes(min, max, self.settings.height_blend) {
+            min_height = min_height.min(biome.height(noise_min).min(biome.height(noise_max)));
+            max_height = max_height.max(biome.height(noise_min).max(biome.height(noise_max)));
+        }
+        let candidates = self.candidate_biomes(min, max, 0.0);
+        if candidates.iter().any(|biome| biome.grows_vegetation()) {
+            max_height += SHRUB_HEIGHT;
+        }
+
+        if aabb.min[1] >= max_height {
+            return Isosurface::Uniform(VoxelData::EMPTY);
+        }
+        let min_depth = min_height - aabb.max[1];
+        if min_depth >= 0.0 {
+            // Below every layer of every biome around, the base material is all there is
+            let first = candidates[0].base;
+            let uniform = candidates
+                .iter()
+                .all(|biome| min_depth >= biome.layers_depth() && biome.base == first);
+            if uniform {
+                return Isosurface::Uniform(first);
+            }
+        }
+        Isosurface::Surface
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{Albedo, MaterialDefinition};
+
+    fn materials() -> MaterialRegistry {
+        let definitions = ["stone", "dirt", "sand"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| MaterialDefinition {
+                id: i as u16 + 1,
+                name: name.to_string(),
+                albedo: Albedo::Color([1.0, 1.0, 1.0, 1.0]),
+                roughness: 1.0,
+                metallic: 0.0,
+                transparent: false,
+                solid: true,
+            })
+            .collect();
+        MaterialRegistry::from_definitions(definitions, None).unwrap()
+    }
+
+    fn settings() -> BiomeSettings {
+        let biome = |name: &str, temperature, layer: &str| BiomeDefinition {
+            name: name.to_string(),
+            temperature,
+            humidity: 0.0,
+            base_height: 10.0,
+            amplitude: 5.0,
+            layers: vec![Layer { material: layer.to_string(), depth: 3.0 }],
+            base: "stone".to_string(),
+            vegetation_density: 0.5,
+            vegetation: None,
+        };
+        BiomeSettings {
+            seed: 5,
+            climate_frequency: 0.01,
+            octaves: 3,
+            frequency: 0.05,
+            persistence: 0.5,
+            lacunarity: 2.0,
+            chunk_size: 64.0,
+            voxel_size: 0.5,
+            height_blend: 0.2,
+            biomes: vec![biome("cold", -0.5, "dirt"), biome("hot", 0.5, "sand")],
+        }
+    }
+
+    #[test]
+    fn test_layers() {
+        let map = BiomeMap::new(settings(), &materials()).unwrap();
+        let biome = &map.biomes[1];
+        assert!(biome.material_at_depth(-1.0).is_empty());
+        assert_eq!(biome.material_at_depth(1.0).material(), 3);
+        assert_eq!(biome.material_at_depth(10.0).material(), 1);
+    }
+
+    #[test]
+    fn test_unknown_material() {
+        let mut settings = settings();
+        settings.biomes[0].base = "lava".to_string();
+        assert!(matches!(
+            BiomeMap::new(settings, &materials()),
+            Err(BiomeError::UnknownMaterial { .. })
+        ));
+    }
+
+    #[test]
+    fn test_candidates_are_conservative() {
+        let map = BiomeMap::new(settings(), &materials()).unwrap();
+        let (min, max) = ([-100.0, 40.0], [-36.0, 104.0]);
+        let candidates: Vec<&str> = map.candidate_biomes(min, max, 0.0)
+            .iter()
+            .map(|biome| biome.definition.name.as_str())
+            .collect();
+        for i in 0..=32 {
+            for j in 0..=32 {
+                let x = min[0] + (max[0] - min[0]) * i as f32 / 32.0;
+                let z = min[1] + (max[1] - min[1]) * j as f32 / 32.0;
+                assert!(candidates.contains(&map.biome_at(x, z).definition.name.as_str()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_height_is_blended_across_borders() {
+        let mut settings = settings();
+        settings.biomes[1].base_height = 30.0;
+        let map = BiomeMap::new(settings, &materials()).unwrap();
+        // Walk along x until the nearest biome changes, the height must not jump there
+        let step = 0.25;
+        let mut previous = (map.biome_at(0.0, 0.0).definition.name.clone(), map.height(0.0, 0.0));
+        let mut borders = 0;
+        for i in 1..8000 {
+            let x = i as f32 * step;
+            let biome = map.biome_at(x, 0.0).definition.name.clone();
+            let height = map.height(x, 0.0);
+            if biome != previous.0 {
+                borders += 1;
+                assert!((height - previous.1).abs() < 5.0, "height jumps from {} to {} at {}", previous.1, height, x);
+            }
+            previous = (biome, height);
+        }
+        assert!(borders > 0);
+    }
+
+    #[test]
+    fn test_classify_bounds_blended_heights() {
+        let mut settings = settings();
+        settings.biomes[1].base_height = 30.0;
+        let map = BiomeMap::new(settings, &materials()).unwrap();
+        let (min, max) = ([-100.0, 40.0], [-36.0, 104.0]);
+        let mut low = std::f32::INFINITY;
+        let mut high = std::f32::NEG_INFINITY;
+        for biome in map.candidate_biomes(min, max, map.settings.height_blend) {
+            low = low.min(biome.height(-1.0));
+            high = high.max(biome.height(1.0));
+        }
+        for i in 0..=32 {
+            for j in 0..=32 {
+                let x = min[0] + (max[0] - min[0]) * i as f32 / 32.0;
+                let z = min[1] + (max[1] - min[1]) * j as f32 / 32.0;
+                let height = map.height(x, z);
+                assert!(low <= height && height <= high);
+            }
+        }
+    }
+
+    #[test]
+    fn test_vegetation() {
+        let materials = materials();
+        let mut settings = settings();
+        for biome in &mut settings.biomes {
+            biome.vegetation = Some("dirt".to_string());
+            biome.vegetation_density = 1.0;
+        }
+        let map = BiomeMap::new(settings.clone(), &materials).unwrap();
+        let height = map.height(3.5, 7.5);
+        assert!(map.has_vegetation(3, 7));
+        assert_eq!(map.sample([3.5, height + 0.5, 7.5]).material(), 2);
+        assert!(map.sample([3.5, height + SHRUB_HEIGHT + 0.5, 7.5]).is_empty());
+
+        for biome in &mut settings.biomes {
+            biome.vegetation_density = 0.0;
+        }
+        let map = BiomeMap::new(settings.clone(), &materials).unwrap();
+        assert!(!map.has_vegetation(3, 7));
+        assert!(map.sample([3.5, height + 0.5, 7.5]).is_empty());
+
+        settings.biomes[0].vegetation = Some("moss".to_string());
+        assert!(matches!(BiomeMap::new(settings, &materials), Err(BiomeError::UnknownMaterial { .. })));
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use amethyst::core::math::{Point3, Vector3};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::octree::bounds::Bounds;
+use crate::octree::world::ChunkCoordinates;
+use crate::octree::world_builder::Isosurface;
+use crate::octree::VoxelData;
+use crate::util::aabb::Aabb;
+use crate::worldgen::noise::Perlin;
+use crate::worldgen::sdf::{Capsule, Sdf};
+use crate::worldgen::{is_voxel_sized, Oracle};
+
+// Branches of branches of branches stop here
+const MAX_BRANCH_DEPTH: u32 = 3;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaveSettings {
+    pub seed: u64,
+    /// Worm systems are seeded per square cell of this many world units on the XZ plane
+    pub cell_size: f32,
+    pub worms_per_cell: u32,
+    /// Worms start between these heights
+    pub min_y: f32,
+    pub max_y: f32,
+    pub min_radius: f32,
+    pub max_radius: f32,
+    /// Number of segments in a trunk. Branches get half as many as their parent.
+    pub steps: u32,
+    pub step_length: f32,
+    /// Maximum change of heading per step, in radians
+    pub turn_rate: f32,
+    /// Chance per step to fork a branch off the current worm
+    pub branch_chance: f32,
+    /// World units spanned by one chunk
+    pub chunk_size: f32,
+    /// Voxel size in world units, see `worldgen::is_voxel_sized`
+    pub voxel_size: f32,
+    /// Cells whose worms are kept in memory. The least recently used ones are dropped beyond this.
+    pub cached_cells: usize,
+}
+
+impl Default for CaveSettings {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            cell_size: 64.0,
+            worms_per_cell: 1,
+            min_y: 0.0,
+            max_y: 12.0,
+            min_radius: 1.0,
+            max_radius: 2.5,
+            steps: 48,
+            step_length: 1.5,
+            turn_rate: 0.6,
+            branch_chance: 0.04,
+            chunk_size: 64.0,
+            voxel_size: 0.5,
+            cached_cells: 256,
+        }
+    }
+}
+
+/**
+ Carves Perlin worm tunnels out of another oracle.
+ Every worm system is a tree of capsules grown from a single trunk, so its tunnels are connected.
+ Straight connectors join the trunks of a cell to its first one, and that one to the first trunks
+ of the neighbouring cells along +X and +Z, so all tunnels form one network.
+ Nothing steers worms towards the surface: the network is sealed unless a worm climbs out of the
+ ground or the base oracle is carved away above it.
+ Only nodes near a tunnel are subdivided; everything else is delegated to the base oracle.
+ */
+pub struct Caves<O> {
+    base: O,
+    settings: CaveSettings,
+    noise: Perlin,
+    cells: RwLock<HashMap<[i32; 2], CachedCell>>,
+    clock: AtomicU64,
+}
+
+struct CachedCell {
+    tunnels: Arc<Vec<Capsule>>,
+    last_used: AtomicU64,
+}
+
+impl<O: Oracle> Caves<O> {
+    pub fn new(base: O, settings: CaveSettings) -> Self {
+        Self {
+            base,
+            noise: Perlin::new(settings.seed),
+            settings,
+            cells: RwLock::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    pub fn settings(&self) -> &CaveSettings {
+        &self.settings
+    }
+
+    /// How far tunnels seeded in one cell can reach outside of it
+    fn reach(&self) -> f32 {
+        // Branch lengths halve every generation, so the whole tree is shorter than twice the trunk
+        let worms = 2.0 * self.settings.steps as f32 * self.settings.step_length;
+        // Connectors end in the next cell
+        worms.max(self.settings.cell_size) + self.settings.max_radius
+    }
+
+    /// Worms of all cells in the range, generating the missing ones.
+    /// Cached cells only take a shared lock, so concurrent classification doesn't serialize on hits.
+    fn cell_tunnels(&self, min_cell: [i32; 2], max_cell: [i32; 2]) -> Vec<Arc<Vec<Capsule>>> {
+        let cells_in_range = || (min_cell[0]..=max_cell[0])
+            .flat_map(move |x| (min_cell[1]..=max_cell[1]).map(move |z| [x, z]));
+        {
+            let cells = self.cells.read().unwrap();
+            let cached: Option<Vec<_>> = cells_in_range()
+                .map(|cell| cells.get(&cell).map(|cached| {
+                    cached.last_used.store(self.clock.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+                    cached.tunnels.clone()
+                }))
+                .collect();
+            if let Some(tunnels) = cached {
+                return tunnels;
+            }
+        }
+
+        let mut cells = self.cells.write().unwrap();
+        let tunnels: Vec<_> = cells_in_range()
+            .map(|cell| {
+                let cached = cells.entry(cell).or_insert_with(|| CachedCell {
+                    tunnels: Arc::new(self.generate_cell(cell)),
+                    last_used: AtomicU64::new(0),
+                });
+                cached.last_used.store(self.clock.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+                cached.tunnels.clone()
+            })
+            .collect();
+        // The cells just used are the most recent ones, so they survive unless the range alone is over the limit
+        let limit = self.settings.cached_cells.max(tunnels.len());
+        if cells.len() > limit {
+            let mut by_age: Vec<([i32; 2], u64)> = cells
+                .iter()
+                .map(|(cell, cached)| (*cell, cached.last_used.load(Ordering::Relaxed)))
+                .collect();
+            by_age.sort_by_key(|(_, last_used)| *last_used);
+            let excess = cells.len() - limit;
+            for (cell, _) in by_age.into_iter().take(excess) {
+                cells.remove(&cell);
+            }
+        }
+        tunnels
+    }
+
+    /// Cells whose worms are currently in memory
+    pub fn cached_cells(&self) -> usize {
+        self.cells.read().unwrap().len()
+    }
+
+    fn generate_cell(&self, cell: [i32; 2]) -> Vec<Capsule> {
+        let settings = &self.settings;
+        let mut rng = StdRng::seed_from_u64(cell_seed(settings.seed, cell));
+        let mut tunnels = Vec::new();
+        let mut starts = Vec::new();
+        for _ in 0..settings.worms_per_cell {
+            let start = self.worm_start(&mut rng, cell);
+            let yaw = rng.gen_range(0.0, std::f32::consts::PI * 2.0);
+            let radius = rng.gen_range(settings.min_radius, settings.max_radius.max(settings.min_radius + std::f32::EPSILON));
+            let head = WormHead { position: start, yaw, pitch: 0.0, radius };
+            self.dig(&mut rng, head, settings.steps, 0, &mut tunnels);
+            starts.push(start);
+        }
+
+        let first = match starts.first() {
+            Some(first) => *first,
+            None => return tunnels,
+        };
+        let neighbours = [[cell[0] + 1, cell[1]], [cell[0], cell[1] + 1]]
+            .iter()
+            .map(|neighbour| self.first_start(*neighbour))
+            .collect::<Vec<_>>();
+        for other in starts[1..].iter().chain(&neighbours) {
+            tunnels.push(Capsule { a: first, b: *other, radius: settings.min_radius });
+        }
+        tunnels
+    }
+
+    fn worm_start(&self, rng: &mut StdRng, cell: [i32; 2]) -> Point3<f32> {
+        let settings = &self.settings;
+        Point3::new(
+            (cell[0] as f32 + rng.gen::<f32>()) * settings.cell_size,
+            rng.gen_range(settings.min_y, settings.max_y.max(settings.min_y + std::f32::EPSILON)),
+            (cell[1] as f32 + rng.gen::<f32>()) * settings.cell_size,
+        )
+    }
+
+    /// Where the first trunk of a cell starts, without growing its worms
+    fn first_start(&self, cell: [i32; 2]) -> Point3<f32> {
+        let mut rng = StdRng::seed_from_u64(cell_seed(self.settings.seed, cell));
+        self.worm_start(&mut rng, cell)
+    }
+
+    fn dig(&self, rng: &mut StdRng, head: WormHead, steps: u32, depth: u32, tunnels: &mut Vec<Capsule>) {
+        let settings = &self.settings;
+        let WormHead { mut position, mut yaw, mut pitch, mut radius } = head;
+        for _ in 0..steps {
+            let direction = Vector3::new(yaw.cos() * pitch.cos(), pitch.sin(), yaw.sin() * pitch.cos());
+            let next = position + direction * settings.step_length;
+            tunnels.push(Capsule { a: position, b: next, radius });
+
+            let sample = position * 0.05;
+            yaw += self.noise.get3(sample.x, sample.y, sample.z) * settings.turn_rate;
+            pitch = (pitch * 0.75 + self.noise.get3(sample.z, sample.x, sample.y) * settings.turn_rate * 0.5)
+                .max(-std::f32::consts::FRAC_PI_4)
+                .min(std::f32::consts::FRAC_PI_4);
+            radius = (radius + rng.gen_range(-0.1, 0.1))
+                .max(settings.min_radius)
+                .min(settings.max_radius);
+
+            if depth < MAX_BRANCH_DEPTH && steps > 1 && rng.gen::<f32>() < settings.branch_chance {
+                let side = if rng.gen() { 1.0 } else { -1.0 };
+                let branch = WormHead {
+                    position: next,
+                    yaw: yaw + side * std::f32::consts::FRAC_PI_2,
+                    pitch,
+                    radius: radius * 0.8,
+                };
+                self.dig(rng, branch, steps / 2, depth + 1, tunnels);
+            }
+            position = next;
+        }
+    }
+
+    /// Tunnel segments that may come within the box
+    fn tunnels_near(&self, aabb: &Aabb) -> Vec<Capsule> {
+        let reach = self.reach();
+        let cell_size = self.settings.cell_size;
+        let min_cell = [
+            ((aabb.min[0] - reach) / cell_size).floor() as i32,
+            ((aabb.min[2] - reach) / cell_size).floor() as i32,
+        ];
+        let max_cell = [
+            ((aabb.max[0] + reach) / cell_size).floor() as i32,
+            ((aabb.max[2] + reach) / cell_size).floor() as i32,
+        ];
+        self.cell_tunnels(min_cell, max_cell)
+            .iter()
+            .flat_map(|cell| cell.iter())
+            .filter(|capsule| capsule_aabb(capsule).overlaps(aabb))
+            .copied()
+            .collect()
+    }
+
+    /// Distance to the nearest tunnel, negative inside
+    pub fn tunnel_distance(&self, p: &Point3<f32>) -> f32 {
+        let aabb = Aabb::new(p.coords.into(), p.coords.into());
+        self.tunnels_near(&aabb)
+            .iter()
+            .map(|capsule| capsule.distance(p))
+            .fold(std::f32::INFINITY, f32::min)
+    }
+}
+
+impl<O: Oracle> Oracle for Caves<O> {
+    fn classify(&self, chunk: &ChunkCoordinates, bounds: &Bounds) -> Isosurface<VoxelData> {
+        let aabb = Aabb::from_bounds(chunk, bounds, self.settings.chunk_size);
+        if is_voxel_sized(&aabb, self.settings.voxel_size) {
+            if self.tunnel_distance(&Point3::from(aabb.center())) < 0.0 {
+                return Isosurface::Uniform(VoxelData::EMPTY);
+            }
+            return self.base.classify(chunk, bounds);
+        }
+
+        let (min, max) = self.tunnels_near(&aabb)
+            .iter()
+            .map(|capsule| capsule.interval(&aabb))
+            .fold((std::f32::INFINITY, std::f32::INFINITY), |(a_min, a_max), (b_min, b_max)| {
+                (a_min.min(b_min), a_max.min(b_max))
+            });
+        if max < 0.0 {
+            return Isosurface::Uniform(VoxelData::EMPTY);
+        }
+        let base = self.base.classify(chunk, bounds);
+        if min > 0.0 {
+            return base;
+        }
+        match base {
+            Isosurface::Uniform(value) if value.is_empty() => Isosurface::Uniform(value),
+            _ => Isosurface::Surface,
+        }
+    }
+}
+
+struct WormHead {
+    position: Point3<f32>,
+    yaw: f32,
+    pitch: f32,
+    radius: f32,
+}
+
+fn capsule_aabb(capsule: &Capsule) -> Aabb {
+    let mut aabb = Aabb::new(capsule.a.coords.into(), capsule.a.coords.into());
+    for i in 0..3 {
+        aabb.min[i] = capsule.a[i].min(capsule.b[i]) - capsule.radius;
+        aabb.max[i] = capsule.a[i].max(capsule.b[i]) + capsule.radius;
+    }
+    aabb
+}
+
+/// SplitMix64 over the seed and cell, so neighbouring cells get unrelated worms
+fn cell_seed(seed: u64, cell: [i32; 2]) -> u64 {
+    let mut z = seed
+        ^ (cell[0] as u32 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ (cell[1] as u32 as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caves(settings: CaveSettings) -> Caves<fn(&ChunkCoordinates, &Bounds) -> Isosurface<VoxelData>> {
+        fn solid(_: &ChunkCoordinates, _: &Bounds) -> Isosurface<VoxelData> {
+            Isosurface::Uniform(VoxelData::solid(1))
+        }
+        Caves::new(solid, settings)
+    }
+
+    /// Number of groups of segments connected through shared endpoints
+    fn networks(tunnels: &[Capsule]) -> usize {
+        let mut parent: Vec<usize> = (0..tunnels.len()).collect();
+        fn find(parent: &mut Vec<usize>, i: usize) -> usize {
+            if parent[i] != i {
+                let root = find(parent, parent[i]);
+                parent[i] = root;
+            }
+            parent[i]
+        }
+        for i in 0..tunnels.len() {
+            for j in 0..i {
+                let (p, q) = (&tunnels[i], &tunnels[j]);
+                if p.a == q.a || p.a == q.b || p.b == q.a || p.b == q.b {
+                    let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+                    parent[a] = b;
+                }
+            }
+        }
+        let mut roots: Vec<usize> = (0..tunnels.len()).map(|i| find(&mut parent, i)).collect();
+        roots.sort();
+        roots.dedup();
+        roots.len()
+    }
+
+    #[test]
+    fn test_worm_systems_are_connected() {
+        let settings = CaveSettings { worms_per_cell: 3, branch_chance: 0.2, ..CaveSettings::default() };
+        let caves = caves(settings);
+        assert_eq!(networks(&caves.generate_cell([2, -1])), 1);
+
+        // Across a block of cells, each connected to its neighbours
+        let mut tunnels = Vec::new();
+        for x in 0..3 {
+            for z in -1..2 {
+                tunnels.extend(caves.generate_cell([x, z]));
+            }
+        }
+        assert_eq!(networks(&tunnels), 1);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let a = caves(CaveSettings::default());
+        let b = caves(CaveSettings::default());
+        assert_eq!(
+            a.generate_cell([0, 0]).iter().map(|c| c.b).collect::<Vec<_>>(),
+            b.generate_cell([0, 0]).iter().map(|c| c.b).collect::<Vec<_>>(),
+        );
+        let tunnel = a.generate_cell([0, 0])[10];
+        assert!(a.tunnel_distance(&tunnel.a) < 0.0);
+    }
+
+    #[test]
+    fn test_cell_cache_is_bounded() {
+        let settings = CaveSettings { cached_cells: 20, ..CaveSettings::default() };
+        let caves = caves(settings.clone());
+        let reach = (caves.reach() / settings.cell_size).ceil() as usize;
+        // Every lookup needs the cells within reach on both sides
+        let per_lookup = (2 * reach + 1) * (2 * reach + 1);
+        let limit = settings.cached_cells.max(per_lookup);
+
+        let tunnel = caves.generate_cell([0, 0])[10];
+        for i in 0..10 {
+            let p = Point3::new(i as f32 * 10.0 * settings.cell_size, 5.0, 0.0);
+            caves.tunnel_distance(&p);
+            assert!(caves.cached_cells() <= limit);
+        }
+        // Evicted cells are generated again, identically
+        assert!(caves.tunnel_distance(&tunnel.a) < 0.0);
+    }
+}
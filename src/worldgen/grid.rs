@@ -0,0 +1,111 @@
+use crate::octree::bounds::Bounds;
+use crate::octree::world::ChunkCoordinates;
+use crate::octree::world_builder::Isosurface;
+use crate::octree::{chunk_coordinates, chunk_position, Chunk, VoxelData};
+use crate::worldgen::{world_builder, Oracle};
+
+/// Dense cube of voxels filling one chunk. The side length is a power of two.
+#[derive(Clone, Debug)]
+pub struct VoxelGrid {
+    size: u32,
+    voxels: Vec<VoxelData>,
+}
+
+impl VoxelGrid {
+    /// Smallest grid holding `extent` voxels along every axis
+    pub fn with_extent(extent: u32) -> Self {
+        let size = extent.max(1).next_power_of_two();
+        Self {
+            size,
+            voxels: vec![VoxelData::EMPTY; (size * size * size) as usize],
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    #[inline]
+    fn index(&self, x: u32, y: u32, z: u32) -> usize {
+        debug_assert!(x < self.size && y < self.size && z < self.size);
+        ((z * self.size + y) * self.size + x) as usize
+    }
+
+    pub fn get(&self, x: u32, y: u32, z: u32) -> VoxelData {
+        self.voxels[self.index(x, y, z)]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, z: u32, value: VoxelData) {
+        let index = self.index(x, y, z);
+        self.voxels[index] = value;
+    }
+
+    /// Builds the chunk at `chunk`, merging uniform regions into the largest possible leaves.
+    pub fn build_chunk(self, chunk: [i32; 3]) -> Chunk {
+        let oracle = GridOracle::new(self, chunk);
+        world_builder(&oracle).build(&chunk_coordinates(chunk))
+    }
+}
+
+/**
+ `WorldBuilder` oracle over a `VoxelGrid`.
+ Keeps a pyramid of the grid where each cell is `Some` if the block of voxels below it is uniform,
+ so every octree node is classified with a single lookup.
+ */
+pub struct GridOracle {
+    chunk: [i32; 3],
+    /// Level `l` has `(size >> l)^3` cells, each covering `2^l` voxels along every axis
+    levels: Vec<Vec<Option<VoxelData>>>,
+    size: u32,
+}
+
+impl GridOracle {
+    pub fn new(grid: VoxelGrid, chunk: [i32; 3]) -> Self {
+        let size = grid.size;
+        let mut levels: Vec<Vec<Option<VoxelData>>> = vec![grid.voxels.into_iter().map(Some).collect()];
+        let mut level_size = size;
+        while level_size > 1 {
+            let previous = levels.last().unwrap();
+            let next_size = level_size / 2;
+            let mut next = Vec::with_capacity((next_size * next_size * next_size) as usize);
+            for z in 0..next_size {
+                for y in 0..next_size {
+                    for x in 0..next_size {
+                        let first = previous[((z * 2 * level_size + y * 2) * level_size + x * 2) as usize];
+                        let uniform = (0..8).all(|i| {
+                            let (dx, dy, dz) = (i & 1, (i >> 1) & 1, i >> 2);
+                            let index = (((z * 2 + dz) * level_size + y * 2 + dy) * level_size + x * 2 + dx) as usize;
+                            first.is_some() && previous[index] == first
+                        });
+                        next.push(if uniform { first } else { None });
+                    }
+                }
+            }
+            levels.push(next);
+            level_size = next_size;
+        }
+        Self { chunk, levels, size }
+    }
+}
+
+impl Oracle for GridOracle {
+    fn classify(&self, chunk: &ChunkCoordinates, bounds: &Bounds) -> Isosurface<VoxelData> {
+        if chunk_position(chunk) != self.chunk {
+            return Isosurface::Uniform(VoxelData::EMPTY);
+        }
+        let position: [f32; 3] = bounds.get_position().into();
+        let cells = bounds.get_width() * self.size as f32;
+        let level = if cells <= 1.0 {
+            0
+        } else {
+            (cells.log2().round() as usize).min(self.levels.len() - 1)
+        };
+        let level_size = self.size >> level;
+        let cell = |p: f32| ((p * level_size as f32) as u32).min(level_size - 1);
+        let (x, y, z) = (cell(position[0]), cell(position[1]), cell(position[2]));
+        match self.levels[level][((z * level_size + y) * level_size + x) as usize] {
+            Some(value) => Isosurface::Uniform(value),
+            None => Isosurface::Surface,
+        }
+    }
+}
@@ -0,0 +1,564 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+use crate::material::MaterialRegistry;
+use crate::octree::bounds::Bounds;
+use crate::octree::world::ChunkCoordinates;
+use crate::octree::world_builder::Isosurface;
+use crate::octree::VoxelData;
+use crate::util::aabb::Aabb;
+use crate::worldgen::{density, is_voxel_sized, Oracle};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum HeightmapSource {
+    /// 8 or 16 bit grayscale image in any format the `image` crate reads
+    Image(PathBuf),
+    /// Headerless little endian 16 bit samples, row by row
+    Raw { path: PathBuf, width: u32, height: u32 },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HeightmapSettings {
+    pub source: HeightmapSource,
+    /// Image of the same size as the heightmap whose red, green, blue and alpha channels weigh
+    /// the materials of `splat_materials` in that order. The heaviest one is the surface material,
+    /// and `surface_material` where all of them weigh nothing. Alpha is only read from images
+    /// that have an alpha channel.
+    #[serde(default)]
+    pub splat_map: Option<PathBuf>,
+    #[serde(default)]
+    pub splat_materials: Vec<String>,
+    /// Material name for the surface where there is no splat map
+    pub surface_material: String,
+    pub surface_depth: f32,
+    pub base_material: String,
+    /// World units between two pixels
+    pub horizontal_scale: f32,
+    /// World units between black and white
+    pub vertical_scale: f32,
+    /// World height of black
+    #[serde(default)]
+    pub vertical_offset: f32,
+    /// Repeat the map over every chunk. Otherwise the map covers chunks from (0, 0) on and
+    /// everything outside of it is empty.
+    #[serde(default)]
+    pub tiled: bool,
+    /// World units spanned by one chunk
+    pub chunk_size: f32,
+    /// Voxel size in world units, see `worldgen::is_voxel_sized`
+    pub voxel_size: f32,
+}
+
+#[derive(Debug)]
+pub enum HeightmapError {
+    Io(PathBuf, std::io::Error),
+    Image(PathBuf, image::ImageError),
+    RawSize { expected: usize, actual: usize },
+    /// A map without a single sample has no height to tile or sample
+    Empty { width: u32, height: u32 },
+    SampleCount { expected: usize, actual: usize },
+    SplatSize { expected: (u32, u32), actual: (u32, u32) },
+    TooManySplatMaterials(usize),
+    UnknownMaterial(String),
+}
+
+impl fmt::Display for HeightmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeightmapError::Io(path, err) => write!(f, "failed to read {}: {}", path.display(), err),
+            HeightmapError::Image(path, err) => write!(f, "failed to decode {}: {}", path.display(), err),
+            HeightmapError::RawSize { expected, actual } => {
+                write!(f, "raw heightmap should be {} bytes but is {}", expected, actual)
+            }
+            HeightmapError::Empty { width, height } => write!(f, "heightmap is empty ({}x{})", width, height),
+            HeightmapError::SampleCount { expected, actual } => {
+                write!(f, "heightmap should have {} samples but has {}", expected, actual)
+            }
+            HeightmapError::SplatSize { expected, actual } => {
+                write!(f, "splat map is {:?} but the heightmap is {:?}", actual, expected)
+            }
+            HeightmapError::TooManySplatMaterials(count) => {
+                write!(f, "a splat map has 4 channels but {} materials were given", count)
+            }
+            HeightmapError::UnknownMaterial(name) => write!(f, "material {} is not in the registry", name),
+        }
+    }
+}
+
+impl std::error::Error for HeightmapError {}
+
+/// Min/max of the samples over power of two blocks, for conservative range queries.
+struct MinMaxPyramid {
+    /// (width, height, (min, max) per block), level `l` has blocks of `2^l` pixels
+    levels: Vec<(u32, u32, Vec<(u16, u16)>)>,
+}
+
+impl MinMaxPyramid {
+    fn new(width: u32, height: u32, samples: &[u16]) -> Self {
+        let mut levels = vec![(width, height, samples.iter().map(|s| (*s, *s)).collect::<Vec<_>>())];
+        loop {
+            let (w, h, blocks) = levels.last().unwrap();
+            if *w <= 1 && *h <= 1 {
+                break;
+            }
+            let (next_w, next_h) = ((w + 1) / 2, (h + 1) / 2);
+            let mut next = Vec::with_capacity((next_w * next_h) as usize);
+            for y in 0..next_h {
+                for x in 0..next_w {
+                    let mut range = (std::u16::MAX, std::u16::MIN);
+                    for (dx, dy) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let (sx, sy) = (x * 2 + dx, y * 2 + dy);
+                        if sx < *w && sy < *h {
+                            let block = blocks[(sy * w + sx) as usize];
+                            range = (range.0.min(block.0), range.1.max(block.1));
+                        }
+                    }
+                    next.push(range);
+                }
+            }
+            levels.push((next_w, next_h, next));
+        }
+        Self { levels }
+    }
+
+    /// Range over the inclusive pixel rectangle, which must lie within the map
+    fn range(&self, min: [u32; 2], max: [u32; 2]) -> (u16, u16) {
+        let span = (max[0] - min[0]).max(max[1] - min[1]) + 1;
+        // At this level the rectangle touches at most 2x2 blocks
+        let level = (32 - (span - 1).leading_zeros() as usize).min(self.levels.len() - 1);
+        let (w, _, blocks) = &self.levels[level];
+        let mut range = (std::u16::MAX, std::u16::MIN);
+        for y in (min[1] >> level)..=(max[1] >> level) {
+            for x in (min[0] >> level)..=(max[0] >> level) {
+                let block = blocks[(y * w + x) as usize];
+                range = (range.0.min(block.0), range.1.max(block.1));
+            }
+        }
+        range
+    }
+}
+
+/**
+ Terrain from a heightmap painted in an external tool, with an optional splat map for
+ the surface material. Heights are bilinearly interpolated between pixels.
+ */
+pub struct Heightmap {
+    settings: HeightmapSettings,
+    width: u32,
+    height: u32,
+    samples: Vec<u16>,
+    pyramid: MinMaxPyramid,
+    /// Surface material per pixel, if there is a splat map
+    splat: Option<Vec<VoxelData>>,
+    surface: VoxelData,
+    base: VoxelData,
+}
+
+impl Heightmap {
+    pub fn load(settings: HeightmapSettings, materials: &MaterialRegistry) -> Result<Self, HeightmapError> {
+        let (width, height, samples) = match &settings.source {
+            HeightmapSource::Image(path) => {
+                let image = image::open(path).map_err(|err| HeightmapError::Image(path.clone(), err))?;
+                match image {
+                    DynamicImage::ImageLuma16(buffer) => (buffer.width(), buffer.height(), buffer.into_raw()),
+                    image => {
+                        let buffer = image.to_luma();
+                        let samples = buffer.pixels().map(|p| p.0[0] as u16 * 257).collect();
+                        (buffer.width(), buffer.height(), samples)
+                    }
+                }
+            }
+            HeightmapSource::Raw { path, width, height } => {
+                let bytes = fs::read(path).map_err(|err| HeightmapError::Io(path.clone(), err))?;
+                let expected = *width as usize * *height as usize * 2;
+                if bytes.len() != expected {
+                    return Err(HeightmapError::RawSize { expected, actual: bytes.len() });
+                }
+                let samples = bytes.chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect();
+                (*width, *height, samples)
+            }
+        };
+
+        let resolve = |name: &str| materials
+            .get_by_name(name)
+            .map(|material| materials.voxel(material.id))
+            .ok_or_else(|| HeightmapError::UnknownMaterial(name.to_string()));
+        let splat = match &settings.splat_map {
+            None => None,
+            Some(path) => {
+                if settings.splat_materials.len() > 4 {
+                    return Err(HeightmapError::TooManySplatMaterials(settings.splat_materials.len()));
+                }
+                let palette = settings.splat_materials
+                    .iter()
+                    .map(|name| resolve(name))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let image = image::open(path).map_err(|err| HeightmapError::Image(path.clone(), err))?;
+                // Opaque images would otherwise weigh the fourth material fully everywhere
+                let channels = if image.color().has_alpha() { 4 } else { 3 };
+                let image = image.to_rgba();
+                if image.dimensions() != (width, height) {
+                    return Err(HeightmapError::SplatSize { expected: (width, height), actual: image.dimensions() });
+                }
+                let fallback = resolve(&settings.surface_material)?;
+                let palette = &palette[..palette.len().min(channels)];
+                Some(image.pixels().map(|pixel| splat_material(pixel.0, palette, fallback)).collect())
+            }
+        };
+        let surface = resolve(&settings.surface_material)?;
+        let base = resolve(&settings.base_material)?;
+        let mut heightmap = Self::from_samples(settings, width, height, samples, surface, base)?;
+        heightmap.splat = splat;
+        Ok(heightmap)
+    }
+
+    /// Builds a heightmap from samples already in memory, row by row.
+    pub fn from_samples(
+        settings: HeightmapSettings,
+        width: u32,
+        height: u32,
+        samples: Vec<u16>,
+        surface: VoxelData,
+        base: VoxelData,
+    ) -> Result<Self, HeightmapError> {
+        if width == 0 || height == 0 {
+            return Err(HeightmapError::Empty { width, height });
+        }
+        let expected = width as usize * height as usize;
+        if samples.len() != expected {
+            return Err(HeightmapError::SampleCount { expected, actual: samples.len() });
+        }
+        let pyramid = MinMaxPyramid::new(width, height, &samples);
+        Ok(Self { settings, width, height, samples, pyramid, splat: None, surface, base })
+    }
+
+    pub fn settings(&self) -> &HeightmapSettings {
+        &self.settings
+    }
+
+    fn to_world_height(&self, sample: f32) -> f32 {
+        self.settings.vertical_offset + sample / std::u16::MAX as f32 * self.settings.vertical_scale
+    }
+
+    /// Maps a pixel coordinate onto the map, or `None` outside of an untiled map
+    fn pixel(&self, x: i64, z: i64) -> Option<(u32, u32)> {
+        let (w, h) = (self.width as i64, self.height as i64);
+        if self.settings.tiled {
+            Some((x.rem_euclid(w) as u32, z.rem_euclid(h) as u32))
+        } else if x >= 0 && z >= 0 && x < w && z < h {
+            Some((x as u32, z as u32))
+        } else {
+            None
+        }
+    }
+
+    fn sample_pixel(&self, x: i64, z: i64) -> Option<u16> {
+        self.pixel(x, z).map(|(x, z)| self.samples[(z * self.width + x) as usize])
+    }
+
+    /// Interpolated height of a column, or `None` outside of an untiled map
+    pub fn height(&self, x: f32, z: f32) -> Option<f32> {
+        let (px, pz) = (x / self.settings.horizontal_scale, z / self.settings.horizontal_scale);
+        let (x0, z0) = (px.floor() as i64, pz.floor() as i64);
+        let (tx, tz) = (px - px.floor(), pz - pz.floor());
+        let h00 = self.sample_pixel(x0, z0)?;
+        // Fall back to the nearest pixel along the far edge of an untiled map
+        let h10 = self.sample_pixel(x0 + 1, z0).unwrap_or(h00);
+        let h01 = self.sample_pixel(x0, z0 + 1).unwrap_or(h00);
+        // Past the last row the far corner repeats the row above it, past the last column
+        // the column before it, and past both the corner pixel itself
+        let h11 = match (self.sample_pixel(x0 + 1, z0 + 1), self.pixel(x0 + 1, z0)) {
+            (Some(h11), _) => h11,
+            (None, Some(_)) => h10,
+            (None, None) => h01,
+        };
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let sample = lerp(
+            lerp(h00 as f32, h10 as f32, tx),
+            lerp(h01 as f32, h11 as f32, tx),
+            tz,
+        );
+        Some(self.to_world_height(sample))
+    }
+
+    pub fn surface_material(&self, x: f32, z: f32) -> VoxelData {
+        let px = (x / self.settings.horizontal_scale).round() as i64;
+        let pz = (z / self.settings.horizontal_scale).round() as i64;
+        match (&self.splat, self.pixel(px, pz)) {
+            (Some(splat), Some((px, pz))) => splat[(pz * self.width + px) as usize],
+            _ => self.surface,
+        }
+    }
+
+    /**
+     Conservative (min, max) height over the XZ rectangle, and whether the map covers all of it.
+     `None` if the rectangle lies entirely outside of an untiled map.
+     */
+    pub fn height_range(&self, min: [f32; 2], max: [f32; 2]) -> Option<(f32, f32, bool)> {
+        let scale = self.settings.horizontal_scale;
+        // Interpolation reads one pixel past the floor
+        let pixel_min = [(min[0] / scale).floor() as i64, (min[1] / scale).floor() as i64];
+        let pixel_max = [(max[0] / scale).floor() as i64 + 1, (max[1] / scale).floor() as i64 + 1];
+        let (w, h) = (self.width as i64, self.height as i64);
+
+        let mut pieces: Vec<([u32; 2], [u32; 2])> = Vec::new();
+        let mut complete = true;
+        if self.settings.tiled {
+            if pixel_max[0] - pixel_min[0] + 1 >= w || pixel_max[1] - pixel_min[1] + 1 >= h {
+                pieces.push(([0, 0], [self.width - 1, self.height - 1]));
+            } else {
+                // Split the rectangle where it wraps around
+                let split = |lo: i64, hi: i64, size: i64| {
+                    let (lo, hi) = (lo.rem_euclid(size), hi.rem_euclid(size));
+                    if lo <= hi {
+                        vec![(lo as u32, hi as u32)]
+                    } else {
+                        vec![(lo as u32, (size - 1) as u32), (0, hi as u32)]
+                    }
+                };
+                for (x0, x1) in split(pixel_min[0], pixel_max[0], w) {
+                    for (z0, z1) in split(pixel_min[1], pixel_max[1], h) {
+                        pieces.push(([x0, z0], [x1, z1]));
+                    }
+                }
+            }
+        } else {
+            let clamped_min = [pixel_min[0].max(0), pixel_min[1].max(0)];
+            let clamped_max = [pixel_max[0].min(w - 1), pixel_max[1].min(h - 1)];
+            if clamped_min[0] > clamped_max[0] || clamped_min[1] > clamped_max[1] {
+                return None;
+            }
+            // The far edge pixel is only read for interpolation, and falls back to the edge
+            complete = pixel_min[0] >= 0 && pixel_min[1] >= 0 && pixel_max[0] - 1 < w && pixel_max[1] - 1 < h;
+            pieces.push((
+                [clamped_min[0] as u32, clamped_min[1] as u32],
+                [clamped_max[0] as u32, clamped_max[1] as u32],
+            ));
+        }
+
+        let (low, high) = pieces
+            .iter()
+            .map(|(min, max)| self.pyramid.range(*min, *max))
+            .fold((std::u16::MAX, std::u16::MIN), |a, b| (a.0.min(b.0), a.1.max(b.1)));
+        Some((self.to_world_height(low as f32), self.to_world_height(high as f32), complete))
+    }
+
+    pub fn sample(&self, position: [f32; 3]) -> VoxelData {
+        let height = match self.height(position[0], position[2]) {
+            Some(height) => height,
+            None => return VoxelData::EMPTY,
+        };
+        let depth = height - position[1];
+        if depth < 0.0 {
+            return VoxelData::EMPTY;
+        }
+        let voxel = if depth < self.settings.surface_depth {
+            self.surface_material(position[0], position[2])
+        } else {
+            self.base
+        };
+        voxel.with_density(density(depth, self.settings.voxel_size))
+    }
+}
+
+/// The material weighed most by the channels of a splat pixel, `fallback` if none weighs anything
+fn splat_material(weights: [u8; 4], palette: &[VoxelData], fallback: VoxelData) -> VoxelData {
+    let mut best = (0, fallback);
+    for (weight, material) in weights.iter().zip(palette) {
+        if *weight > best.0 {
+            best = (*weight, *material);
+        }
+    }
+    best.1
+}
+
+impl Oracle for Heightmap {
+    fn classify(&self, chunk: &ChunkCoordinates, bounds: &Bounds) -> Isosurface<VoxelData> {
+        let aabb = Aabb::from_bounds(chunk, bounds, self.settings.chunk_size);
+        if is_voxel_sized(&aabb, self.settings.voxel_size) {
+            return Isosurface::Uniform(self.sample(aabb.center()));
+        }
+        let (min_height, max_height, complete) = match self.height_range([aabb.min[0], aabb.min[2]], [aabb.max[0], aabb.max[2]]) {
+            Some(range) => range,
+            None => return Isosurface::Uniform(VoxelData::EMPTY),
+        };
+        if aabb.min[1] >= max_height {
+            return Isosurface::Uniform(VoxelData::EMPTY);
+        }
+        if complete && min_height - aabb.max[1] >= self.settings.surface_depth {
+            return Isosurface::Uniform(self.base);
+        }
+        Isosurface::Surface
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{Albedo, MaterialDefinition};
+
+    fn materials() -> MaterialRegistry {
+        let definitions = ["stone", "dirt", "sand", "snow", "grass"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| MaterialDefinition {
+                id: i as u16 + 1,
+                name: name.to_string(),
+                albedo: Albedo::Color([1.0, 1.0, 1.0, 1.0]),
+                roughness: 1.0,
+                metallic: 0.0,
+                transparent: false,
+                solid: true,
+            })
+            .collect();
+        MaterialRegistry::from_definitions(definitions, None).unwrap()
+    }
+
+    /// One world unit per pixel, and heights equal to the samples
+    fn settings(source: HeightmapSource) -> HeightmapSettings {
+        HeightmapSettings {
+            source,
+            splat_map: None,
+            splat_materials: Vec::new(),
+            surface_material: "grass".to_string(),
+            surface_depth: 1.0,
+            base_material: "stone".to_string(),
+            horizontal_scale: 1.0,
+            vertical_scale: std::u16::MAX as f32,
+            vertical_offset: 0.0,
+            tiled: false,
+            chunk_size: 64.0,
+            voxel_size: 0.5,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gog-heightmap-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn raw_file(dir: &std::path::Path, samples: &[u16]) -> PathBuf {
+        let path = dir.join("height.raw");
+        let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes().to_vec()).collect();
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_height_at_edges() {
+        let source = HeightmapSource::Raw { path: PathBuf::new(), width: 2, height: 2 };
+        let stone = VoxelData::solid(1);
+        let map = Heightmap::from_samples(settings(source), 2, 2, vec![0, 100, 200, 300], stone, stone).unwrap();
+        let height = |x, z| map.height(x, z).map(|height| height.round());
+        assert_eq!(height(0.5, 0.5), Some(150.0));
+        // Along the last row and column the missing far pixels repeat the edge
+        assert_eq!(height(0.5, 1.5), Some(250.0));
+        assert_eq!(height(0.5, 1.9), Some(250.0));
+        assert_eq!(height(1.5, 0.5), Some(200.0));
+        assert_eq!(height(1.5, 1.5), Some(300.0));
+        assert_eq!(map.height(2.0, 0.0), None);
+        assert_eq!(map.height(-0.1, 0.0), None);
+    }
+
+    #[test]
+    fn test_raw_loader() {
+        let dir = temp_dir("raw");
+        let samples = [0, 1000, 2000, 3000, 4000, 5000];
+        let path = raw_file(&dir, &samples);
+        let source = HeightmapSource::Raw { path: path.clone(), width: 3, height: 2 };
+        let map = Heightmap::load(settings(source), &materials()).unwrap();
+        assert_eq!(map.height(2.0, 1.0).map(f32::round), Some(5000.0));
+        assert_eq!(map.height(1.0, 0.0).map(f32::round), Some(1000.0));
+
+        let source = HeightmapSource::Raw { path, width: 4, height: 2 };
+        assert!(matches!(
+            Heightmap::load(settings(source), &materials()),
+            Err(HeightmapError::RawSize { expected: 16, actual: 12 })
+        ));
+
+        // An empty file matches an empty map, which would have nothing to tile
+        let empty = raw_file(&dir, &[]);
+        let mut settings = settings(HeightmapSource::Raw { path: empty, width: 0, height: 3 });
+        settings.tiled = true;
+        assert!(matches!(
+            Heightmap::load(settings, &materials()),
+            Err(HeightmapError::Empty { width: 0, height: 3 })
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_splat_map() {
+        let dir = temp_dir("splat");
+        let raw = raw_file(&dir, &[0; 4]);
+        let mut settings = settings(HeightmapSource::Raw { path: raw, width: 2, height: 2 });
+        settings.splat_materials = ["dirt", "sand", "snow", "stone"].iter().map(|name| name.to_string()).collect();
+        let materials = materials();
+        let material = |map: &Heightmap, x: f32, z: f32| map.surface_material(x, z).material();
+
+        // Without alpha, an opaque image never picks the fourth material
+        let rgb = dir.join("splat.png");
+        image::RgbImage::from_raw(2, 2, vec![255, 0, 0, 0, 200, 0, 0, 0, 0, 10, 20, 30]).unwrap().save(&rgb).unwrap();
+        settings.splat_map = Some(rgb);
+        let map = Heightmap::load(settings.clone(), &materials).unwrap();
+        assert_eq!(material(&map, 0.0, 0.0), 2);
+        assert_eq!(material(&map, 1.0, 0.0), 3);
+        // All channels zero falls back to the surface material
+        assert_eq!(material(&map, 0.0, 1.0), 5);
+        assert_eq!(material(&map, 1.0, 1.0), 4);
+
+        // With alpha, the alpha channel weighs the fourth material
+        let rgba = dir.join("splat_alpha.png");
+        image::RgbaImage::from_raw(2, 2, vec![255, 0, 0, 0, 0, 0, 0, 200, 10, 0, 0, 255, 0, 0, 0, 0])
+            .unwrap()
+            .save(&rgba)
+            .unwrap();
+        settings.splat_map = Some(rgba.clone());
+        let map = Heightmap::load(settings.clone(), &materials).unwrap();
+        assert_eq!(material(&map, 0.0, 0.0), 2);
+        assert_eq!(material(&map, 1.0, 0.0), 1);
+        assert_eq!(material(&map, 0.0, 1.0), 1);
+        assert_eq!(material(&map, 1.0, 1.0), 5);
+
+        // Channels without a material don't count
+        settings.splat_materials.truncate(1);
+        let map = Heightmap::load(settings.clone(), &materials).unwrap();
+        assert_eq!(material(&map, 0.0, 0.0), 2);
+        assert_eq!(material(&map, 1.0, 0.0), 5);
+        assert_eq!(material(&map, 0.0, 1.0), 2);
+
+        settings.splat_map = Some(dir.join("missing.png"));
+        assert!(matches!(Heightmap::load(settings, &materials), Err(HeightmapError::Image(..))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_pyramid_range() {
+        let (width, height) = (13, 7);
+        let samples: Vec<u16> = (0..width * height).map(|i| ((i * 7919) % 1000) as u16).collect();
+        let pyramid = MinMaxPyramid::new(width, height, &samples);
+        for x0 in 0..width {
+            for x1 in x0..width {
+                for z0 in 0..height {
+                    for z1 in z0..height {
+                        let mut expected = (std::u16::MAX, std::u16::MIN);
+                        for z in z0..=z1 {
+                            for x in x0..=x1 {
+                                let s = samples[(z * width + x) as usize];
+                                expected = (expected.0.min(s), expected.1.max(s));
+                            }
+                        }
+                        let actual = pyramid.range([x0, z0], [x1, z1]);
+                        assert!(actual.0 <= expected.0 && actual.1 >= expected.1);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use crate::octree::bounds::Bounds;
+use crate::octree::world::ChunkCoordinates;
+use crate::octree::world_builder::Isosurface;
+use crate::octree::{VoxelData, WorldBuilder};
+use crate::util::aabb::Aabb;
+
+pub mod biome;
+pub mod caves;
+pub mod grid;
+pub mod heightmap;
+pub mod noise;
+pub mod sdf;
+pub mod terrain;
+
+/**
+ Classifies octree nodes for `WorldBuilder`.
+ Returning `Isosurface::Uniform` must be conservative: the whole `Bounds` really has that value.
+ Anything uncertain should be `Isosurface::Surface` so the builder subdivides further.
+ */
+pub trait Oracle {
+    fn classify(&self, chunk: &ChunkCoordinates, bounds: &Bounds) -> Isosurface<VoxelData>;
+}
+
+impl<F> Oracle for F
+    where F: Fn(&ChunkCoordinates, &Bounds) -> Isosurface<VoxelData> {
+    fn classify(&self, chunk: &ChunkCoordinates, bounds: &Bounds) -> Isosurface<VoxelData> {
+        self(chunk, bounds)
+    }
+}
+
+/**
+ Whether a node is no larger than a voxel. Oracles classify such nodes by sampling their center
+ instead of subdividing further, which bounds the depth of the generated trees.
+ */
+pub fn is_voxel_sized(aabb: &Aabb, voxel_size: f32) -> bool {
+    aabb.size()[0] <= voxel_size
+}
+
+/// Density of a voxel `depth` below the surface, saturating one voxel deep
+pub fn density(depth: f32, voxel_size: f32) -> i8 {
+    (depth / voxel_size * std::i8::MAX as f32).min(std::i8::MAX as f32) as i8
+}
+
+pub fn world_builder<'a, O: Oracle + ?Sized>(
+    oracle: &'a O,
+) -> WorldBuilder<impl Fn(&ChunkCoordinates, &Bounds) -> Isosurface<VoxelData> + 'a> {
+    WorldBuilder::new(move |chunk: &ChunkCoordinates, bounds: &Bounds| oracle.classify(chunk, bounds))
+}
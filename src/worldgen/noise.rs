@@ -0,0 +1,239 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+/**
+ Upper bounds on the gradient magnitude of `Perlin::get2` and `Perlin::get3`, per unit of input.
+ Used to derive conservative min/max bounds for a region from a single sample.
+
+ Inside a lattice cell the noise is a multilinear blend of corner values `g = G·d`, where `d` is
+ the offset from the corner, with weights `fade(t)`. Along x, each blend `lerp(u, g0, g1)` has
+ derivative `fade'(x) (g1 - g0) + (1 - u) ∂g0/∂x + u ∂g1/∂x`, and the outer blends only take convex
+ combinations of those, so one axis is bounded by `max fade' * max |g1 - g0| + max |∂g/∂x|`.
+ `fade'(t) = 30 t² (1 - t)²` peaks at 30/16 = 1.875.
+
+ - 2D: gradients are the diagonals `(±1, ±1) / √2`. The offsets of neighbours along x differ by one
+   in x, so `|g1 - g0| <= (1 + 2 |y|) / √2 <= 3 / √2` and `|∂g/∂x| <= 1 / √2`. One axis is bounded by
+   `(1.875 * 3 + 1) / √2`, and both together by √2 times that: 6.625.
+ - 3D: gradients have two components of ±1 and one zero, so `|g| <= 2` and `|g1 - g0| <= 4`, with
+   `|∂g/∂x| <= 1`. One axis is bounded by `1.875 * 4 + 1 = 8.5`, all three by `8.5 * √3 < 14.73`.
+ */
+pub const PERLIN_LIPSCHITZ_2D: f32 = 6.625;
+pub const PERLIN_LIPSCHITZ_3D: f32 = 14.73;
+
+/// Ken Perlin's improved gradient noise, with a permutation table shuffled from a seed.
+#[derive(Clone)]
+pub struct Perlin {
+    permutation: [u8; 512],
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Self {
+        let mut table: Vec<u8> = (0..=255).collect();
+        table.shuffle(&mut StdRng::seed_from_u64(seed));
+        let mut permutation = [0; 512];
+        for i in 0..512 {
+            permutation[i] = table[i & 255];
+        }
+        Self { permutation }
+    }
+
+    #[inline]
+    fn hash(&self, x: i32, y: i32, z: i32) -> u8 {
+        let p = &self.permutation;
+        let x = (x & 255) as usize;
+        let y = (y & 255) as usize;
+        let z = (z & 255) as usize;
+        p[p[p[x] as usize + y] as usize + z]
+    }
+
+    /// 2D noise in roughly [-1, 1]
+    pub fn get2(&self, x: f32, y: f32) -> f32 {
+        let (xi, yi) = (x.floor() as i32, y.floor() as i32);
+        let (xf, yf) = (x - x.floor(), y - y.floor());
+        let (u, v) = (fade(xf), fade(yf));
+
+        let g00 = grad2(self.hash(xi, yi, 0), xf, yf);
+        let g10 = grad2(self.hash(xi + 1, yi, 0), xf - 1.0, yf);
+        let g01 = grad2(self.hash(xi, yi + 1, 0), xf, yf - 1.0);
+        let g11 = grad2(self.hash(xi + 1, yi + 1, 0), xf - 1.0, yf - 1.0);
+
+        lerp(v, lerp(u, g00, g10), lerp(u, g01, g11))
+    }
+
+    /// 3D noise in roughly [-1, 1]
+    pub fn get3(&self, x: f32, y: f32, z: f32) -> f32 {
+        let (xi, yi, zi) = (x.floor() as i32, y.floor() as i32, z.floor() as i32);
+        let (xf, yf, zf) = (x - x.floor(), y - y.floor(), z - z.floor());
+        let (u, v, w) = (fade(xf), fade(yf), fade(zf));
+
+        let corner = |dx: i32, dy: i32, dz: i32| {
+            grad3(
+                self.hash(xi + dx, yi + dy, zi + dz),
+                xf - dx as f32,
+                yf - dy as f32,
+                zf - dz as f32,
+            )
+        };
+
+        lerp(
+            w,
+            lerp(v, lerp(u, corner(0, 0, 0), corner(1, 0, 0)), lerp(u, corner(0, 1, 0), corner(1, 1, 0))),
+            lerp(v, lerp(u, corner(0, 0, 1), corner(1, 0, 1)), lerp(u, corner(0, 1, 1), corner(1, 1, 1))),
+        )
+    }
+}
+
+#[inline]
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+#[inline]
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+#[inline]
+fn grad2(hash: u8, x: f32, y: f32) -> f32 {
+    // Unit diagonals keep the output within [-1, 1]
+    const DIAGONAL: f32 = std::f32::consts::FRAC_1_SQRT_2;
+    match hash & 3 {
+        0 => (x + y) * DIAGONAL,
+        1 => (-x + y) * DIAGONAL,
+        2 => (x - y) * DIAGONAL,
+        _ => (-x - y) * DIAGONAL,
+    }
+}
+
+#[inline]
+fn grad3(hash: u8, x: f32, y: f32, z: f32) -> f32 {
+    match hash & 15 {
+        0 | 12 => x + y,
+        1 | 14 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x + z,
+        5 => -x + z,
+        6 => x - z,
+        7 => -x - z,
+        8 => y + z,
+        9 | 13 => -y + z,
+        10 => y - z,
+        _ => -y - z,
+    }
+}
+
+/// Fractal Brownian motion: several octaves of `Perlin` at increasing frequency.
+#[derive(Clone)]
+pub struct Fractal {
+    perlin: Perlin,
+    pub octaves: u32,
+    pub frequency: f32,
+    pub persistence: f32,
+    pub lacunarity: f32,
+}
+
+impl Fractal {
+    pub fn new(seed: u64, octaves: u32, frequency: f32, persistence: f32, lacunarity: f32) -> Self {
+        Self {
+            perlin: Perlin::new(seed),
+            octaves,
+            frequency,
+            persistence,
+            lacunarity,
+        }
+    }
+
+    fn octaves(&self) -> impl Iterator<Item = (u32, f32, f32)> {
+        let (persistence, lacunarity) = (self.persistence, self.lacunarity);
+        (0..self.octaves).scan((1.0, self.frequency), move |state, octave| {
+            let (amplitude, frequency) = *state;
+            *state = (amplitude * persistence, frequency * lacunarity);
+            Some((octave, amplitude, frequency))
+        })
+    }
+
+    fn total_amplitude(&self) -> f32 {
+        self.octaves().map(|(_, amplitude, _)| amplitude).sum()
+    }
+
+    /// Normalized to roughly [-1, 1]
+    pub fn get2(&self, x: f32, y: f32) -> f32 {
+        let sum: f32 = self.octaves()
+            .map(|(octave, amplitude, frequency)| {
+                // Offset each octave so that the lattice points don't line up at the origin
+                let offset = octave as f32 * 17.31;
+                amplitude * self.perlin.get2(x * frequency + offset, y * frequency + offset)
+            })
+            .sum();
+        sum / self.total_amplitude()
+    }
+
+    /// Normalized to roughly [-1, 1]
+    pub fn get3(&self, x: f32, y: f32, z: f32) -> f32 {
+        let sum: f32 = self.octaves()
+            .map(|(octave, amplitude, frequency)| {
+                let offset = octave as f32 * 17.31;
+                amplitude * self.perlin.get3(x * frequency + offset, y * frequency + offset, z * frequency + offset)
+            })
+            .sum();
+        sum / self.total_amplitude()
+    }
+
+    /// Upper bound on the gradient magnitude of `get2`.
+    pub fn lipschitz2(&self) -> f32 {
+        self.lipschitz(PERLIN_LIPSCHITZ_2D)
+    }
+
+    /// Upper bound on the gradient magnitude of `get3`.
+    pub fn lipschitz3(&self) -> f32 {
+        self.lipschitz(PERLIN_LIPSCHITZ_3D)
+    }
+
+    fn lipschitz(&self, base: f32) -> f32 {
+        let sum: f32 = self.octaves()
+            .map(|(_, amplitude, frequency)| amplitude * frequency * base)
+            .sum();
+        sum / self.total_amplitude()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fractal, Perlin, PERLIN_LIPSCHITZ_2D, PERLIN_LIPSCHITZ_3D};
+
+    #[test]
+    fn test_deterministic() {
+        let a = Fractal::new(42, 4, 0.1, 0.5, 2.0);
+        let b = Fractal::new(42, 4, 0.1, 0.5, 2.0);
+        let c = Fractal::new(43, 4, 0.1, 0.5, 2.0);
+        let samples = |noise: &Fractal| (0..64)
+            .map(|i| noise.get2(i as f32 * 0.37, i as f32 * 1.13))
+            .collect::<Vec<_>>();
+        assert_eq!(samples(&a), samples(&b));
+        assert_ne!(samples(&a), samples(&c));
+    }
+
+    #[test]
+    fn test_lipschitz_bound() {
+        let perlin = Perlin::new(7);
+        let step = 0.001;
+        for i in 0..2000 {
+            let x = i as f32 * 0.0371;
+            let y = i as f32 * 0.0193;
+            let z = i as f32 * 0.0517;
+            let value = perlin.get2(x, y);
+            let dx = (perlin.get2(x + step, y) - value) / step;
+            let dy = (perlin.get2(x, y + step) - value) / step;
+            assert!((dx * dx + dy * dy).sqrt() <= PERLIN_LIPSCHITZ_2D);
+            assert!(value.abs() <= 1.0);
+
+            let value = perlin.get3(x, y, z);
+            let dx = (perlin.get3(x + step, y, z) - value) / step;
+            let dy = (perlin.get3(x, y + step, z) - value) / step;
+            let dz = (perlin.get3(x, y, z + step) - value) / step;
+            assert!((dx * dx + dy * dy + dz * dz).sqrt() <= PERLIN_LIPSCHITZ_3D);
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use amethyst::core::math::{Isometry3, Point3, UnitQuaternion, Vector2, Vector3};
+
+use crate::octree::bounds::Bounds;
+use crate::octree::world::ChunkCoordinates;
+use crate::octree::world_builder::Isosurface;
+use crate::octree::VoxelData;
+use crate::util::aabb::Aabb;
+use crate::worldgen::{density, is_voxel_sized, Oracle};
+
+/**
+ A signed distance field: negative inside, positive outside.
+ Implementations must be 1-Lipschitz, so that `interval` can bound a whole region from one sample.
+ */
+pub trait Sdf {
+    fn distance(&self, p: &Point3<f32>) -> f32;
+
+    /// Conservative (min, max) of `distance` over the box.
+    fn interval(&self, aabb: &Aabb) -> (f32, f32) {
+        lipschitz_interval(self.distance(&aabb_center(aabb)), aabb)
+    }
+
+    fn union<B: Sdf>(self, other: B) -> Union<Self, B> where Self: Sized {
+        Union(self, other)
+    }
+
+    fn intersection<B: Sdf>(self, other: B) -> Intersection<Self, B> where Self: Sized {
+        Intersection(self, other)
+    }
+
+    fn difference<B: Sdf>(self, other: B) -> Difference<Self, B> where Self: Sized {
+        Difference(self, other)
+    }
+
+    fn smooth_union<B: Sdf>(self, other: B, radius: f32) -> SmoothUnion<Self, B> where Self: Sized {
+        SmoothUnion { a: self, b: other, radius }
+    }
+
+    fn translate(self, offset: Vector3<f32>) -> Transform<Self> where Self: Sized {
+        Transform::new(self, Isometry3::translation(offset.x, offset.y, offset.z))
+    }
+
+    fn rotate(self, rotation: UnitQuaternion<f32>) -> Transform<Self> where Self: Sized {
+        Transform::new(self, Isometry3::from_parts(Vector3::zeros().into(), rotation))
+    }
+
+    fn scale(self, factor: f32) -> Scale<Self> where Self: Sized {
+        Scale { shape: self, factor }
+    }
+
+    /// Turns the field into a `WorldBuilder` oracle filling the inside with `material`.
+    fn with_material(self, material: VoxelData, chunk_size: f32, voxel_size: f32) -> SdfOracle<Self> where Self: Sized {
+        SdfOracle { shape: self, material, chunk_size, voxel_size }
+    }
+}
+
+fn aabb_center(aabb: &Aabb) -> Point3<f32> {
+    Point3::from(aabb.center())
+}
+
+fn lipschitz_interval(center_distance: f32, aabb: &Aabb) -> (f32, f32) {
+    let half_diagonal = Vector3::from(aabb.size()).norm() * 0.5;
+    (center_distance - half_diagonal, center_distance + half_diagonal)
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Sphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+impl Sdf for Sphere {
+    fn distance(&self, p: &Point3<f32>) -> f32 {
+        (p - self.center).norm() - self.radius
+    }
+}
+
+/// Axis aligned box
+#[derive(Copy, Clone, Debug)]
+pub struct Cuboid {
+    pub center: Point3<f32>,
+    pub half_extents: Vector3<f32>,
+}
+
+impl Sdf for Cuboid {
+    fn distance(&self, p: &Point3<f32>) -> f32 {
+        let q = (p - self.center).abs() - self.half_extents;
+        let outside = q.sup(&Vector3::zeros()).norm();
+        let inside = q.x.max(q.y).max(q.z).min(0.0);
+        outside + inside
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Capsule {
+    pub a: Point3<f32>,
+    pub b: Point3<f32>,
+    pub radius: f32,
+}
+
+impl Sdf for Capsule {
+    fn distance(&self, p: &Point3<f32>) -> f32 {
+        let pa = p - self.a;
+        let ba = self.b - self.a;
+        let h = (pa.dot(&ba) / ba.dot(&ba)).max(0.0).min(1.0);
+        (pa - ba * h).norm() - self.radius
+    }
+}
+
+/// Capped cylinder along the Y axis
+#[derive(Copy, Clone, Debug)]
+pub struct Cylinder {
+    pub center: Point3<f32>,
+    pub radius: f32,
+    pub half_height: f32,
+}
+
+impl Sdf for Cylinder {
+    fn distance(&self, p: &Point3<f32>) -> f32 {
+        let p = p - self.center;
+        let d = Vector2::new(
+            Vector2::new(p.x, p.z).norm() - self.radius,
+            p.y.abs() - self.half_height,
+        );
+        d.x.max(d.y).min(0.0) + d.sup(&Vector2::zeros()).norm()
+    }
+}
+
+/// Torus lying on the XZ plane
+#[derive(Copy, Clone, Debug)]
+pub struct Torus {
+    pub center: Point3<f32>,
+    pub major_radius: f32,
+    pub minor_radius: f32,
+}
+
+impl Sdf for Torus {
+    fn distance(&self, p: &Point3<f32>) -> f32 {
+        let p = p - self.center;
+        let q = Vector2::new(Vector2::new(p.x, p.z).norm() - self.major_radius, p.y);
+        q.norm() - self.minor_radius
+    }
+}
+
+/// Half space below the plane `dot(normal, p) = offset`. `normal` must be normalized.
+#[derive(Copy, Clone, Debug)]
+pub struct Plane {
+    pub normal: Vector3<f32>,
+    pub offset: f32,
+}
+
+impl Sdf for Plane {
+    fn distance(&self, p: &Point3<f32>) -> f32 {
+        self.normal.dot(&p.coords) - self.offset
+    }
+
+    fn interval(&self, aabb: &Aabb) -> (f32, f32) {
+        // Exact: the extreme corners along the normal
+        let center = self.distance(&aabb_center(aabb));
+        let size = Vector3::from(aabb.size());
+        let extent = self.normal.abs().dot(&size) * 0.5;
+        (center - extent, center + extent)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Union<A, B>(pub A, pub B);
+
+impl<A: Sdf, B: Sdf> Sdf for Union<A, B> {
+    fn distance(&self, p: &Point3<f32>) -> f32 {
+        self.0.distance(p).min(self.1.distance(p))
+    }
+
+    fn interval(&self, aabb: &Aabb) -> (f32, f32) {
+        let (a_min, a_max) = self.0.interval(aabb);
+        let (b_min, b_max) = self.1.interval(aabb);
+        (a_min.min(b_min), a_max.min(b_max))
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Intersection<A, B>(pub A, pub B);
+
+impl<A: Sdf, B: Sdf> Sdf for Intersection<A, B> {
+    fn distance(&self, p: &Point3<f32>) -> f32 {
+        self.0.distance(p).max(self.1.distance(p))
+    }
+
+    fn interval(&self, aabb: &Aabb) -> (f32, f32) {
+        let (a_min, a_max) = self.0.interval(aabb);
+        let (b_min, b_max) = self.1.interval(aabb);
+        (a_min.max(b_min), a_max.max(b_max))
+    }
+}
+
+/// `A` with `B` carved out of it
+#[derive(Copy, Clone, Debug)]
+pub struct Difference<A, B>(pub A, pub B);
+
+impl<A: Sdf, B: Sdf> Sdf for Difference<A, B> {
+    fn distance(&self, p: &Point3<f32>) -> f32 {
+        self.0.distance(p).max(-self.1.distance(p))
+    }
+
+    fn interval(&self, aabb: &Aabb) -> (f32, f32) {
+        let (a_min, a_max) = self.0.interval(aabb);
+        let (b_min, b_max) = self.1.interval(aabb);
+        (a_min.max(-b_max), a_max.max(-b_min))
+    }
+}
+
+/// Polynomial smooth minimum, blending the surfaces within `radius` of each other
+#[derive(Copy, Clone, Debug)]
+pub struct SmoothUnion<A, B> {
+    pub a: A,
+    pub b: B,
+    pub radius: f32,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for SmoothUnion<A, B> {
+    fn distance(&self, p: &Point3<f32>) -> f32 {
+        let a = self.a.distance(p);
+        let b = self.b.distance(p);
+        let h = (0.5 + 0.5 * (b - a) / self.radius).max(0.0).min(1.0);
+        b + (a - b) * h - self.radius * h * (1.0 - h)
+    }
+
+    fn interval(&self, aabb: &Aabb) -> (f32, f32) {
+        // The blend only ever pulls the surface outwards, by at most radius / 4
+        let (a_min, a_max) = self.a.interval(aabb);
+        let (b_min, b_max) = self.b.interval(aabb);
+        (a_min.min(b_min) - self.radius * 0.25, a_max.min(b_max))
+    }
+}
+
+/// Rigid transform of a shape. Stores the inverse, which is what evaluation needs.
+#[derive(Copy, Clone, Debug)]
+pub struct Transform<S> {
+    pub shape: S,
+    inverse: Isometry3<f32>,
+}
+
+impl<S> Transform<S> {
+    pub fn new(shape: S, transform: Isometry3<f32>) -> Self {
+        Self { shape, inverse: transform.inverse() }
+    }
+}
+
+impl<S: Sdf> Sdf for Transform<S> {
+    fn distance(&self, p: &Point3<f32>) -> f32 {
+        self.shape.distance(&(self.inverse * p))
+    }
+}
+
+/// Uniform scale around the origin
+#[derive(Copy, Clone, Debug)]
+pub struct Scale<S> {
+    pub shape: S,
+    pub factor: f32,
+}
+
+impl<S: Sdf> Sdf for Scale<S> {
+    fn distance(&self, p: &Point3<f32>) -> f32 {
+        self.shape.distance(&(p / self.factor)) * self.factor
+    }
+}
+
+/// `WorldBuilder` oracle for an `Sdf`, created with `Sdf::with_material`.
+#[derive(Copy, Clone, Debug)]
+pub struct SdfOracle<S> {
+    pub shape: S,
+    pub material: VoxelData,
+    /// World units spanned by one chunk
+    pub chunk_size: f32,
+    /// Voxel size in world units, see `worldgen::is_voxel_sized`
+    pub voxel_size: f32,
+}
+
+impl<S: Sdf> SdfOracle<S> {
+    pub fn sample(&self, p: &Point3<f32>) -> VoxelData {
+        let distance = self.shape.distance(p);
+        if distance > 0.0 {
+            return VoxelData::EMPTY;
+        }
+        self.material.with_density(density(-distance, self.voxel_size))
+    }
+}
+
+impl<S: Sdf> Oracle for SdfOracle<S> {
+    fn classify(&self, chunk: &ChunkCoordinates, bounds: &Bounds) -> Isosurface<VoxelData> {
+        let aabb = Aabb::from_bounds(chunk, bounds, self.chunk_size);
+        if is_voxel_sized(&aabb, self.voxel_size) {
+            return Isosurface::Uniform(self.sample(&aabb_center(&aabb)));
+        }
+        let (min, max) = self.shape.interval(&aabb);
+        if min > 0.0 {
+            Isosurface::Uniform(VoxelData::EMPTY)
+        } else if max < 0.0 {
+            Isosurface::Uniform(self.material)
+        } else {
+            Isosurface::Surface
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_interval_contains_samples<S: Sdf>(shape: &S, aabb: &Aabb) {
+        let (min, max) = shape.interval(aabb);
+        let size = aabb.size();
+        for i in 0..=8 {
+            for j in 0..=8 {
+                for k in 0..=8 {
+                    let p = Point3::new(
+                        aabb.min[0] + size[0] * i as f32 / 8.0,
+                        aabb.min[1] + size[1] * j as f32 / 8.0,
+                        aabb.min[2] + size[2] * k as f32 / 8.0,
+                    );
+                    let d = shape.distance(&p);
+                    assert!(min - 1e-4 <= d && d <= max + 1e-4, "{} not in [{}, {}]", d, min, max);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_primitives() {
+        let sphere = Sphere { center: Point3::new(1.0, 0.0, 0.0), radius: 2.0 };
+        assert!((sphere.distance(&Point3::new(4.0, 0.0, 0.0)) - 1.0).abs() < 1e-6);
+        let cuboid = Cuboid { center: Point3::origin(), half_extents: Vector3::new(1.0, 2.0, 3.0) };
+        assert!((cuboid.distance(&Point3::new(0.0, 0.0, 0.0)) + 1.0).abs() < 1e-6);
+        assert!((cuboid.distance(&Point3::new(2.0, 0.0, 0.0)) - 1.0).abs() < 1e-6);
+        let plane = Plane { normal: Vector3::y(), offset: 1.0 };
+        assert!((plane.distance(&Point3::new(5.0, 3.0, -2.0)) - 2.0).abs() < 1e-6);
+        let torus = Torus { center: Point3::origin(), major_radius: 3.0, minor_radius: 1.0 };
+        assert!((torus.distance(&Point3::new(3.0, 0.0, 0.0)) + 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_csg_intervals() {
+        let shape = Sphere { center: Point3::new(0.0, 0.0, 0.0), radius: 4.0 }
+            .smooth_union(Capsule { a: Point3::new(-5.0, 0.0, 0.0), b: Point3::new(5.0, 2.0, 0.0), radius: 1.0 }, 1.5)
+            .difference(Cylinder { center: Point3::origin(), radius: 1.0, half_height: 10.0 })
+            .intersection(Plane { normal: Vector3::y(), offset: 2.0 })
+            .rotate(UnitQuaternion::from_euler_angles(0.3, 0.2, 0.1))
+            .translate(Vector3::new(1.0, 2.0, 3.0))
+            .scale(1.5);
+        for &(min, size) in &[([-2.0, 0.0, 1.0], 1.0), ([0.0, 0.0, 0.0], 4.0), ([-8.0, -8.0, -8.0], 16.0)] {
+            let aabb = Aabb::new(min, [min[0] + size, min[1] + size, min[2] + size]);
+            assert_interval_contains_samples(&shape, &aabb);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+
+use crate::octree::bounds::Bounds;
+use crate::octree::world::ChunkCoordinates;
+use crate::octree::world_builder::Isosurface;
+use crate::octree::VoxelData;
+use crate::util::aabb::Aabb;
+use crate::worldgen::noise::Fractal;
+use crate::worldgen::{density, is_voxel_sized, Oracle};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TerrainSettings {
+    pub seed: u64,
+    pub octaves: u32,
+    /// Frequency of the first octave, in cycles per world unit
+    pub frequency: f32,
+    pub persistence: f32,
+    pub lacunarity: f32,
+    /// Terrain height where the noise is zero, in world units
+    pub base_height: f32,
+    /// Maximum deviation from `base_height`
+    pub amplitude: f32,
+    /// World units spanned by one chunk
+    pub chunk_size: f32,
+    /// Voxel size in world units, see `worldgen::is_voxel_sized`
+    pub voxel_size: f32,
+    pub surface_material: u16,
+    pub surface_depth: f32,
+    pub subsurface_material: u16,
+    pub subsurface_depth: f32,
+    pub base_material: u16,
+}
+
+impl Default for TerrainSettings {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            octaves: 5,
+            frequency: 0.01,
+            persistence: 0.5,
+            lacunarity: 2.0,
+            base_height: 16.0,
+            amplitude: 12.0,
+            chunk_size: 64.0,
+            voxel_size: 0.5,
+            surface_material: 3,
+            surface_depth: 1.0,
+            subsurface_material: 2,
+            subsurface_depth: 4.0,
+            base_material: 1,
+        }
+    }
+}
+
+/// Heightmap terrain from fractal Perlin noise. The same settings always produce the same world.
+#[derive(Clone)]
+pub struct Terrain {
+    settings: TerrainSettings,
+    noise: Fractal,
+}
+
+impl Terrain {
+    pub fn new(settings: TerrainSettings) -> Self {
+        let noise = Fractal::new(
+            settings.seed,
+            settings.octaves,
+            settings.frequency,
+            settings.persistence,
+            settings.lacunarity,
+        );
+        Self { settings, noise }
+    }
+
+    pub fn settings(&self) -> &TerrainSettings {
+        &self.settings
+    }
+
+    pub fn height(&self, x: f32, z: f32) -> f32 {
+        self.settings.base_height + self.settings.amplitude * self.noise.get2(x, z)
+    }
+
+    /// Conservative (min, max) of the height over the rectangle between `min` and `max` on the XZ plane.
+    pub fn height_range(&self, min: [f32; 2], max: [f32; 2]) -> (f32, f32) {
+        let center = [(min[0] + max[0]) * 0.5, (min[1] + max[1]) * 0.5];
+        let half_diagonal = ((max[0] - min[0]).powi(2) + (max[1] - min[1]).powi(2)).sqrt() * 0.5;
+        let slack = self.settings.amplitude * self.noise.lipschitz2() * half_diagonal;
+        let height = self.height(center[0], center[1]);
+        let global_min = self.settings.base_height - self.settings.amplitude;
+        let global_max = self.settings.base_height + self.settings.amplitude;
+        ((height - slack).max(global_min), (height + slack).min(global_max))
+    }
+
+    /// Material at a given depth below the surface. Monotonic in depth.
+    pub fn material_at_depth(&self, depth: f32) -> VoxelData {
+        let settings = &self.settings;
+        if depth < 0.0 {
+            VoxelData::EMPTY
+        } else if depth < settings.surface_depth {
+            VoxelData::solid(settings.surface_material)
+        } else if depth < settings.surface_depth + settings.subsurface_depth {
+            VoxelData::solid(settings.subsurface_material)
+        } else {
+            VoxelData::solid(settings.base_material)
+        }
+    }
+
+    /// Voxel at a single point, with density proportional to the depth below the surface.
+    pub fn sample(&self, position: [f32; 3]) -> VoxelData {
+        let depth = self.height(position[0], position[2]) - position[1];
+        let voxel = self.material_at_depth(depth);
+        if voxel.is_empty() {
+            return voxel;
+        }
+        voxel.with_density(density(depth, self.settings.voxel_size))
+    }
+}
+
+impl Oracle for Terrain {
+    fn classify(&self, chunk: &ChunkCoordinates, bounds: &Bounds) -> Isosurface<VoxelData> {
+        let aabb = Aabb::from_bounds(chunk, bounds, self.settings.chunk_size);
+        if is_voxel_sized(&aabb, self.settings.voxel_size) {
+            return Isosurface::Uniform(self.sample(aabb.center()));
+        }
+
+        let (min_height, max_height) = self.height_range(
+            [aabb.min[0], aabb.min[2]],
+            [aabb.max[0], aabb.max[2]],
+        );
+        if aabb.min[1] >= max_height {
+            return Isosurface::Uniform(VoxelData::EMPTY);
+        }
+        let min_depth = min_height - aabb.max[1];
+        let max_depth = max_height - aabb.min[1];
+        if min_depth >= 0.0 {
+            let shallowest = self.material_at_depth(min_depth);
+            if shallowest == self.material_at_depth(max_depth) {
+                return Isosurface::Uniform(shallowest);
+            }
+        }
+        Isosurface::Surface
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Terrain, TerrainSettings};
+
+    #[test]
+    fn test_height_range_is_conservative() {
+        let terrain = Terrain::new(TerrainSettings::default());
+        for &size in &[1.0, 8.0, 64.0] {
+            let min = [13.0, -41.0];
+            let max = [13.0 + size, -41.0 + size];
+            let (low, high) = terrain.height_range(min, max);
+            for i in 0..=16 {
+                for j in 0..=16 {
+                    let x = min[0] + size * i as f32 / 16.0;
+                    let z = min[1] + size * j as f32 / 16.0;
+                    let height = terrain.height(x, z);
+                    assert!(low <= height && height <= high);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_same_seed_same_world() {
+        let a = Terrain::new(TerrainSettings { seed: 1234, ..TerrainSettings::default() });
+        let b = Terrain::new(TerrainSettings { seed: 1234, ..TerrainSettings::default() });
+        for i in 0..32 {
+            let position = [i as f32 * 3.7, 10.0, i as f32 * -2.3];
+            assert_eq!(a.sample(position), b.sample(position));
+        }
+    }
+}